@@ -18,16 +18,28 @@
 
         server = pkgs.rust.packages.stable.rustPlatform.buildRustPackage {
           name = "server";
-          src = ./server;
+          # The whole repository is needed for the shared protocol crate.
+          src = ./.;
+          cargoRoot = "server";
+          buildAndTestSubdir = "server";
 
           cargoLock = {
             lockFile = ./server/Cargo.lock;
           };
+
+          # Mixing calls decodes and encodes Opus on the server
+          nativeBuildInputs = with pkgs; [
+            pkg-config
+            libopus
+          ];
         };
 
         tty_client = pkgs.rust.packages.stable.rustPlatform.buildRustPackage {
           name = "tty_client";
-          src = ./tty_client;
+          # The whole repository is needed for the shared protocol crate.
+          src = ./.;
+          cargoRoot = "tty_client";
+          buildAndTestSubdir = "tty_client";
 
           cargoLock = {
             lockFile = ./tty_client/Cargo.lock;
@@ -0,0 +1,107 @@
+//! Encoding of socket addresses exchanged between server and clients.
+//!
+//! An address is a family byte, followed by the IP address and a big-endian port.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::{DecodeError, message::ensure_len};
+
+const FAMILY_IPV4: u8 = 4;
+const FAMILY_IPV6: u8 = 6;
+
+/// Length of an encoded IPv4 address: family, 4 bytes of address and port.
+pub const ENCODED_IPV4_LEN: usize = 1 + 4 + 2;
+/// Length of an encoded IPv6 address: family, 16 bytes of address and port.
+pub const ENCODED_IPV6_LEN: usize = 1 + 16 + 2;
+
+/// Encodes `addr`. IPv4-mapped IPv6 addresses are sent as plain IPv4, the peer might not
+/// have IPv6 at all.
+pub fn encode(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            buf.push(FAMILY_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(FAMILY_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+pub fn decode(buf: &[u8]) -> Result<(SocketAddr, usize), DecodeError> {
+    ensure_len(buf, 1)?;
+
+    let (ip, len) = match buf[0] {
+        FAMILY_IPV4 => {
+            ensure_len(buf, ENCODED_IPV4_LEN)?;
+            let octets: [u8; 4] = buf[1..5].try_into().unwrap();
+            (IpAddr::from(Ipv4Addr::from(octets)), ENCODED_IPV4_LEN)
+        }
+        FAMILY_IPV6 => {
+            ensure_len(buf, ENCODED_IPV6_LEN)?;
+            let octets: [u8; 16] = buf[1..17].try_into().unwrap();
+            (IpAddr::from(Ipv6Addr::from(octets)), ENCODED_IPV6_LEN)
+        }
+        _ => return Err(DecodeError::Invalid("unknown address family")),
+    };
+
+    let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+
+    Ok((SocketAddr::new(ip, port), len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(addr: SocketAddr) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode(&addr, &mut buf);
+
+        assert_eq!(decode(&buf), Ok((addr, buf.len())));
+        buf
+    }
+
+    #[test]
+    fn ipv4_round_trip() {
+        let buf = round_trip("192.168.1.20:40123".parse().unwrap());
+        assert_eq!(buf, [FAMILY_IPV4, 192, 168, 1, 20, 0x9c, 0xbb]);
+    }
+
+    #[test]
+    fn ipv6_round_trip() {
+        let buf = round_trip("[2001:db8::1]:443".parse().unwrap());
+        assert_eq!(buf.len(), ENCODED_IPV6_LEN);
+    }
+
+    #[test]
+    fn ipv4_mapped_is_sent_as_ipv4() {
+        let mut buf = Vec::new();
+        encode(&"[::ffff:10.0.0.1]:9000".parse().unwrap(), &mut buf);
+
+        assert_eq!(
+            decode(&buf),
+            Ok(("10.0.0.1:9000".parse().unwrap(), ENCODED_IPV4_LEN))
+        );
+    }
+
+    #[test]
+    fn incomplete() {
+        assert_eq!(decode(&[]), Err(DecodeError::Incomplete(1)));
+        assert_eq!(
+            decode(&[FAMILY_IPV4, 127, 0]),
+            Err(DecodeError::Incomplete(4))
+        );
+        assert_eq!(decode(&[FAMILY_IPV6, 0]), Err(DecodeError::Incomplete(17)));
+    }
+
+    #[test]
+    fn unknown_family() {
+        assert!(matches!(
+            decode(&[5, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+//! End-to-end encryption of the audio clients send each other.
+//!
+//! Each sender picks a random [`MediaKey`] for the call and hands it to the others through
+//! the [`key_exchange`](crate::key_exchange) it runs with each of them. Every client in a room
+//! also derives the same key from the room secret, with Argon2id so that guessing a weak
+//! secret offline is slow, which goes into those exchanges.
+//!
+//! A sender seals its frames with XChaCha20-Poly1305 under a nonce made of a stream id it
+//! picks at random when the call starts and a counter, which go in front of the ciphertext.
+//! Receivers only take the first stream they hear from each sender, and drop frames they
+//! already opened, or that are too old to tell.
+
+use std::fmt;
+
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+
+use crate::signal::RoomHash;
+
+/// Length of a [`MediaKey`].
+pub const KEY_LEN: usize = 32;
+/// Length of the random id of a sender's stream, the start of every nonce.
+pub const STREAM_ID_LEN: usize = 16;
+/// Length of a sealed frame's nonce: the stream id, then the frame's counter.
+pub const NONCE_LEN: usize = STREAM_ID_LEN + 8;
+/// Length of the tag authenticating a sealed frame.
+pub const TAG_LEN: usize = 16;
+/// How much longer a sealed frame is than the frame itself.
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// How many frames before the latest one a receiver still takes, if they come late.
+const REPLAY_WINDOW: u64 = 64;
+
+/// A key a sender encrypts its audio with, or the one derived from the room secret.
+#[derive(Clone)]
+pub struct MediaKey(pub(crate) [u8; KEY_LEN]);
+
+/// Encrypts one sender's frames.
+pub struct Sealer {
+    cipher: XChaCha20Poly1305,
+    stream_id: [u8; STREAM_ID_LEN],
+    counter: u64,
+}
+
+/// Decrypts the frames of one sender, rejecting replays.
+pub struct Opener {
+    cipher: XChaCha20Poly1305,
+    /// The stream taken from the sender, once a frame of it was opened.
+    stream_id: Option<[u8; STREAM_ID_LEN]>,
+    /// Counter of the latest frame opened.
+    latest: u64,
+    /// Which of the [`REPLAY_WINDOW`] frames up to `latest` were opened, `latest` being the
+    /// lowest bit.
+    seen: u64,
+}
+
+/// Why a sealed frame was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// Too short to hold a nonce and a tag.
+    Truncated,
+    /// It was not sealed with our key for this sender, or was tampered with.
+    Forged,
+    /// It belongs to another stream than the one taken from this sender.
+    OtherStream,
+    /// It was opened already, or is too old to tell.
+    Replayed,
+}
+
+impl MediaKey {
+    /// A key for a sender's audio, never used before.
+    pub fn random() -> Self {
+        let mut key = [0; KEY_LEN];
+        getrandom::fill(&mut key).expect("The system has no source of randomness");
+        Self(key)
+    }
+
+    /// Derives the key of the room `room_hash` from its `secret`. Slow on purpose.
+    pub fn derive(room_hash: &RoomHash, secret: &[u8]) -> Self {
+        let mut key = [0; KEY_LEN];
+        argon2::Argon2::default()
+            .hash_password_into(secret, room_hash, &mut key)
+            .expect("The room hash is a valid salt, and the key a valid output length");
+        Self(key)
+    }
+}
+
+impl Sealer {
+    /// Starts a new stream, under a random id.
+    pub fn new(key: &MediaKey) -> Self {
+        let mut stream_id = [0; STREAM_ID_LEN];
+        getrandom::fill(&mut stream_id).expect("The system has no source of randomness");
+
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.0.into()),
+            stream_id,
+            counter: 0,
+        }
+    }
+
+    /// Encrypts the next `frame`, authenticating `associated` along with it: what the
+    /// receiver checks about who sent it.
+    pub fn seal(&mut self, associated: &[u8], frame: &[u8]) -> Vec<u8> {
+        let nonce = nonce(&self.stream_id, self.counter);
+        self.counter += 1;
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: frame,
+                    aad: associated,
+                },
+            )
+            .expect("Frames are far below what XChaCha20-Poly1305 can encrypt");
+
+        [&nonce[..], &ciphertext].concat()
+    }
+}
+
+impl Opener {
+    pub fn new(key: &MediaKey) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.0.into()),
+            stream_id: None,
+            latest: 0,
+            seen: 0,
+        }
+    }
+
+    /// Decrypts `sealed`, which must have been sealed with the same `associated` data.
+    pub fn open(&mut self, associated: &[u8], sealed: &[u8]) -> Result<Vec<u8>, OpenError> {
+        if sealed.len() < OVERHEAD {
+            return Err(OpenError::Truncated);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let (stream_id, counter) = nonce.split_at(STREAM_ID_LEN);
+        let stream_id: [u8; STREAM_ID_LEN] = stream_id.try_into().unwrap();
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+
+        if self.stream_id.is_some_and(|taken| taken != stream_id) {
+            return Err(OpenError::OtherStream);
+        }
+        if self.stream_id.is_some() && !self.is_new(counter) {
+            return Err(OpenError::Replayed);
+        }
+
+        // Only what opens counts as seen, anyone can send garbage under any nonce
+        let frame = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated,
+                },
+            )
+            .map_err(|_| OpenError::Forged)?;
+
+        if self.stream_id.is_none() {
+            self.stream_id = Some(stream_id);
+            self.latest = counter;
+            self.seen = 1;
+        } else if counter > self.latest {
+            let ahead = counter - self.latest;
+            self.seen = if ahead < REPLAY_WINDOW {
+                self.seen << ahead | 1
+            } else {
+                1
+            };
+            self.latest = counter;
+        } else {
+            self.seen |= 1 << (self.latest - counter);
+        }
+
+        Ok(frame)
+    }
+
+    /// Whether the frame numbered `counter` was not opened yet, and is recent enough to tell.
+    fn is_new(&self, counter: u64) -> bool {
+        if counter > self.latest {
+            return true;
+        }
+        let behind = self.latest - counter;
+        behind < REPLAY_WINDOW && self.seen & (1 << behind) == 0
+    }
+}
+
+fn nonce(stream_id: &[u8; STREAM_ID_LEN], counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..STREAM_ID_LEN].copy_from_slice(stream_id);
+    nonce[STREAM_ID_LEN..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+impl fmt::Debug for MediaKey {
+    // Secrets stay out of logs and panic messages
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MediaKey(..)")
+    }
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenError::Truncated => write!(f, "too short to be sealed"),
+            OpenError::Forged => write!(f, "does not authenticate"),
+            OpenError::OtherStream => write!(f, "from another stream of the sender"),
+            OpenError::Replayed => write!(f, "replayed"),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: MediaKey = MediaKey([7; KEY_LEN]);
+    const SENDER: &[u8] = &[0, 0, 0, 1];
+
+    #[test]
+    fn derived_keys_depend_on_room_and_secret() {
+        let key = MediaKey::derive(&[1; 64], b"secret");
+
+        assert_eq!(key.0, MediaKey::derive(&[1; 64], b"secret").0);
+        assert_ne!(key.0, MediaKey::derive(&[1; 64], b"guess").0);
+        assert_ne!(key.0, MediaKey::derive(&[2; 64], b"secret").0);
+    }
+
+    #[test]
+    fn sealed_frames_open() {
+        let (mut sealer, mut opener) = (Sealer::new(&KEY), Opener::new(&KEY));
+
+        for frame in [&b"hello"[..], b"", b"world"] {
+            let sealed = sealer.seal(SENDER, frame);
+            assert_eq!(sealed.len(), frame.len() + OVERHEAD);
+            assert_eq!(opener.open(SENDER, &sealed).as_deref(), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn tampered_frames_do_not_open() {
+        let (mut sealer, mut opener) = (Sealer::new(&KEY), Opener::new(&KEY));
+        let sealed = sealer.seal(SENDER, b"hello");
+
+        // Flipping any bit, or claiming another sender, gives it away
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(opener.open(SENDER, &tampered).is_err());
+        }
+        assert_eq!(opener.open(&[0, 0, 0, 2], &sealed), Err(OpenError::Forged));
+        assert_eq!(
+            Opener::new(&MediaKey([8; KEY_LEN])).open(SENDER, &sealed),
+            Err(OpenError::Forged)
+        );
+        assert_eq!(
+            opener.open(SENDER, &sealed[..10]),
+            Err(OpenError::Truncated)
+        );
+
+        assert!(opener.open(SENDER, &sealed).is_ok());
+    }
+
+    #[test]
+    fn replays_are_dropped() {
+        let (mut sealer, mut opener) = (Sealer::new(&KEY), Opener::new(&KEY));
+        let sealed: Vec<_> = (0..100).map(|_| sealer.seal(SENDER, b"frame")).collect();
+
+        assert!(opener.open(SENDER, &sealed[1]).is_ok());
+        assert_eq!(opener.open(SENDER, &sealed[1]), Err(OpenError::Replayed));
+
+        // Late frames are fine, once
+        assert!(opener.open(SENDER, &sealed[0]).is_ok());
+        assert_eq!(opener.open(SENDER, &sealed[0]), Err(OpenError::Replayed));
+
+        // Too late to tell
+        assert!(opener.open(SENDER, &sealed[80]).is_ok());
+        assert!(opener.open(SENDER, &sealed[10]).is_err());
+        assert!(opener.open(SENDER, &sealed[20]).is_ok());
+
+        // Another stream sealed with the same key, say from an earlier call
+        let other = Sealer::new(&KEY).seal(SENDER, b"frame");
+        assert_eq!(opener.open(SENDER, &other), Err(OpenError::OtherStream));
+    }
+}
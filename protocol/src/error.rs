@@ -0,0 +1,63 @@
+use std::{fmt, io};
+
+/// Why a buffer could not be decoded into a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ends before the message does. At least this many more bytes are needed.
+    Incomplete(usize),
+    /// The first byte does not identify any known message.
+    UnknownTag(u8),
+    /// The message is recognized but its contents are not valid.
+    Invalid(&'static str),
+}
+
+/// Why a message could not be read from a stream.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Decode(DecodeError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete(needed) => {
+                write!(f, "incomplete message, {needed} more bytes needed")
+            }
+            DecodeError::UnknownTag(tag) => write!(f, "unknown message tag {tag}"),
+            DecodeError::Invalid(reason) => write!(f, "invalid message: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{e}"),
+            ReadError::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Io(e) => Some(e),
+            ReadError::Decode(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<DecodeError> for ReadError {
+    fn from(e: DecodeError) -> Self {
+        ReadError::Decode(e)
+    }
+}
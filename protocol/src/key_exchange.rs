@@ -0,0 +1,298 @@
+//! Ephemeral key exchanges between the clients in a call, so that the server relaying it
+//! cannot listen in.
+//!
+//! Each pair of clients runs X25519 with keys made for the pair: both first send a
+//! [`Commitment`] to their [`PublicKey`], and only reveal it once the other's commitment
+//! arrived. Whoever sits in the middle has to pick its keys before learning either client's,
+//! so it cannot steer the [`Sas`] both clients derive to match. If the users read theirs to
+//! each other and they agree, nobody is in the middle.
+//!
+//! Each client then seals the key it encrypts its audio with, see
+//! [`encryption`](crate::encryption), for the other with the [`Agreement`] they reached.
+
+use std::fmt;
+
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use curve25519_dalek::MontgomeryPoint;
+
+use crate::{
+    encryption::{self, MediaKey},
+    pake::hash,
+};
+
+/// Length of a [`PublicKey`] on the wire.
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// Length of a [`Commitment`] on the wire.
+pub const COMMITMENT_LEN: usize = 32;
+/// Length of a [`SealedKey`] on the wire: a nonce, the key, and a tag.
+pub const SEALED_KEY_LEN: usize = encryption::NONCE_LEN + encryption::KEY_LEN + encryption::TAG_LEN;
+
+/// Tells hashes made for different purposes apart.
+const COMMITMENT_LABEL: &[u8] = b"simple_call key exchange commitment";
+const WRAPPING_KEY_LABEL: &[u8] = b"simple_call key exchange wrapping key";
+const SAS_LABEL: &[u8] = b"simple_call key exchange SAS";
+
+/// How many different codes users compare.
+const SAS_VALUES: u32 = 1_000_000;
+
+/// A client's X25519 public key for the exchange with one peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(pub [u8; PUBLIC_KEY_LEN]);
+
+/// Hash of a [`PublicKey`], sent before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commitment(pub [u8; COMMITMENT_LEN]);
+
+/// A [`MediaKey`] sealed for one peer.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SealedKey(pub [u8; SEALED_KEY_LEN]);
+
+/// Our side of the exchange with one peer.
+pub struct KeyExchange {
+    secret: [u8; 32],
+    public_key: PublicKey,
+}
+
+/// What an exchange with one peer leaves both sides with.
+pub struct Agreement {
+    cipher: XChaCha20Poly1305,
+    sas: Sas,
+}
+
+/// Short authentication string: a code both sides of an exchange show their users, equal
+/// unless someone is in the middle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sas(u32);
+
+/// Why an exchange failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyExchangeError {
+    /// The peer's public key is not the one it committed to.
+    CommitmentMismatch,
+    /// The peer's public key gives no shared secret.
+    InvalidKey,
+    /// A sealed key was not sealed for this exchange, or was tampered with.
+    Forged,
+}
+
+impl KeyExchange {
+    /// Starts an exchange, with keys of its own.
+    pub fn new() -> Self {
+        let mut secret = [0; 32];
+        getrandom::fill(&mut secret).expect("The system has no source of randomness");
+        let public_key = PublicKey(MontgomeryPoint::mul_base_clamped(secret).to_bytes());
+
+        Self { secret, public_key }
+    }
+
+    /// What to send the peer first.
+    pub fn commitment(&self) -> Commitment {
+        commitment(&self.public_key)
+    }
+
+    /// What to send the peer once its commitment arrived.
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Ends the exchange with the peer that committed to `peer_commitment` then sent
+    /// `peer_public_key`. `room_key` goes into the agreement, so that only those who know the
+    /// room secret reach it.
+    pub fn finish(
+        self,
+        room_key: &MediaKey,
+        peer_commitment: &Commitment,
+        peer_public_key: &PublicKey,
+    ) -> Result<Agreement, KeyExchangeError> {
+        if commitment(peer_public_key) != *peer_commitment {
+            return Err(KeyExchangeError::CommitmentMismatch);
+        }
+
+        let shared = MontgomeryPoint(peer_public_key.0).mul_clamped(self.secret);
+        // Keys of small order give everyone the same secret
+        if shared.to_bytes() == [0; 32] {
+            return Err(KeyExchangeError::InvalidKey);
+        }
+
+        // Both sides list the keys in the same order
+        let (low, high) = if self.public_key.0 <= peer_public_key.0 {
+            (&self.public_key, peer_public_key)
+        } else {
+            (peer_public_key, &self.public_key)
+        };
+
+        let wrapping_key = hash(&[
+            WRAPPING_KEY_LABEL,
+            &room_key.0,
+            shared.as_bytes(),
+            &low.0,
+            &high.0,
+        ]);
+        let cipher = XChaCha20Poly1305::new_from_slice(&wrapping_key[..encryption::KEY_LEN])
+            .expect("The wrapping key is a valid key length");
+
+        // Leaves the room key out, users comparing codes need not share the same secret
+        let sas = hash(&[SAS_LABEL, shared.as_bytes(), &low.0, &high.0]);
+        let sas = Sas(u32::from_be_bytes(sas[..4].try_into().unwrap()) % SAS_VALUES);
+
+        Ok(Agreement { cipher, sas })
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Agreement {
+    /// The code to show the user, for them to compare with the peer's.
+    pub fn sas(&self) -> Sas {
+        self.sas
+    }
+
+    /// Seals `key` for the peer.
+    pub fn seal_key(&self, key: &MediaKey) -> SealedKey {
+        let mut nonce = [0; encryption::NONCE_LEN];
+        getrandom::fill(&mut nonce).expect("The system has no source of randomness");
+
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), &key.0[..])
+            .expect("Keys are far below what XChaCha20-Poly1305 can encrypt");
+
+        let mut sealed = [0; SEALED_KEY_LEN];
+        sealed[..encryption::NONCE_LEN].copy_from_slice(&nonce);
+        sealed[encryption::NONCE_LEN..].copy_from_slice(&ciphertext);
+        SealedKey(sealed)
+    }
+
+    /// Opens the key the peer sealed for us.
+    pub fn open_key(&self, sealed: &SealedKey) -> Result<MediaKey, KeyExchangeError> {
+        let (nonce, ciphertext) = sealed.0.split_at(encryption::NONCE_LEN);
+        let key = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[],
+                },
+            )
+            .map_err(|_| KeyExchangeError::Forged)?;
+
+        Ok(MediaKey(key.try_into().expect("Only keys are sealed")))
+    }
+}
+
+fn commitment(public_key: &PublicKey) -> Commitment {
+    let mut commitment = [0; COMMITMENT_LEN];
+    commitment.copy_from_slice(&hash(&[COMMITMENT_LABEL, &public_key.0])[..COMMITMENT_LEN]);
+    Commitment(commitment)
+}
+
+impl fmt::Display for Sas {
+    /// Six digits in two groups, easy to read out.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:03} {:03}", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+impl fmt::Debug for SealedKey {
+    // Meaningless to anyone reading logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SealedKey(..)")
+    }
+}
+
+impl fmt::Display for KeyExchangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyExchangeError::CommitmentMismatch => {
+                write!(f, "the public key does not match its commitment")
+            }
+            KeyExchangeError::InvalidKey => write!(f, "invalid public key"),
+            KeyExchangeError::Forged => write!(f, "the sealed key does not authenticate"),
+        }
+    }
+}
+
+impl std::error::Error for KeyExchangeError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROOM_KEY: MediaKey = MediaKey([3; encryption::KEY_LEN]);
+
+    /// Runs an exchange between `a` and `b` through an honest server.
+    fn run(a: KeyExchange, b: KeyExchange) -> (Agreement, Agreement) {
+        let (a_commitment, a_public_key) = (a.commitment(), a.public_key());
+        let (b_commitment, b_public_key) = (b.commitment(), b.public_key());
+
+        (
+            a.finish(&ROOM_KEY, &b_commitment, &b_public_key).unwrap(),
+            b.finish(&ROOM_KEY, &a_commitment, &a_public_key).unwrap(),
+        )
+    }
+
+    #[test]
+    fn both_sides_agree() {
+        let (a, b) = run(KeyExchange::new(), KeyExchange::new());
+        assert_eq!(a.sas(), b.sas());
+
+        let key = MediaKey([9; encryption::KEY_LEN]);
+        assert_eq!(b.open_key(&a.seal_key(&key)).unwrap().0, key.0);
+        assert_eq!(a.open_key(&b.seal_key(&key)).unwrap().0, key.0);
+    }
+
+    #[test]
+    fn someone_in_the_middle_shows_in_the_codes() {
+        // The server runs one exchange with each client instead of relaying theirs
+        let (a, a_middle) = run(KeyExchange::new(), KeyExchange::new());
+        let (b, b_middle) = run(KeyExchange::new(), KeyExchange::new());
+
+        assert_eq!(a.sas(), a_middle.sas());
+        assert_eq!(b.sas(), b_middle.sas());
+        // Equal one time in a million
+        assert_ne!(a.sas(), b.sas());
+
+        let key = MediaKey([9; encryption::KEY_LEN]);
+        assert_eq!(
+            b.open_key(&a.seal_key(&key)).err(),
+            Some(KeyExchangeError::Forged)
+        );
+    }
+
+    #[test]
+    fn keys_must_match_their_commitment() {
+        let (a, b, other) = (KeyExchange::new(), KeyExchange::new(), KeyExchange::new());
+
+        assert_eq!(
+            a.finish(&ROOM_KEY, &b.commitment(), &other.public_key())
+                .err(),
+            Some(KeyExchangeError::CommitmentMismatch)
+        );
+    }
+
+    #[test]
+    fn small_order_keys_are_rejected() {
+        let identity = PublicKey([0; PUBLIC_KEY_LEN]);
+
+        assert_eq!(
+            KeyExchange::new()
+                .finish(&ROOM_KEY, &commitment(&identity), &identity)
+                .err(),
+            Some(KeyExchangeError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn codes_read_as_six_digits() {
+        assert_eq!(Sas(42).to_string(), "000 042");
+        assert_eq!(Sas(123_456).to_string(), "123 456");
+    }
+}
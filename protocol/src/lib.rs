@@ -0,0 +1,18 @@
+//! Wire protocol shared by the simple_call server and its clients.
+//!
+//! Everything that crosses the network is defined here exactly once, so both
+//! sides always agree on the byte layout.
+
+pub mod addr;
+pub mod encryption;
+mod error;
+pub mod key_exchange;
+pub mod media;
+mod message;
+pub mod pake;
+pub mod relay;
+pub mod report;
+pub mod signal;
+
+pub use error::{DecodeError, ReadError};
+pub use message::Message;
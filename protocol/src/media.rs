@@ -0,0 +1,531 @@
+//! The header in front of every audio packet, laid out like an RTP header (RFC 3550).
+//!
+//! Each sender numbers its packets with a sequence number, and stamps them with the sample
+//! its audio starts at. Silent frames are not sent: they move the timestamp on but not the
+//! sequence number, and the packet after them carries the marker flag, so receivers tell
+//! silence apart from loss. A random SSRC tells a sender's streams apart if it starts over.
+//!
+//! Instead, as silence starts and every so often during it, the sender sends a comfort noise
+//! packet as RFC 3389 lays them out: the level of the background noise, which receivers play
+//! until the sender speaks again. They are numbered like audio, and keep the call alive.
+//!
+//! In a relayed call, the header goes after the relay's, see [`relay`](crate::relay). With
+//! [`encryption`](crate::encryption), it stays in the clear and the audio after it is sealed
+//! along with it.
+
+use std::time::Duration;
+
+use crate::report::{self, ReportBlock, SenderReport};
+
+/// Length of the header, without CSRCs or extensions, which we never send.
+pub const HEADER_LEN: usize = 12;
+
+/// RTP version, the top two bits of the first byte.
+const VERSION: u8 = 2;
+
+/// Payload type of Opus audio, one of the dynamic ones as usual for Opus.
+pub const PAYLOAD_TYPE_OPUS: u8 = 111;
+
+/// Payload type of comfort noise, the static one RFC 3551 gives it. Its payload is a single
+/// byte: the noise level in -dBov, from 0 to 127.
+pub const PAYLOAD_TYPE_COMFORT_NOISE: u8 = 13;
+
+/// How many packets before the latest one a receiver still tells duplicates apart for.
+const HISTORY: u64 = 64;
+
+/// What a packet says about itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaHeader {
+    /// First packet after silence.
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    /// The first sample of its audio, at 48 kHz.
+    pub timestamp: u32,
+    /// Random id of the sender's stream.
+    pub ssrc: u32,
+}
+
+/// Numbers and stamps the packets of one sender's stream.
+pub struct MediaSender {
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32,
+    /// Whether frames went unsent since the last packet.
+    silent: bool,
+}
+
+/// Follows one sender's stream as it arrives, telling which packets were lost, came late or
+/// came twice.
+#[derive(Default)]
+pub struct MediaReceiver {
+    /// The stream followed, once a packet of it arrived.
+    ssrc: Option<u32>,
+    /// Extended sequence numbers, counting the times the 16 bit ones wrapped: the first
+    /// packet's, and the highest yet.
+    first: u64,
+    highest: u64,
+    /// Which of the [`HISTORY`] packets up to `highest` arrived, `highest` being the lowest
+    /// bit.
+    seen: u64,
+    stats: MediaStats,
+    /// Packets expected and received at the last report, to tell what was lost since.
+    reported: (u64, u64),
+}
+
+/// How a packet fits in its stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// After every packet received so far, `lost` of them missing right before it.
+    InOrder { lost: u64 },
+    /// After a later one, its turn is over.
+    Late,
+    /// It arrived already.
+    Duplicate,
+}
+
+/// Counts of what happened to a stream so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MediaStats {
+    /// Packets that arrived, late or not, duplicates aside.
+    pub received: u64,
+    /// Packets that did not arrive, or not yet.
+    pub lost: u64,
+    /// Packets that arrived after a later one.
+    pub reordered: u64,
+    pub duplicates: u64,
+}
+
+impl MediaHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0] = VERSION << 6;
+        bytes[1] = (self.marker as u8) << 7 | self.payload_type & 0x7f;
+        bytes[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        bytes
+    }
+
+    /// Splits an audio packet into its header and payload, skipping CSRCs, extensions and
+    /// padding other RTP senders may add.
+    ///
+    /// Returns `None` if it is not an RTP packet, or too short for what its header says.
+    pub fn split(packet: &[u8]) -> Option<(MediaHeader, &[u8])> {
+        let (fixed, mut rest) = packet.split_first_chunk::<HEADER_LEN>()?;
+        if fixed[0] >> 6 != VERSION {
+            return None;
+        }
+        let padded = fixed[0] & 0x20 != 0;
+        let extended = fixed[0] & 0x10 != 0;
+        let csrc_count = (fixed[0] & 0x0f) as usize;
+
+        rest = rest.get(csrc_count * 4..)?;
+        if extended {
+            let (extension, _) = rest.split_first_chunk::<4>()?;
+            let words = u16::from_be_bytes([extension[2], extension[3]]) as usize;
+            rest = rest.get(4 + words * 4..)?;
+        }
+        if padded {
+            let padding = *rest.last()? as usize;
+            rest = rest.get(..rest.len().checked_sub(padding)?)?;
+        }
+
+        let header = MediaHeader {
+            marker: fixed[1] & 0x80 != 0,
+            payload_type: fixed[1] & 0x7f,
+            sequence: u16::from_be_bytes([fixed[2], fixed[3]]),
+            timestamp: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+            ssrc: u32::from_be_bytes([fixed[8], fixed[9], fixed[10], fixed[11]]),
+        };
+        Some((header, rest))
+    }
+}
+
+impl MediaSender {
+    /// Starts a stream, from a random SSRC, sequence number and timestamp as RFC 3550 asks.
+    pub fn new() -> Self {
+        let mut random = [0; 10];
+        getrandom::fill(&mut random).expect("The system has no source of randomness");
+
+        Self {
+            ssrc: u32::from_be_bytes([random[0], random[1], random[2], random[3]]),
+            sequence: u16::from_be_bytes([random[4], random[5]]),
+            timestamp: u32::from_be_bytes([random[6], random[7], random[8], random[9]]),
+            silent: true,
+        }
+    }
+
+    /// Header of the next packet, holding `samples` samples of audio.
+    pub fn next(&mut self, samples: u32) -> MediaHeader {
+        let header = self.header(PAYLOAD_TYPE_OPUS, samples);
+        self.silent = false;
+        header
+    }
+
+    /// Header of a comfort noise packet, standing for `samples` samples of silence. The
+    /// silence goes on, the next packet of audio starts a talkspurt.
+    pub fn comfort_noise(&mut self, samples: u32) -> MediaHeader {
+        let header = MediaHeader {
+            marker: false,
+            ..self.header(PAYLOAD_TYPE_COMFORT_NOISE, samples)
+        };
+        self.silent = true;
+        header
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Timestamp of the next packet, where the stream is at.
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Skips `samples` samples of silence, not sent.
+    pub fn skip(&mut self, samples: u32) {
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        self.silent = true;
+    }
+
+    fn header(&mut self, payload_type: u8, samples: u32) -> MediaHeader {
+        let header = MediaHeader {
+            marker: self.silent,
+            payload_type,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            ssrc: self.ssrc,
+        };
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        header
+    }
+}
+
+impl Default for MediaSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MediaReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes note of a packet with `header`. A new SSRC means the sender started over, its
+    /// stream is followed from scratch.
+    pub fn receive(&mut self, header: &MediaHeader) -> Arrival {
+        if self.ssrc != Some(header.ssrc) {
+            // Room below the first packet for those arriving after it
+            let first = 1 << 16 | header.sequence as u64;
+            *self = Self {
+                ssrc: Some(header.ssrc),
+                first,
+                highest: first,
+                seen: 1,
+                stats: MediaStats {
+                    received: 1,
+                    ..MediaStats::default()
+                },
+                ..Self::default()
+            };
+            return Arrival::InOrder { lost: 0 };
+        }
+
+        let sequence = self.extend(header.sequence);
+        if sequence > self.highest {
+            let ahead = sequence - self.highest;
+            self.seen = if ahead < HISTORY {
+                self.seen << ahead | 1
+            } else {
+                1
+            };
+            self.highest = sequence;
+            self.stats.received += 1;
+            self.update_lost();
+            return Arrival::InOrder { lost: ahead - 1 };
+        }
+
+        let behind = self.highest - sequence;
+        if behind < HISTORY {
+            if self.seen & 1 << behind != 0 {
+                self.stats.duplicates += 1;
+                return Arrival::Duplicate;
+            }
+            self.seen |= 1 << behind;
+        }
+        self.first = self.first.min(sequence);
+        self.stats.received += 1;
+        self.stats.reordered += 1;
+        self.update_lost();
+        Arrival::Late
+    }
+
+    pub fn stats(&self) -> MediaStats {
+        self.stats
+    }
+
+    /// The stream followed, once a packet of it arrived.
+    pub fn ssrc(&self) -> Option<u32> {
+        self.ssrc
+    }
+
+    /// A block reporting how the stream is heard, with its interarrival `jitter` and the last
+    /// sender report from it, with how long ago it came. The fraction lost counts the packets
+    /// lost since the last block.
+    ///
+    /// Returns `None` until a packet came.
+    pub fn report(
+        &mut self,
+        jitter: u32,
+        sender_report: Option<(&SenderReport, Duration)>,
+    ) -> Option<ReportBlock> {
+        let ssrc = self.ssrc?;
+
+        let expected = self.highest - self.first + 1;
+        let received = self.stats.received;
+        let (expected_before, received_before) = self.reported;
+        self.reported = (expected, received);
+
+        let expected_since = expected - expected_before;
+        let lost_since = expected_since.saturating_sub(received - received_before);
+        let fraction_lost = match expected_since {
+            0 => 0,
+            _ => (lost_since * 256 / expected_since).min(255) as u8,
+        };
+
+        let (last_sender_report, delay_since_sender_report) = sender_report
+            .map_or((0, 0), |(report, since)| {
+                (report.compact_time(), report::compact_duration(since))
+            });
+
+        Some(ReportBlock {
+            ssrc,
+            fraction_lost,
+            cumulative_lost: self.stats.lost.min(0xff_ffff) as u32,
+            // Extended sequence numbers start a wrap up
+            highest_sequence: (self.highest - (1 << 16)) as u32,
+            jitter,
+            last_sender_report,
+            delay_since_sender_report,
+        })
+    }
+
+    /// The extended sequence number closest to the highest one with these low 16 bits.
+    fn extend(&self, sequence: u16) -> u64 {
+        let candidate = self.highest & !0xffff | sequence as u64;
+        [
+            candidate.saturating_sub(1 << 16),
+            candidate,
+            candidate + (1 << 16),
+        ]
+        .into_iter()
+        .min_by_key(|extended| extended.abs_diff(self.highest))
+        .unwrap()
+    }
+
+    fn update_lost(&mut self) {
+        let expected = self.highest - self.first + 1;
+        self.stats.lost = expected.saturating_sub(self.stats.received);
+    }
+}
+
+impl MediaStats {
+    /// Adds up the counts of several streams.
+    pub fn add(&mut self, other: &MediaStats) {
+        self.received += other.received;
+        self.lost += other.lost;
+        self.reordered += other.reordered;
+        self.duplicates += other.duplicates;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(sequence: u16) -> MediaHeader {
+        MediaHeader {
+            marker: false,
+            payload_type: PAYLOAD_TYPE_OPUS,
+            sequence,
+            timestamp: 0,
+            ssrc: 7,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let header = MediaHeader {
+            marker: true,
+            payload_type: PAYLOAD_TYPE_OPUS,
+            sequence: 0xfffe,
+            timestamp: 0x0102_0304,
+            ssrc: 0xdead_beef,
+        };
+        let packet = [&header.to_bytes()[..], b"opus"].concat();
+
+        assert_eq!(packet[0], 0x80, "Version 2, no padding, extension or CSRCs");
+        assert_eq!(MediaHeader::split(&packet), Some((header, &b"opus"[..])));
+    }
+
+    #[test]
+    fn csrcs_extensions_and_padding_are_skipped() {
+        let mut packet = header(1).to_bytes().to_vec();
+        packet[0] |= 0x20 | 0x10 | 1;
+        packet.extend_from_slice(&[0; 4]); // One CSRC
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 1, 0, 0, 0, 0]); // A one word extension
+        packet.extend_from_slice(b"opus");
+        packet.extend_from_slice(&[0, 0, 3]); // Three bytes of padding
+
+        assert_eq!(MediaHeader::split(&packet), Some((header(1), &b"opus"[..])));
+
+        // Claiming more than there is
+        packet.truncate(HEADER_LEN + 6);
+        assert_eq!(MediaHeader::split(&packet), None);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut packet = header(1).to_bytes();
+        packet[0] = 1 << 6;
+
+        assert_eq!(MediaHeader::split(&packet), None);
+        assert_eq!(MediaHeader::split(&packet[..HEADER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn silence_moves_the_timestamp_but_not_the_sequence() {
+        let mut sender = MediaSender::new();
+
+        let first = sender.next(960);
+        assert!(first.marker, "The stream starts with a talkspurt");
+        let second = sender.next(960);
+        assert!(!second.marker);
+        assert_eq!(second.sequence, first.sequence.wrapping_add(1));
+        assert_eq!(second.timestamp, first.timestamp.wrapping_add(960));
+
+        sender.skip(960);
+        sender.skip(960);
+        let after_silence = sender.next(960);
+        assert!(after_silence.marker);
+        assert_eq!(after_silence.sequence, second.sequence.wrapping_add(1));
+        assert_eq!(
+            after_silence.timestamp,
+            second.timestamp.wrapping_add(3 * 960)
+        );
+        assert_eq!(after_silence.ssrc, first.ssrc);
+    }
+
+    #[test]
+    fn comfort_noise_is_numbered_but_does_not_end_silence() {
+        let mut sender = MediaSender::new();
+        let speech = sender.next(960);
+
+        let noise = sender.comfort_noise(960);
+        assert_eq!(noise.payload_type, PAYLOAD_TYPE_COMFORT_NOISE);
+        assert!(!noise.marker);
+        assert_eq!(noise.sequence, speech.sequence.wrapping_add(1));
+        assert_eq!(noise.timestamp, speech.timestamp.wrapping_add(960));
+
+        sender.skip(960);
+        let after_silence = sender.next(960);
+        assert!(after_silence.marker);
+        assert_eq!(after_silence.sequence, noise.sequence.wrapping_add(1));
+        assert_eq!(
+            after_silence.timestamp,
+            noise.timestamp.wrapping_add(2 * 960)
+        );
+    }
+
+    #[test]
+    fn loss_reordering_and_duplicates_are_told_apart() {
+        let mut receiver = MediaReceiver::new();
+
+        assert_eq!(receiver.receive(&header(10)), Arrival::InOrder { lost: 0 });
+        assert_eq!(receiver.receive(&header(13)), Arrival::InOrder { lost: 2 });
+        assert_eq!(receiver.receive(&header(11)), Arrival::Late);
+        assert_eq!(receiver.receive(&header(11)), Arrival::Duplicate);
+        assert_eq!(receiver.receive(&header(14)), Arrival::InOrder { lost: 0 });
+
+        assert_eq!(
+            receiver.stats(),
+            MediaStats {
+                received: 4,
+                lost: 1,
+                reordered: 1,
+                duplicates: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut receiver = MediaReceiver::new();
+
+        receiver.receive(&header(0xfffe));
+        assert_eq!(receiver.receive(&header(1)), Arrival::InOrder { lost: 2 });
+        assert_eq!(receiver.receive(&header(0xffff)), Arrival::Late);
+        assert_eq!(receiver.stats().lost, 1);
+
+        // Before the first packet, it was lost until now
+        assert_eq!(receiver.receive(&header(0xfffd)), Arrival::Late);
+        assert_eq!(receiver.stats().lost, 1);
+    }
+
+    #[test]
+    fn reports_count_losses_since_the_last() {
+        let mut receiver = MediaReceiver::new();
+        assert_eq!(receiver.report(0, None), None);
+
+        for sequence in [0xfffe, 0xffff, 1, 2] {
+            receiver.receive(&header(sequence));
+        }
+        let sender_report = SenderReport {
+            ssrc: 7,
+            ntp_time: 0x0001_0002_0003_0004,
+            timestamp: 0,
+            packets: 0,
+            octets: 0,
+        };
+        let block = receiver
+            .report(480, Some((&sender_report, Duration::from_millis(500))))
+            .unwrap();
+        assert_eq!(
+            block,
+            ReportBlock {
+                ssrc: 7,
+                fraction_lost: 51, // One in five, out of 256
+                cumulative_lost: 1,
+                highest_sequence: 0x0001_0002,
+                jitter: 480,
+                last_sender_report: 0x0002_0003,
+                delay_since_sender_report: 0x8000,
+            }
+        );
+
+        // Nothing lost since
+        receiver.receive(&header(3));
+        let block = receiver.report(0, None).unwrap();
+        assert_eq!(block.fraction_lost, 0);
+        assert_eq!(block.cumulative_lost, 1);
+        assert_eq!(block.last_sender_report, 0);
+    }
+
+    #[test]
+    fn a_new_ssrc_starts_over() {
+        let mut receiver = MediaReceiver::new();
+        receiver.receive(&header(10));
+        receiver.receive(&header(20));
+
+        let restarted = MediaHeader {
+            ssrc: 8,
+            ..header(3)
+        };
+        assert_eq!(receiver.receive(&restarted), Arrival::InOrder { lost: 0 });
+        assert_eq!(receiver.stats().lost, 0);
+    }
+}
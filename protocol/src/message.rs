@@ -0,0 +1,50 @@
+use std::io::{Read, Write};
+
+use crate::{DecodeError, ReadError};
+
+/// A message that can be written to and read from a byte stream.
+pub trait Message: Sized {
+    /// Append the wire representation of `self` to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decode one message from the start of `buf`.
+    ///
+    /// Returns the message and how many bytes of `buf` it used.
+    fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError>;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    /// Read exactly one message from `reader`, never consuming bytes past its end.
+    fn read_from(reader: &mut impl Read) -> Result<Self, ReadError> {
+        let mut buf = Vec::new();
+
+        loop {
+            match Self::decode(&buf) {
+                Ok((message, _)) => return Ok(message),
+                Err(DecodeError::Incomplete(needed)) => {
+                    let filled = buf.len();
+                    buf.resize(filled + needed, 0);
+                    reader.read_exact(&mut buf[filled..])?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Fail with [`DecodeError::Incomplete`] unless `buf` holds at least `len` bytes.
+pub(crate) fn ensure_len(buf: &[u8], len: usize) -> Result<(), DecodeError> {
+    if buf.len() < len {
+        Err(DecodeError::Incomplete(len - buf.len()))
+    } else {
+        Ok(())
+    }
+}
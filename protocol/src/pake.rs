@@ -0,0 +1,279 @@
+//! Password-authenticated key exchange proving that clients sharing a room know its secret.
+//!
+//! This is CPace over ristretto255: the room secret is hashed to a generator, each client sends
+//! a random multiple of it as its [`KeyShare`], and both derive the same key from the other's
+//! share only if they started from the same secret. Each then sends a [`Confirmation`] of the
+//! key for the other to check. Whoever relays the exchange learns nothing to guess the secret
+//! offline with, and can only test one guess per exchange it takes part in.
+//!
+//! Clients claiming moderation of a call prove the same way that they hold the server's
+//! moderator secret, the server taking the other side of the exchange.
+
+use std::fmt;
+
+use curve25519_dalek::{
+    RistrettoPoint, Scalar, ristretto::CompressedRistretto, traits::IsIdentity,
+};
+use sha2::{Digest, Sha512};
+
+use crate::signal::RoomHash;
+
+/// Length of a [`KeyShare`] on the wire.
+pub const KEY_SHARE_LEN: usize = 32;
+/// Length of a [`Confirmation`] on the wire.
+pub const CONFIRMATION_LEN: usize = 32;
+/// Length of the key both sides of an exchange agree on.
+pub const SESSION_KEY_LEN: usize = 32;
+
+/// Tells hashes made for different purposes apart.
+const GENERATOR_LABEL: &[u8] = b"simple_call CPace generator";
+const SESSION_KEY_LABEL: &[u8] = b"simple_call CPace session key";
+const CONFIRMATION_LABEL: &[u8] = b"simple_call CPace confirmation";
+/// Takes the place of the room hash in exchanges about the moderator secret.
+const MODERATOR_LABEL: &[u8] = b"simple_call CPace moderator";
+
+/// What a client sends to whoever it proves it knows the room secret to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyShare(pub [u8; KEY_SHARE_LEN]);
+
+/// Proof that a client derived the key of an exchange.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Confirmation(pub [u8; CONFIRMATION_LEN]);
+
+/// A client's side of the exchanges it takes part in. Its share may be sent to several peers,
+/// each exchange with one of them gives a different key.
+pub struct Pake {
+    /// Our share is the generator times this.
+    scalar: Scalar,
+    share: KeyShare,
+    room_hash: RoomHash,
+}
+
+/// An exchange with one peer, once its share arrived.
+pub struct Exchange {
+    session_key: [u8; SESSION_KEY_LEN],
+    confirmation: Confirmation,
+    expected: Confirmation,
+}
+
+/// Why an exchange failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PakeError {
+    /// The peer's share is not a valid group element.
+    InvalidShare,
+    /// The peer's confirmation does not match: it does not know the same room secret.
+    Mismatch,
+}
+
+impl Pake {
+    /// Starts proving we know `secret`, the secret of the room `room_hash`.
+    pub fn new(room_hash: &RoomHash, secret: &[u8]) -> Self {
+        let generator =
+            RistrettoPoint::from_uniform_bytes(&hash(&[GENERATOR_LABEL, room_hash, secret]));
+
+        let mut random = [0; 64];
+        getrandom::fill(&mut random).expect("The system has no source of randomness");
+        let scalar = Scalar::from_bytes_mod_order_wide(&random);
+
+        let share = KeyShare((generator * scalar).compress().to_bytes());
+
+        Self {
+            scalar,
+            share,
+            room_hash: *room_hash,
+        }
+    }
+
+    /// Starts proving we know the moderator `secret`, or checking that a client does.
+    pub fn moderator(secret: &[u8]) -> Self {
+        Self::new(&hash(&[MODERATOR_LABEL]), secret)
+    }
+
+    /// What to send our peers.
+    pub fn share(&self) -> KeyShare {
+        self.share
+    }
+
+    /// Derives the key of the exchange with the peer that sent `peer_share`.
+    pub fn exchange(&self, peer_share: &KeyShare) -> Result<Exchange, PakeError> {
+        let peer_point = CompressedRistretto(peer_share.0)
+            .decompress()
+            .filter(|point| !point.is_identity())
+            .ok_or(PakeError::InvalidShare)?;
+        let shared = (peer_point * self.scalar).compress();
+
+        // Both sides list the shares in the same order
+        let (low, high) = if self.share.0 <= peer_share.0 {
+            (&self.share, peer_share)
+        } else {
+            (peer_share, &self.share)
+        };
+        let mut session_key = [0; SESSION_KEY_LEN];
+        session_key.copy_from_slice(
+            &hash(&[
+                SESSION_KEY_LABEL,
+                &self.room_hash,
+                shared.as_bytes(),
+                &low.0,
+                &high.0,
+            ])[..SESSION_KEY_LEN],
+        );
+
+        Ok(Exchange {
+            confirmation: confirmation(&session_key, &self.share),
+            expected: confirmation(&session_key, peer_share),
+            session_key,
+        })
+    }
+}
+
+impl Exchange {
+    /// What to send the peer to prove we derived the key.
+    pub fn confirmation(&self) -> Confirmation {
+        self.confirmation
+    }
+
+    /// Checks the peer's confirmation, handing out the key both sides now share if it matches.
+    pub fn finish(self, peer: &Confirmation) -> Result<[u8; SESSION_KEY_LEN], PakeError> {
+        let diff = self
+            .expected
+            .0
+            .iter()
+            .zip(peer.0)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+
+        if diff == 0 {
+            Ok(self.session_key)
+        } else {
+            Err(PakeError::Mismatch)
+        }
+    }
+}
+
+/// Confirms `session_key` on behalf of whoever sent `share`, so the two sides' confirmations
+/// differ and one cannot be reflected back as the other.
+fn confirmation(session_key: &[u8; SESSION_KEY_LEN], share: &KeyShare) -> Confirmation {
+    let mut confirmation = [0; CONFIRMATION_LEN];
+    confirmation
+        .copy_from_slice(&hash(&[CONFIRMATION_LABEL, session_key, &share.0])[..CONFIRMATION_LEN]);
+    Confirmation(confirmation)
+}
+
+/// SHA-512 of `parts`, each prefixed with its length so they cannot run into one another.
+pub(crate) fn hash(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+impl fmt::Debug for Confirmation {
+    // Not secret, but meaningless to anyone reading logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Confirmation(..)")
+    }
+}
+
+impl fmt::Display for PakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PakeError::InvalidShare => write!(f, "invalid key share"),
+            PakeError::Mismatch => write!(f, "the room secrets do not match"),
+        }
+    }
+}
+
+impl std::error::Error for PakeError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROOM: RoomHash = [7; 64];
+
+    /// Runs an exchange between two clients, returning the keys each ends up with.
+    fn run(
+        a: &Pake,
+        b: &Pake,
+    ) -> (
+        Result<[u8; SESSION_KEY_LEN], PakeError>,
+        Result<[u8; SESSION_KEY_LEN], PakeError>,
+    ) {
+        let a_exchange = a.exchange(&b.share()).unwrap();
+        let b_exchange = b.exchange(&a.share()).unwrap();
+        let (a_confirmation, b_confirmation) =
+            (a_exchange.confirmation(), b_exchange.confirmation());
+
+        (
+            a_exchange.finish(&b_confirmation),
+            b_exchange.finish(&a_confirmation),
+        )
+    }
+
+    #[test]
+    fn same_secret_agrees_on_a_key() {
+        let (a, b) = (Pake::new(&ROOM, b"secret"), Pake::new(&ROOM, b"secret"));
+
+        let (a_key, b_key) = run(&a, &b);
+        assert_eq!(a_key.unwrap(), b_key.unwrap());
+
+        // Every exchange gives a different key
+        let c = Pake::new(&ROOM, b"secret");
+        assert_ne!(run(&a, &c).0.unwrap(), a_key.unwrap());
+    }
+
+    #[test]
+    fn different_secrets_do_not_match() {
+        let (a, b) = (Pake::new(&ROOM, b"secret"), Pake::new(&ROOM, b"guess"));
+        assert_eq!(
+            run(&a, &b),
+            (Err(PakeError::Mismatch), Err(PakeError::Mismatch))
+        );
+
+        // The same secret for another room is another secret
+        let c = Pake::new(&[8; 64], b"secret");
+        assert_eq!(
+            run(&a, &c),
+            (Err(PakeError::Mismatch), Err(PakeError::Mismatch))
+        );
+    }
+
+    #[test]
+    fn moderator_secrets_are_not_room_secrets() {
+        let (a, b) = (Pake::moderator(b"secret"), Pake::moderator(b"secret"));
+        let (a_key, b_key) = run(&a, &b);
+        assert_eq!(a_key.unwrap(), b_key.unwrap());
+
+        let room = Pake::new(&ROOM, b"secret");
+        assert_eq!(
+            run(&a, &room),
+            (Err(PakeError::Mismatch), Err(PakeError::Mismatch))
+        );
+    }
+
+    #[test]
+    fn confirmations_cannot_be_reflected() {
+        let (a, b) = (Pake::new(&ROOM, b"secret"), Pake::new(&ROOM, b"secret"));
+        let exchange = a.exchange(&b.share()).unwrap();
+        let own = exchange.confirmation();
+
+        assert_eq!(exchange.finish(&own), Err(PakeError::Mismatch));
+    }
+
+    #[test]
+    fn invalid_shares_are_rejected() {
+        let a = Pake::new(&ROOM, b"secret");
+        let identity = KeyShare(CompressedRistretto::default().0);
+
+        assert!(matches!(
+            a.exchange(&identity),
+            Err(PakeError::InvalidShare)
+        ));
+        assert!(matches!(
+            a.exchange(&KeyShare([0xff; KEY_SHARE_LEN])),
+            Err(PakeError::InvalidShare)
+        ));
+    }
+}
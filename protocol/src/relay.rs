@@ -0,0 +1,135 @@
+//! Framing of the datagrams clients send to the server's UDP sockets.
+//!
+//! Each datagram starts with the sender's session id, so that a single server port can
+//! carry many calls. What the server forwards to a client starts with the session id of the
+//! participant who sent it, see [`forwarded`], so group calls can tell their voices apart.
+//! A server mixing the call sends each client a single stream instead, from
+//! [`SessionId::MIXER`].
+//!
+//! A client registers its address with a datagram holding just its session id and its
+//! [`Token`], see [`registration`].
+
+use std::fmt;
+
+/// Identifies a client's media stream to the server, handed out in
+/// [`ServerMessage::PartnerFound`](crate::signal::ServerMessage::PartnerFound).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(pub u32);
+
+/// Length of the header in front of every datagram sent to the server.
+pub const HEADER_LEN: usize = 4;
+
+/// Length of a [`Token`].
+pub const TOKEN_LEN: usize = 16;
+
+/// A random secret the server hands a client over the signaling connection, which the
+/// client proves its registration datagram comes from it with.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Token(pub [u8; TOKEN_LEN]);
+
+impl SessionId {
+    /// Never handed out to a client, it sends the audio the server mixed for a participant.
+    pub const MIXER: SessionId = SessionId(0);
+
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl Token {
+    /// Whether `bytes` is this token, taking the same time wherever they differ.
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        bytes.len() == TOKEN_LEN
+            && self
+                .0
+                .iter()
+                .zip(bytes)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for Token {
+    // Secrets stay out of logs and panic messages
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+/// The datagram a client registers its address with.
+pub fn registration(session_id: SessionId, token: &Token) -> [u8; HEADER_LEN + TOKEN_LEN] {
+    let mut datagram = [0; HEADER_LEN + TOKEN_LEN];
+    datagram[..HEADER_LEN].copy_from_slice(&session_id.to_bytes());
+    datagram[HEADER_LEN..].copy_from_slice(&token.0);
+    datagram
+}
+
+/// The datagram the relay sends on for `sender`, who sent `payload`.
+pub fn forwarded(sender: SessionId, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_LEN + payload.len());
+    datagram.extend_from_slice(&sender.to_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// Splits a datagram sent to or forwarded by the server into the sender's session id and the
+/// payload.
+///
+/// Returns `None` if it is too short to even hold the header.
+pub fn split(datagram: &[u8]) -> Option<(SessionId, &[u8])> {
+    let (header, payload) = datagram.split_first_chunk::<HEADER_LEN>()?;
+    Some((SessionId(u32::from_be_bytes(*header)), payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let id = SessionId(0x0102_0304);
+
+        let mut datagram = id.to_bytes().to_vec();
+        datagram.extend_from_slice(b"opus");
+
+        assert_eq!(split(&datagram), Some((id, &b"opus"[..])));
+    }
+
+    #[test]
+    fn registration_carries_token() {
+        let token = Token([7; TOKEN_LEN]);
+        let datagram = registration(SessionId(9), &token);
+
+        let (id, payload) = split(&datagram).unwrap();
+        assert_eq!(id, SessionId(9));
+        assert!(token.matches(payload));
+    }
+
+    #[test]
+    fn token_matches_only_itself() {
+        let token = Token([7; TOKEN_LEN]);
+
+        assert!(!token.matches(&[7; TOKEN_LEN - 1]));
+        assert!(!token.matches(&[7; TOKEN_LEN + 1]));
+        assert!(!token.matches(&[[7; TOKEN_LEN - 1].as_slice(), &[8]].concat()));
+        assert_eq!(format!("{:?}", token), "Token(..)");
+    }
+
+    #[test]
+    fn forwarded_names_the_sender() {
+        let datagram = forwarded(SessionId(3), b"opus");
+
+        assert_eq!(split(&datagram), Some((SessionId(3), &b"opus"[..])));
+    }
+
+    #[test]
+    fn too_short() {
+        assert_eq!(split(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,322 @@
+//! Reports on how audio is heard, laid out like RTCP sender and receiver reports (RFC 3550).
+//!
+//! They travel next to the audio, over the same path: RTCP packet types do not collide with
+//! the payload types of [`media`](crate::media) headers, see RFC 5761. Every so often, each
+//! participant sends a sender report about what it sent, and a receiver report with a block
+//! about every stream it hears. A block echoes the time of the last sender report from the
+//! stream, and how long ago it came, so that the sender can tell the round trip.
+//!
+//! With [`encryption`](crate::encryption), a report's first [`HEADER_LEN`] bytes stay in the
+//! clear, telling it from audio, and the rest is sealed along with them as audio is: senders
+//! size their FEC from what reports say, only those in the call may make them up.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// RTP version, the top two bits of the first byte.
+const VERSION: u8 = 2;
+
+/// RTCP packet types.
+const SENDER_REPORT: u8 = 200;
+const RECEIVER_REPORT: u8 = 201;
+
+/// Length of the common header with the sender's SSRC.
+pub const HEADER_LEN: usize = 8;
+/// Lengths of the sender information, and of a report block.
+const SENDER_INFO_LEN: usize = 20;
+const BLOCK_LEN: usize = 24;
+
+/// Most blocks a report holds, its count has 5 bits.
+pub const MAX_BLOCKS: usize = 31;
+
+/// Seconds from the NTP epoch, 1900, to the Unix one.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// A sender report, without blocks: we send those in receiver reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderReport {
+    pub ssrc: u32,
+    /// When it was sent, see [`ntp_time`].
+    pub ntp_time: u64,
+    /// The same time, in the stream's timestamps.
+    pub timestamp: u32,
+    /// Packets and payload bytes sent since the stream started.
+    pub packets: u32,
+    pub octets: u32,
+}
+
+/// How the stream of `ssrc` was heard, by whoever sent the report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    /// Share of the packets expected since the last report that were lost, out of 256.
+    pub fraction_lost: u8,
+    /// Packets lost since the stream started, on 24 bits.
+    pub cumulative_lost: u32,
+    /// Highest sequence number received, extended by the times it wrapped.
+    pub highest_sequence: u32,
+    /// Interarrival jitter, in timestamp units.
+    pub jitter: u32,
+    /// Middle 32 bits of the last sender report's NTP time, 0 if none came.
+    pub last_sender_report: u32,
+    /// How long since that sender report came, in 1/65536 seconds.
+    pub delay_since_sender_report: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Report {
+    Sender(SenderReport),
+    /// From the stream `ssrc`, about those it hears.
+    Receiver {
+        ssrc: u32,
+        blocks: Vec<ReportBlock>,
+    },
+}
+
+impl Report {
+    /// Lays the report out. Receiver reports keep their first [`MAX_BLOCKS`] blocks.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (packet_type, ssrc, count) = match self {
+            Report::Sender(report) => (SENDER_REPORT, report.ssrc, 0),
+            Report::Receiver { ssrc, blocks } => {
+                (RECEIVER_REPORT, *ssrc, blocks.len().min(MAX_BLOCKS))
+            }
+        };
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + SENDER_INFO_LEN + count * BLOCK_LEN);
+        bytes.push(VERSION << 6 | count as u8);
+        bytes.push(packet_type);
+        bytes.extend_from_slice(&[0, 0]); // Length, once known
+        bytes.extend_from_slice(&ssrc.to_be_bytes());
+
+        match self {
+            Report::Sender(report) => {
+                bytes.extend_from_slice(&report.ntp_time.to_be_bytes());
+                bytes.extend_from_slice(&report.timestamp.to_be_bytes());
+                bytes.extend_from_slice(&report.packets.to_be_bytes());
+                bytes.extend_from_slice(&report.octets.to_be_bytes());
+            }
+            Report::Receiver { blocks, .. } => {
+                for block in &blocks[..count] {
+                    bytes.extend_from_slice(&block.ssrc.to_be_bytes());
+                    let lost = block.cumulative_lost.min(0x7f_ffff);
+                    bytes.extend_from_slice(
+                        &(u32::from(block.fraction_lost) << 24 | lost).to_be_bytes(),
+                    );
+                    bytes.extend_from_slice(&block.highest_sequence.to_be_bytes());
+                    bytes.extend_from_slice(&block.jitter.to_be_bytes());
+                    bytes.extend_from_slice(&block.last_sender_report.to_be_bytes());
+                    bytes.extend_from_slice(&block.delay_since_sender_report.to_be_bytes());
+                }
+            }
+        }
+
+        // In 32 bit words, minus one
+        let words = (bytes.len() / 4 - 1) as u16;
+        bytes[2..4].copy_from_slice(&words.to_be_bytes());
+        bytes
+    }
+
+    /// Reads a report, the first of a compound packet. Returns `None` if it is not a sender
+    /// or receiver report, or is cut short.
+    pub fn parse(packet: &[u8]) -> Option<Report> {
+        if !is_report(packet) || packet.len() < HEADER_LEN {
+            return None;
+        }
+        let count = (packet[0] & 0x1f) as usize;
+        let len = (u16::from_be_bytes([packet[2], packet[3]]) as usize + 1) * 4;
+        if len < HEADER_LEN {
+            return None;
+        }
+        let packet = packet.get(..len)?;
+        let ssrc = read_u32(packet, 4);
+
+        match packet[1] {
+            SENDER_REPORT if packet.len() >= HEADER_LEN + SENDER_INFO_LEN => {
+                Some(Report::Sender(SenderReport {
+                    ssrc,
+                    ntp_time: (read_u32(packet, 8) as u64) << 32 | read_u32(packet, 12) as u64,
+                    timestamp: read_u32(packet, 16),
+                    packets: read_u32(packet, 20),
+                    octets: read_u32(packet, 24),
+                }))
+            }
+            RECEIVER_REPORT if packet.len() >= HEADER_LEN + count * BLOCK_LEN => {
+                let blocks = packet[HEADER_LEN..HEADER_LEN + count * BLOCK_LEN]
+                    .chunks_exact(BLOCK_LEN)
+                    .map(|block| {
+                        let lost = read_u32(block, 4);
+                        ReportBlock {
+                            ssrc: read_u32(block, 0),
+                            fraction_lost: (lost >> 24) as u8,
+                            cumulative_lost: lost & 0xff_ffff,
+                            highest_sequence: read_u32(block, 8),
+                            jitter: read_u32(block, 12),
+                            last_sender_report: read_u32(block, 16),
+                            delay_since_sender_report: read_u32(block, 20),
+                        }
+                    })
+                    .collect();
+                Some(Report::Receiver { ssrc, blocks })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SenderReport {
+    /// The middle 32 bits of its time, what blocks echo.
+    pub fn compact_time(&self) -> u32 {
+        (self.ntp_time >> 16) as u32
+    }
+}
+
+impl ReportBlock {
+    /// The round trip to whoever sent the block and back, which came at `arrival`, an NTP
+    /// time. `None` if they did not hear a sender report of ours yet.
+    pub fn round_trip(&self, arrival: u64) -> Option<Duration> {
+        if self.last_sender_report == 0 {
+            return None;
+        }
+        let round_trip = ((arrival >> 16) as u32)
+            .wrapping_sub(self.last_sender_report)
+            .wrapping_sub(self.delay_since_sender_report);
+        // Clocks do not go back, unless the block is made up
+        (round_trip < 1 << 31).then(|| Duration::from_secs_f64(round_trip as f64 / 65536.0))
+    }
+}
+
+/// Whether `packet` is a report rather than audio: RTCP packet types take the place of the
+/// marker and payload type in media headers.
+pub fn is_report(packet: &[u8]) -> bool {
+    packet.len() >= 2 && packet[0] >> 6 == VERSION && (192..=223).contains(&packet[1])
+}
+
+/// The current time as NTP lays it out: seconds since 1900 in the top 32 bits, and their
+/// fraction in the bottom ones.
+pub fn ntp_time() -> u64 {
+    let since_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_unix.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = ((since_unix.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    seconds << 32 | fraction
+}
+
+/// Converts a duration to the 1/65536 seconds blocks count delays in.
+pub fn compact_duration(duration: Duration) -> u32 {
+    (duration.as_secs_f64() * 65536.0).min(u32::MAX as f64) as u32
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::media::{MediaHeader, PAYLOAD_TYPE_COMFORT_NOISE, PAYLOAD_TYPE_OPUS};
+
+    fn block(ssrc: u32) -> ReportBlock {
+        ReportBlock {
+            ssrc,
+            fraction_lost: 64,
+            cumulative_lost: 12,
+            highest_sequence: 0x0001_0002,
+            jitter: 480,
+            last_sender_report: 0x1234_5678,
+            delay_since_sender_report: 0x0001_0000,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let sender = Report::Sender(SenderReport {
+            ssrc: 7,
+            ntp_time: ntp_time(),
+            timestamp: 0xdead_beef,
+            packets: 100,
+            octets: 10_000,
+        });
+        let bytes = sender.to_bytes();
+        assert_eq!(bytes.len(), 28);
+        assert_eq!(Report::parse(&bytes), Some(sender));
+
+        let receiver = Report::Receiver {
+            ssrc: 7,
+            blocks: vec![block(8), block(9)],
+        };
+        let bytes = receiver.to_bytes();
+        assert_eq!(bytes.len(), 8 + 2 * 24);
+        assert_eq!(bytes[0], 0x82, "Version 2, two blocks");
+        assert_eq!(u16::from_be_bytes([bytes[2], bytes[3]]), 13);
+        assert_eq!(Report::parse(&bytes), Some(receiver));
+
+        assert_eq!(Report::parse(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn lengths_too_short_for_the_header_are_refused() {
+        let mut bytes = Report::Receiver {
+            ssrc: 7,
+            blocks: vec![],
+        }
+        .to_bytes();
+        bytes[2..4].copy_from_slice(&0u16.to_be_bytes());
+        assert_eq!(Report::parse(&bytes), None);
+
+        assert_eq!(
+            Report::parse(&[0x80, SENDER_REPORT, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn reports_are_told_from_audio() {
+        for payload_type in [PAYLOAD_TYPE_OPUS, PAYLOAD_TYPE_COMFORT_NOISE] {
+            for marker in [false, true] {
+                let header = MediaHeader {
+                    marker,
+                    payload_type,
+                    sequence: 1,
+                    timestamp: 2,
+                    ssrc: 3,
+                };
+                assert!(!is_report(&header.to_bytes()));
+            }
+        }
+
+        let report = Report::Receiver {
+            ssrc: 7,
+            blocks: vec![],
+        };
+        assert!(is_report(&report.to_bytes()));
+    }
+
+    #[test]
+    fn round_trips_take_out_the_delay_of_the_reporter() {
+        let sent = ntp_time();
+        let report = SenderReport {
+            ssrc: 7,
+            ntp_time: sent,
+            timestamp: 0,
+            packets: 0,
+            octets: 0,
+        };
+
+        // Held for a second by the reporter, two tenths of a second on the way
+        let block = ReportBlock {
+            last_sender_report: report.compact_time(),
+            delay_since_sender_report: compact_duration(Duration::from_secs(1)),
+            ..block(7)
+        };
+        let arrival = sent + (12 << 32) / 10;
+        let round_trip = block.round_trip(arrival).unwrap();
+        assert!(round_trip.abs_diff(Duration::from_millis(200)) < Duration::from_millis(1));
+
+        let never_heard = ReportBlock {
+            last_sender_report: 0,
+            ..block
+        };
+        assert_eq!(never_heard.round_trip(arrival), None);
+    }
+}
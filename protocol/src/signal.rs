@@ -0,0 +1,1019 @@
+//! Messages exchanged over the TCP signaling connection.
+//!
+//! Every message starts with a one byte tag identifying it, followed by its fields.
+
+use std::{fmt, net::SocketAddr, ops::BitAnd};
+
+use crate::{
+    DecodeError, Message, addr,
+    key_exchange::{
+        COMMITMENT_LEN, Commitment, PUBLIC_KEY_LEN, PublicKey, SEALED_KEY_LEN, SealedKey,
+    },
+    message::ensure_len,
+    pake::{CONFIRMATION_LEN, Confirmation, KEY_SHARE_LEN, KeyShare},
+    relay::{SessionId, TOKEN_LEN, Token},
+};
+
+/// Version of the signaling protocol implemented by this crate.
+///
+/// Must be increased whenever the byte layout of any message changes.
+pub const PROTOCOL_VERSION: u16 = 15;
+
+/// Oldest protocol version this crate can still talk to.
+pub const MIN_SUPPORTED_VERSION: u16 = 15;
+
+/// Sent right after the hello tag so that peers speaking something else entirely are detected.
+const HELLO_MAGIC: [u8; 2] = *b"SC";
+
+/// Length of the SHA-512 hash of a room's name, identifying it. The name is no secret, those
+/// joining the room prove they know its secret with [`crate::pake`].
+pub const ROOM_HASH_LEN: usize = 64;
+
+pub type RoomHash = [u8; ROOM_HASH_LEN];
+
+// Client message tags
+
+const CLIENT_JOIN_ROOM: u8 = 1;
+const CLIENT_READY: u8 = 3;
+const CLIENT_HELLO: u8 = 4;
+const CLIENT_HANGUP: u8 = 5;
+const CLIENT_MODERATE: u8 = 6;
+const CLIENT_LIST_PARTICIPANTS: u8 = 7;
+const CLIENT_SET_MUTED: u8 = 8;
+const CLIENT_KICK: u8 = 9;
+const CLIENT_SET_LOCKED: u8 = 10;
+const CLIENT_KEY_SHARE: u8 = 11;
+const CLIENT_KEY_CONFIRMATION: u8 = 12;
+const CLIENT_PEER_VERIFIED: u8 = 13;
+const CLIENT_KEY_COMMITMENT: u8 = 14;
+const CLIENT_PUBLIC_KEY: u8 = 15;
+const CLIENT_SENDER_KEY: u8 = 16;
+const CLIENT_MODERATOR_CONFIRMATION: u8 = 17;
+
+// Server message tags
+
+const SIGNAL_WAITING_IN_ROOM: u8 = 1;
+const SIGNAL_PARTNER_FOUND: u8 = 2;
+const SIGNAL_READY: u8 = 3;
+const SIGNAL_PEER_ADDRESS: u8 = 4;
+const SIGNAL_HELLO: u8 = 5;
+const SIGNAL_ERROR: u8 = 6;
+const SIGNAL_PARTNER_LEFT: u8 = 7;
+const SIGNAL_PARTICIPANT_LEFT: u8 = 8;
+const SIGNAL_PARTICIPANTS: u8 = 9;
+const SIGNAL_MODERATOR: u8 = 10;
+const SIGNAL_MUTED: u8 = 11;
+const SIGNAL_NOT_ALLOWED: u8 = 12;
+const SIGNAL_PEER_KEY_SHARE: u8 = 13;
+const SIGNAL_PEER_KEY_CONFIRMATION: u8 = 14;
+const SIGNAL_ADMITTED: u8 = 15;
+const SIGNAL_EXCHANGE_KEYS: u8 = 16;
+const SIGNAL_PEER_KEY_COMMITMENT: u8 = 17;
+const SIGNAL_PEER_PUBLIC_KEY: u8 = 18;
+const SIGNAL_PEER_SENDER_KEY: u8 = 19;
+const SIGNAL_MODERATOR_KEY_SHARE: u8 = 20;
+
+/// Optional features a peer supports, exchanged during the hello.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+/// Someone in a call, as listed to its moderators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParticipantInfo {
+    pub session_id: SessionId,
+    pub moderator: bool,
+    pub muted: bool,
+}
+
+/// Reasons the server gives for refusing to go on with a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The client speaks a protocol version the server does not support.
+    UnsupportedVersion,
+    /// The client sent something that is not a valid message at this point.
+    MalformedMessage,
+    /// Something failed on the server's side, the client may try again later.
+    Internal,
+    /// The clients did not register their UDP addresses in time, most likely a firewall drops
+    /// the datagrams.
+    HandshakeTimeout,
+    /// The room already has a call going on.
+    RoomFull,
+    /// The client is not allowed in the room.
+    Unauthorized,
+    /// The server is serving as many clients as it can, the client may try again later.
+    ServerBusy,
+    /// A moderator removed the client from the call.
+    Kicked,
+    /// A moderator locked the room against new joins.
+    RoomLocked,
+    /// The server only takes connections switching to TLS, and the client did not offer to.
+    TlsRequired,
+    /// Sent by a newer server, this client does not know what it means.
+    Unknown(u8),
+}
+
+/// Messages sent by a client to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// Identifies the protocol spoken by the client. Always the first message of a connection.
+    Hello {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    /// Join the room identified by `room_hash`. Sent once the server answered the hello.
+    JoinRoom { room_hash: RoomHash, relay: bool },
+    /// Answer to [`ServerMessage::Ready`].
+    Ready,
+    /// The client ends the call. The server tells the partner and closes the connection.
+    Hangup,
+    /// Claims moderation of the call, starting an exchange proving the client holds the
+    /// moderator secret, see [`Pake::moderator`](crate::pake::Pake::moderator). The server
+    /// answers with [`ServerMessage::ModeratorKeyShare`], or [`ServerMessage::NotAllowed`] if
+    /// nobody may moderate.
+    Moderate(KeyShare),
+    /// Asks for a [`ServerMessage::Participants`]. This and the following are only for
+    /// moderators, others are answered [`ServerMessage::NotAllowed`].
+    ListParticipants,
+    /// Stops or resumes relaying the participant's media.
+    SetMuted { session_id: SessionId, muted: bool },
+    /// Removes the participant from the call.
+    Kick(SessionId),
+    /// Turns away anyone joining the room while locked.
+    SetLocked(bool),
+    /// The client's share of the exchanges proving it knows the room secret, sent right after
+    /// [`ServerMessage::PartnerFound`].
+    KeyShare(KeyShare),
+    /// Confirms the key of the exchange with `peer`, forwarded to it.
+    KeyConfirmation {
+        peer: SessionId,
+        confirmation: Confirmation,
+    },
+    /// Whether `peer`'s confirmation matched. A peer that does not know the room secret is
+    /// removed from the call, one that does may be sent media.
+    PeerVerified { peer: SessionId, verified: bool },
+    /// Commits to our public key for the exchange with `peer`, forwarded to it. The key
+    /// follows once `peer`'s commitment arrived.
+    KeyCommitment {
+        peer: SessionId,
+        commitment: Commitment,
+    },
+    /// Our public key for the exchange with `peer`, forwarded to it.
+    PublicKey {
+        peer: SessionId,
+        public_key: PublicKey,
+    },
+    /// The key we encrypt our audio with, sealed for `peer` and forwarded to it.
+    SenderKey { peer: SessionId, sealed: SealedKey },
+    /// Confirms the key of the exchange about the moderator secret. The server answers with
+    /// [`ServerMessage::Moderator`] or [`ServerMessage::NotAllowed`].
+    ModeratorConfirmation(Confirmation),
+}
+
+/// Messages sent by the server to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    /// Answer to a compatible [`ClientMessage::Hello`], with what the server supports.
+    Hello {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    /// The server refuses to go on, and will close the connection.
+    Error(ErrorCode),
+    /// The client is in the room, waiting for a partner.
+    WaitingInRoom,
+    /// A partner joined, or the client joined a call under way. The client must register its
+    /// UDP address by sending [`relay::registration`](crate::relay::registration) to
+    /// `udp_port` on the server, and prove it knows the room secret starting with
+    /// [`ClientMessage::KeyShare`]. When `relay` is set, the server decided to relay the call
+    /// and media goes to that same port, prefixed with `session_id`. Otherwise the partner's
+    /// address follows once both proved it.
+    PartnerFound {
+        udp_port: u16,
+        relay: bool,
+        session_id: SessionId,
+        token: Token,
+    },
+    /// Asks the client whether it is still there. It must answer with [`ClientMessage::Ready`].
+    Ready,
+    /// UDP address of the partner, sent when the call is not relayed.
+    PeerAddress(SocketAddr),
+    /// The call is over: the partner hung up or disconnected, or nothing went through the
+    /// relay for too long. The server closes the connection right after.
+    PartnerLeft,
+    /// Someone left a group call that goes on without them. Carries the session id their
+    /// relayed media was prefixed with.
+    ParticipantLeft(SessionId),
+    /// Everyone in the call, answering [`ClientMessage::ListParticipants`].
+    Participants(Vec<ParticipantInfo>),
+    /// The client moderates the call now.
+    Moderator,
+    /// A moderator muted the client, or unmuted it. The server drops its media while muted.
+    Muted(bool),
+    /// The client asked for something it may not do: only moderators moderate, and only
+    /// relayed calls can be muted.
+    NotAllowed,
+    /// The client is to prove it knows the room secret to `peer`, or check that `peer` does:
+    /// it answers with [`ClientMessage::KeyConfirmation`].
+    PeerKeyShare {
+        peer: SessionId,
+        key_share: KeyShare,
+    },
+    /// `peer`'s confirmation of the key of their exchange. The client answers with
+    /// [`ClientMessage::PeerVerified`].
+    PeerKeyConfirmation {
+        peer: SessionId,
+        confirmation: Confirmation,
+    },
+    /// The client proved it knows the room secret. Its media is relayed from now on, and a
+    /// direct call's partner address follows once both are admitted.
+    Admitted,
+    /// The client is to exchange keys with `peer`, who was admitted to the call or whom the
+    /// client was admitted alongside, starting with [`ClientMessage::KeyCommitment`]. Only
+    /// sent when the clients encrypt their audio.
+    ExchangeKeys(SessionId),
+    /// `peer`'s commitment to its public key for the exchange with the client.
+    PeerKeyCommitment {
+        peer: SessionId,
+        commitment: Commitment,
+    },
+    /// `peer`'s public key for the exchange with the client.
+    PeerPublicKey {
+        peer: SessionId,
+        public_key: PublicKey,
+    },
+    /// The key `peer` encrypts its audio with, sealed for the client.
+    PeerSenderKey { peer: SessionId, sealed: SealedKey },
+    /// The server's share of the exchange the client started with [`ClientMessage::Moderate`],
+    /// new for every claim. The client answers with [`ClientMessage::ModeratorConfirmation`].
+    ModeratorKeyShare(KeyShare),
+}
+
+impl Message for ClientMessage {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            ClientMessage::Hello {
+                version,
+                capabilities,
+            } => {
+                buf.push(CLIENT_HELLO);
+                encode_hello(*version, *capabilities, buf);
+            }
+            ClientMessage::JoinRoom { room_hash, relay } => {
+                buf.push(CLIENT_JOIN_ROOM);
+                buf.extend_from_slice(room_hash);
+                buf.push(*relay as u8);
+            }
+            ClientMessage::Ready => buf.push(CLIENT_READY),
+            ClientMessage::Hangup => buf.push(CLIENT_HANGUP),
+            ClientMessage::Moderate(key_share) => {
+                buf.push(CLIENT_MODERATE);
+                buf.extend_from_slice(&key_share.0);
+            }
+            ClientMessage::ListParticipants => buf.push(CLIENT_LIST_PARTICIPANTS),
+            ClientMessage::SetMuted { session_id, muted } => {
+                buf.push(CLIENT_SET_MUTED);
+                buf.extend_from_slice(&session_id.to_bytes());
+                buf.push(*muted as u8);
+            }
+            ClientMessage::Kick(session_id) => {
+                buf.push(CLIENT_KICK);
+                buf.extend_from_slice(&session_id.to_bytes());
+            }
+            ClientMessage::SetLocked(locked) => {
+                buf.push(CLIENT_SET_LOCKED);
+                buf.push(*locked as u8);
+            }
+            ClientMessage::KeyShare(key_share) => {
+                buf.push(CLIENT_KEY_SHARE);
+                buf.extend_from_slice(&key_share.0);
+            }
+            ClientMessage::KeyConfirmation { peer, confirmation } => {
+                buf.push(CLIENT_KEY_CONFIRMATION);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&confirmation.0);
+            }
+            ClientMessage::PeerVerified { peer, verified } => {
+                buf.push(CLIENT_PEER_VERIFIED);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.push(*verified as u8);
+            }
+            ClientMessage::KeyCommitment { peer, commitment } => {
+                buf.push(CLIENT_KEY_COMMITMENT);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&commitment.0);
+            }
+            ClientMessage::PublicKey { peer, public_key } => {
+                buf.push(CLIENT_PUBLIC_KEY);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&public_key.0);
+            }
+            ClientMessage::SenderKey { peer, sealed } => {
+                buf.push(CLIENT_SENDER_KEY);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&sealed.0);
+            }
+            ClientMessage::ModeratorConfirmation(confirmation) => {
+                buf.push(CLIENT_MODERATOR_CONFIRMATION);
+                buf.extend_from_slice(&confirmation.0);
+            }
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        ensure_len(buf, 1)?;
+
+        match buf[0] {
+            CLIENT_HELLO => {
+                let (version, capabilities, len) = decode_hello(&buf[1..])?;
+                let hello = ClientMessage::Hello {
+                    version,
+                    capabilities,
+                };
+                Ok((hello, 1 + len))
+            }
+            CLIENT_JOIN_ROOM => {
+                let len = 1 + ROOM_HASH_LEN + 1;
+                ensure_len(buf, len)?;
+
+                let mut room_hash = [0; ROOM_HASH_LEN];
+                room_hash.copy_from_slice(&buf[1..1 + ROOM_HASH_LEN]);
+
+                let relay = decode_flag(buf[1 + ROOM_HASH_LEN])?;
+
+                Ok((ClientMessage::JoinRoom { room_hash, relay }, len))
+            }
+            CLIENT_READY => Ok((ClientMessage::Ready, 1)),
+            CLIENT_HANGUP => Ok((ClientMessage::Hangup, 1)),
+            CLIENT_MODERATE => {
+                let len = 1 + KEY_SHARE_LEN;
+                ensure_len(buf, len)?;
+                let key_share = KeyShare(buf[1..len].try_into().unwrap());
+                Ok((ClientMessage::Moderate(key_share), len))
+            }
+            CLIENT_LIST_PARTICIPANTS => Ok((ClientMessage::ListParticipants, 1)),
+            CLIENT_SET_MUTED => {
+                ensure_len(buf, 6)?;
+                let session_id = decode_session_id(&buf[1..5]);
+                let muted = decode_flag(buf[5])?;
+                Ok((ClientMessage::SetMuted { session_id, muted }, 6))
+            }
+            CLIENT_KICK => {
+                ensure_len(buf, 5)?;
+                Ok((ClientMessage::Kick(decode_session_id(&buf[1..5])), 5))
+            }
+            CLIENT_SET_LOCKED => {
+                ensure_len(buf, 2)?;
+                Ok((ClientMessage::SetLocked(decode_flag(buf[1])?), 2))
+            }
+            CLIENT_KEY_SHARE => {
+                let len = 1 + KEY_SHARE_LEN;
+                ensure_len(buf, len)?;
+                let key_share = KeyShare(buf[1..len].try_into().unwrap());
+                Ok((ClientMessage::KeyShare(key_share), len))
+            }
+            CLIENT_KEY_CONFIRMATION => {
+                let len = 5 + CONFIRMATION_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let confirmation = Confirmation(buf[5..len].try_into().unwrap());
+                Ok((ClientMessage::KeyConfirmation { peer, confirmation }, len))
+            }
+            CLIENT_PEER_VERIFIED => {
+                ensure_len(buf, 6)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let verified = decode_flag(buf[5])?;
+                Ok((ClientMessage::PeerVerified { peer, verified }, 6))
+            }
+            CLIENT_KEY_COMMITMENT => {
+                let len = 5 + COMMITMENT_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let commitment = Commitment(buf[5..len].try_into().unwrap());
+                Ok((ClientMessage::KeyCommitment { peer, commitment }, len))
+            }
+            CLIENT_PUBLIC_KEY => {
+                let len = 5 + PUBLIC_KEY_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let public_key = PublicKey(buf[5..len].try_into().unwrap());
+                Ok((ClientMessage::PublicKey { peer, public_key }, len))
+            }
+            CLIENT_SENDER_KEY => {
+                let len = 5 + SEALED_KEY_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let sealed = SealedKey(buf[5..len].try_into().unwrap());
+                Ok((ClientMessage::SenderKey { peer, sealed }, len))
+            }
+            CLIENT_MODERATOR_CONFIRMATION => {
+                let len = 1 + CONFIRMATION_LEN;
+                ensure_len(buf, len)?;
+                let confirmation = Confirmation(buf[1..len].try_into().unwrap());
+                Ok((ClientMessage::ModeratorConfirmation(confirmation), len))
+            }
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Message for ServerMessage {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            ServerMessage::Hello {
+                version,
+                capabilities,
+            } => {
+                buf.push(SIGNAL_HELLO);
+                encode_hello(*version, *capabilities, buf);
+            }
+            ServerMessage::Error(code) => {
+                buf.push(SIGNAL_ERROR);
+                buf.push(code.to_byte());
+            }
+            ServerMessage::WaitingInRoom => buf.push(SIGNAL_WAITING_IN_ROOM),
+            ServerMessage::PartnerFound {
+                udp_port,
+                relay,
+                session_id,
+                token,
+            } => {
+                buf.push(SIGNAL_PARTNER_FOUND);
+                buf.extend_from_slice(&udp_port.to_be_bytes());
+                buf.push(*relay as u8);
+                buf.extend_from_slice(&session_id.to_bytes());
+                buf.extend_from_slice(&token.0);
+            }
+            ServerMessage::Ready => buf.push(SIGNAL_READY),
+            ServerMessage::PeerAddress(peer) => {
+                buf.push(SIGNAL_PEER_ADDRESS);
+                addr::encode(peer, buf);
+            }
+            ServerMessage::PartnerLeft => buf.push(SIGNAL_PARTNER_LEFT),
+            ServerMessage::ParticipantLeft(session_id) => {
+                buf.push(SIGNAL_PARTICIPANT_LEFT);
+                buf.extend_from_slice(&session_id.to_bytes());
+            }
+            ServerMessage::Participants(participants) => {
+                buf.push(SIGNAL_PARTICIPANTS);
+                // Calls are far smaller than this
+                let count = u16::try_from(participants.len()).unwrap_or(u16::MAX);
+                buf.extend_from_slice(&count.to_be_bytes());
+                for participant in &participants[..count.into()] {
+                    buf.extend_from_slice(&participant.session_id.to_bytes());
+                    buf.push(participant.moderator as u8);
+                    buf.push(participant.muted as u8);
+                }
+            }
+            ServerMessage::Moderator => buf.push(SIGNAL_MODERATOR),
+            ServerMessage::Muted(muted) => {
+                buf.push(SIGNAL_MUTED);
+                buf.push(*muted as u8);
+            }
+            ServerMessage::NotAllowed => buf.push(SIGNAL_NOT_ALLOWED),
+            ServerMessage::PeerKeyShare { peer, key_share } => {
+                buf.push(SIGNAL_PEER_KEY_SHARE);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&key_share.0);
+            }
+            ServerMessage::PeerKeyConfirmation { peer, confirmation } => {
+                buf.push(SIGNAL_PEER_KEY_CONFIRMATION);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&confirmation.0);
+            }
+            ServerMessage::Admitted => buf.push(SIGNAL_ADMITTED),
+            ServerMessage::ExchangeKeys(peer) => {
+                buf.push(SIGNAL_EXCHANGE_KEYS);
+                buf.extend_from_slice(&peer.to_bytes());
+            }
+            ServerMessage::PeerKeyCommitment { peer, commitment } => {
+                buf.push(SIGNAL_PEER_KEY_COMMITMENT);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&commitment.0);
+            }
+            ServerMessage::PeerPublicKey { peer, public_key } => {
+                buf.push(SIGNAL_PEER_PUBLIC_KEY);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&public_key.0);
+            }
+            ServerMessage::PeerSenderKey { peer, sealed } => {
+                buf.push(SIGNAL_PEER_SENDER_KEY);
+                buf.extend_from_slice(&peer.to_bytes());
+                buf.extend_from_slice(&sealed.0);
+            }
+            ServerMessage::ModeratorKeyShare(key_share) => {
+                buf.push(SIGNAL_MODERATOR_KEY_SHARE);
+                buf.extend_from_slice(&key_share.0);
+            }
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        ensure_len(buf, 1)?;
+
+        match buf[0] {
+            SIGNAL_HELLO => {
+                let (version, capabilities, len) = decode_hello(&buf[1..])?;
+                let hello = ServerMessage::Hello {
+                    version,
+                    capabilities,
+                };
+                Ok((hello, 1 + len))
+            }
+            SIGNAL_ERROR => {
+                ensure_len(buf, 2)?;
+                Ok((ServerMessage::Error(ErrorCode::from_byte(buf[1])), 2))
+            }
+            SIGNAL_WAITING_IN_ROOM => Ok((ServerMessage::WaitingInRoom, 1)),
+            SIGNAL_PARTNER_FOUND => {
+                const LEN: usize = 8 + TOKEN_LEN;
+
+                ensure_len(buf, LEN)?;
+                let udp_port = u16::from_be_bytes([buf[1], buf[2]]);
+                let relay = decode_flag(buf[3])?;
+                let session_id = decode_session_id(&buf[4..8]);
+                let token = Token(buf[8..LEN].try_into().unwrap());
+
+                let partner_found = ServerMessage::PartnerFound {
+                    udp_port,
+                    relay,
+                    session_id,
+                    token,
+                };
+                Ok((partner_found, LEN))
+            }
+            SIGNAL_READY => Ok((ServerMessage::Ready, 1)),
+            SIGNAL_PEER_ADDRESS => {
+                let (peer, len) = addr::decode(&buf[1..])?;
+                Ok((ServerMessage::PeerAddress(peer), 1 + len))
+            }
+            SIGNAL_PARTNER_LEFT => Ok((ServerMessage::PartnerLeft, 1)),
+            SIGNAL_PARTICIPANT_LEFT => {
+                ensure_len(buf, 5)?;
+                let session_id = decode_session_id(&buf[1..5]);
+                Ok((ServerMessage::ParticipantLeft(session_id), 5))
+            }
+            SIGNAL_PARTICIPANTS => {
+                const ENTRY_LEN: usize = 6;
+
+                ensure_len(buf, 3)?;
+                let count = usize::from(u16::from_be_bytes([buf[1], buf[2]]));
+                let len = 3 + count * ENTRY_LEN;
+                ensure_len(buf, len)?;
+
+                let participants = buf[3..len]
+                    .chunks_exact(ENTRY_LEN)
+                    .map(|entry| {
+                        Ok(ParticipantInfo {
+                            session_id: decode_session_id(&entry[..4]),
+                            moderator: decode_flag(entry[4])?,
+                            muted: decode_flag(entry[5])?,
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok((ServerMessage::Participants(participants), len))
+            }
+            SIGNAL_MODERATOR => Ok((ServerMessage::Moderator, 1)),
+            SIGNAL_MUTED => {
+                ensure_len(buf, 2)?;
+                Ok((ServerMessage::Muted(decode_flag(buf[1])?), 2))
+            }
+            SIGNAL_NOT_ALLOWED => Ok((ServerMessage::NotAllowed, 1)),
+            SIGNAL_PEER_KEY_SHARE => {
+                let len = 5 + KEY_SHARE_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let key_share = KeyShare(buf[5..len].try_into().unwrap());
+                Ok((ServerMessage::PeerKeyShare { peer, key_share }, len))
+            }
+            SIGNAL_PEER_KEY_CONFIRMATION => {
+                let len = 5 + CONFIRMATION_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let confirmation = Confirmation(buf[5..len].try_into().unwrap());
+                Ok((
+                    ServerMessage::PeerKeyConfirmation { peer, confirmation },
+                    len,
+                ))
+            }
+            SIGNAL_ADMITTED => Ok((ServerMessage::Admitted, 1)),
+            SIGNAL_EXCHANGE_KEYS => {
+                ensure_len(buf, 5)?;
+                Ok((
+                    ServerMessage::ExchangeKeys(decode_session_id(&buf[1..5])),
+                    5,
+                ))
+            }
+            SIGNAL_PEER_KEY_COMMITMENT => {
+                let len = 5 + COMMITMENT_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let commitment = Commitment(buf[5..len].try_into().unwrap());
+                Ok((ServerMessage::PeerKeyCommitment { peer, commitment }, len))
+            }
+            SIGNAL_PEER_PUBLIC_KEY => {
+                let len = 5 + PUBLIC_KEY_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let public_key = PublicKey(buf[5..len].try_into().unwrap());
+                Ok((ServerMessage::PeerPublicKey { peer, public_key }, len))
+            }
+            SIGNAL_PEER_SENDER_KEY => {
+                let len = 5 + SEALED_KEY_LEN;
+                ensure_len(buf, len)?;
+                let peer = decode_session_id(&buf[1..5]);
+                let sealed = SealedKey(buf[5..len].try_into().unwrap());
+                Ok((ServerMessage::PeerSenderKey { peer, sealed }, len))
+            }
+            SIGNAL_MODERATOR_KEY_SHARE => {
+                let len = 1 + KEY_SHARE_LEN;
+                ensure_len(buf, len)?;
+                let key_share = KeyShare(buf[1..len].try_into().unwrap());
+                Ok((ServerMessage::ModeratorKeyShare(key_share), len))
+            }
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+fn decode_flag(byte: u8) -> Result<bool, DecodeError> {
+    match byte {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(DecodeError::Invalid("flag must be 0 or 1")),
+    }
+}
+
+/// Reads a session id from exactly four bytes.
+fn decode_session_id(bytes: &[u8]) -> SessionId {
+    SessionId(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Whether a peer speaking `version` can be talked to.
+pub fn is_supported_version(version: u16) -> bool {
+    (MIN_SUPPORTED_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+fn encode_hello(version: u16, capabilities: Capabilities, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&HELLO_MAGIC);
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf.extend_from_slice(&capabilities.0.to_be_bytes());
+}
+
+fn decode_hello(buf: &[u8]) -> Result<(u16, Capabilities, usize), DecodeError> {
+    ensure_len(buf, HELLO_MAGIC.len())?;
+    if buf[..HELLO_MAGIC.len()] != HELLO_MAGIC {
+        return Err(DecodeError::Invalid("hello magic does not match"));
+    }
+
+    let len = HELLO_MAGIC.len() + 2 + 4;
+    ensure_len(buf, len)?;
+
+    let version = u16::from_be_bytes([buf[2], buf[3]]);
+    let capabilities = Capabilities(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]));
+
+    Ok((version, capabilities, len))
+}
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// The clients encrypt their audio end to end, see [`encryption`](crate::encryption). A
+    /// server mixing calls must read it, so it leaves this out of its hello, and the clients
+    /// send their audio in the clear.
+    pub const ENCRYPTED_MEDIA: Capabilities = Capabilities(1);
+    /// Right after the hellos, both sides switch the connection to TLS, the client starting
+    /// the handshake. A server only taking TLS answers hellos without it with
+    /// [`ErrorCode::TlsRequired`].
+    pub const TLS: Capabilities = Capabilities(2);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Both sets of features.
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    /// These features, but those of `other`.
+    pub fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+impl ErrorCode {
+    fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::UnsupportedVersion => 1,
+            ErrorCode::MalformedMessage => 2,
+            ErrorCode::Internal => 3,
+            ErrorCode::HandshakeTimeout => 4,
+            ErrorCode::RoomFull => 5,
+            ErrorCode::Unauthorized => 6,
+            ErrorCode::ServerBusy => 7,
+            ErrorCode::Kicked => 8,
+            ErrorCode::RoomLocked => 9,
+            ErrorCode::TlsRequired => 10,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+
+    fn from_byte(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::UnsupportedVersion,
+            2 => ErrorCode::MalformedMessage,
+            3 => ErrorCode::Internal,
+            4 => ErrorCode::HandshakeTimeout,
+            5 => ErrorCode::RoomFull,
+            6 => ErrorCode::Unauthorized,
+            7 => ErrorCode::ServerBusy,
+            8 => ErrorCode::Kicked,
+            9 => ErrorCode::RoomLocked,
+            10 => ErrorCode::TlsRequired,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            ErrorCode::MalformedMessage => write!(f, "malformed message"),
+            ErrorCode::Internal => write!(f, "internal server error"),
+            ErrorCode::HandshakeTimeout => write!(f, "UDP handshake timed out"),
+            ErrorCode::RoomFull => write!(f, "room full"),
+            ErrorCode::Unauthorized => write!(f, "unauthorized"),
+            ErrorCode::ServerBusy => write!(f, "server busy"),
+            ErrorCode::Kicked => write!(f, "kicked by a moderator"),
+            ErrorCode::RoomLocked => write!(f, "room locked"),
+            ErrorCode::TlsRequired => write!(f, "TLS required"),
+            ErrorCode::Unknown(code) => write!(f, "unknown error code {code}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip<M: Message + PartialEq + std::fmt::Debug>(message: M) {
+        let mut bytes = message.to_bytes();
+        assert_eq!(M::decode(&bytes), Ok((message, bytes.len())));
+
+        // Every strict prefix must ask for more bytes instead of misparsing.
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                M::decode(&bytes[..len]),
+                Err(DecodeError::Incomplete(_))
+            ));
+        }
+
+        // Trailing bytes belong to the next message and must not be consumed.
+        let len = bytes.len();
+        bytes.push(0xff);
+        assert_eq!(M::decode(&bytes).map(|(_, used)| used), Ok(len));
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        let mut room_hash = [0; ROOM_HASH_LEN];
+        room_hash
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8);
+
+        round_trip(ClientMessage::JoinRoom {
+            room_hash,
+            relay: true,
+        });
+        round_trip(ClientMessage::JoinRoom {
+            room_hash,
+            relay: false,
+        });
+        round_trip(ClientMessage::Ready);
+        round_trip(ClientMessage::Hangup);
+        round_trip(ClientMessage::Moderate(KeyShare(core::array::from_fn(
+            |i| i as u8,
+        ))));
+        round_trip(ClientMessage::ListParticipants);
+        round_trip(ClientMessage::SetMuted {
+            session_id: SessionId(3),
+            muted: true,
+        });
+        round_trip(ClientMessage::SetMuted {
+            session_id: SessionId(u32::MAX),
+            muted: false,
+        });
+        round_trip(ClientMessage::Kick(SessionId(0x0a0b_0c0d)));
+        round_trip(ClientMessage::SetLocked(true));
+        round_trip(ClientMessage::SetLocked(false));
+        round_trip(ClientMessage::KeyShare(KeyShare([9; KEY_SHARE_LEN])));
+        round_trip(ClientMessage::KeyConfirmation {
+            peer: SessionId(5),
+            confirmation: Confirmation(core::array::from_fn(|i| i as u8)),
+        });
+        round_trip(ClientMessage::PeerVerified {
+            peer: SessionId(5),
+            verified: true,
+        });
+        round_trip(ClientMessage::PeerVerified {
+            peer: SessionId(6),
+            verified: false,
+        });
+        round_trip(ClientMessage::KeyCommitment {
+            peer: SessionId(7),
+            commitment: Commitment([4; COMMITMENT_LEN]),
+        });
+        round_trip(ClientMessage::PublicKey {
+            peer: SessionId(7),
+            public_key: PublicKey(core::array::from_fn(|i| i as u8)),
+        });
+        round_trip(ClientMessage::SenderKey {
+            peer: SessionId(u32::MAX),
+            sealed: SealedKey(core::array::from_fn(|i| i as u8)),
+        });
+        round_trip(ClientMessage::ModeratorConfirmation(Confirmation(
+            [3; CONFIRMATION_LEN],
+        )));
+        round_trip(ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities(0xdead_beef),
+        });
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        round_trip(ServerMessage::Hello {
+            version: 7,
+            capabilities: Capabilities::NONE,
+        });
+        round_trip(ServerMessage::Error(ErrorCode::UnsupportedVersion));
+        round_trip(ServerMessage::Error(ErrorCode::MalformedMessage));
+        round_trip(ServerMessage::Error(ErrorCode::Internal));
+        round_trip(ServerMessage::Error(ErrorCode::HandshakeTimeout));
+        round_trip(ServerMessage::Error(ErrorCode::RoomFull));
+        round_trip(ServerMessage::Error(ErrorCode::Unauthorized));
+        round_trip(ServerMessage::Error(ErrorCode::ServerBusy));
+        round_trip(ServerMessage::Error(ErrorCode::Kicked));
+        round_trip(ServerMessage::Error(ErrorCode::RoomLocked));
+        round_trip(ServerMessage::Error(ErrorCode::TlsRequired));
+        round_trip(ServerMessage::Error(ErrorCode::Unknown(200)));
+        round_trip(ServerMessage::WaitingInRoom);
+        round_trip(ServerMessage::PartnerFound {
+            udp_port: 50_000,
+            relay: false,
+            session_id: SessionId(1),
+            token: Token([0; TOKEN_LEN]),
+        });
+        round_trip(ServerMessage::PartnerFound {
+            udp_port: 50_001,
+            relay: true,
+            session_id: SessionId(u32::MAX),
+            token: Token(core::array::from_fn(|i| i as u8)),
+        });
+        round_trip(ServerMessage::Ready);
+        round_trip(ServerMessage::PartnerLeft);
+        round_trip(ServerMessage::ParticipantLeft(SessionId(0x0a0b_0c0d)));
+        round_trip(ServerMessage::Participants(Vec::new()));
+        round_trip(ServerMessage::Participants(vec![
+            ParticipantInfo {
+                session_id: SessionId(1),
+                moderator: true,
+                muted: false,
+            },
+            ParticipantInfo {
+                session_id: SessionId(2),
+                moderator: false,
+                muted: true,
+            },
+        ]));
+        round_trip(ServerMessage::Moderator);
+        round_trip(ServerMessage::Muted(true));
+        round_trip(ServerMessage::Muted(false));
+        round_trip(ServerMessage::NotAllowed);
+        round_trip(ServerMessage::PeerKeyShare {
+            peer: SessionId(5),
+            key_share: KeyShare(core::array::from_fn(|i| i as u8)),
+        });
+        round_trip(ServerMessage::PeerKeyConfirmation {
+            peer: SessionId(u32::MAX),
+            confirmation: Confirmation([1; CONFIRMATION_LEN]),
+        });
+        round_trip(ServerMessage::Admitted);
+        round_trip(ServerMessage::ExchangeKeys(SessionId(8)));
+        round_trip(ServerMessage::PeerKeyCommitment {
+            peer: SessionId(8),
+            commitment: Commitment(core::array::from_fn(|i| i as u8)),
+        });
+        round_trip(ServerMessage::PeerPublicKey {
+            peer: SessionId(8),
+            public_key: PublicKey([5; PUBLIC_KEY_LEN]),
+        });
+        round_trip(ServerMessage::PeerSenderKey {
+            peer: SessionId(9),
+            sealed: SealedKey([6; SEALED_KEY_LEN]),
+        });
+        round_trip(ServerMessage::ModeratorKeyShare(KeyShare(
+            [2; KEY_SHARE_LEN],
+        )));
+        round_trip(ServerMessage::PeerAddress("10.0.0.7:9000".parse().unwrap()));
+        round_trip(ServerMessage::PeerAddress(
+            "[fe80::1]:9000".parse().unwrap(),
+        ));
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        assert_eq!(ServerMessage::decode(&[0]), Err(DecodeError::UnknownTag(0)));
+        assert_eq!(
+            ClientMessage::decode(&[200]),
+            Err(DecodeError::UnknownTag(200))
+        );
+    }
+
+    #[test]
+    fn hello_without_magic_is_rejected() {
+        let mut bytes = ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+        }
+        .to_bytes();
+        bytes[1] = b'X';
+
+        assert!(matches!(
+            ClientMessage::decode(&bytes),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn supported_versions() {
+        assert!(is_supported_version(PROTOCOL_VERSION));
+        assert!(!is_supported_version(MIN_SUPPORTED_VERSION - 1));
+        assert!(!is_supported_version(PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn capabilities_combine() {
+        let both = Capabilities::ENCRYPTED_MEDIA.union(Capabilities::TLS);
+
+        assert!(both.contains(Capabilities::TLS));
+        assert!(!both.without(Capabilities::TLS).contains(Capabilities::TLS));
+        assert_eq!(
+            both & Capabilities::TLS.union(Capabilities(4)),
+            Capabilities::TLS
+        );
+    }
+
+    #[test]
+    fn invalid_relay_flag_is_rejected() {
+        let mut bytes = ClientMessage::JoinRoom {
+            room_hash: [0; ROOM_HASH_LEN],
+            relay: false,
+        }
+        .to_bytes();
+        *bytes.last_mut().unwrap() = 2;
+
+        assert!(matches!(
+            ClientMessage::decode(&bytes),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn read_from_stops_at_message_end() {
+        let mut bytes = ServerMessage::PartnerFound {
+            udp_port: 1234,
+            relay: true,
+            session_id: SessionId(7),
+            token: Token([3; TOKEN_LEN]),
+        }
+        .to_bytes();
+        ServerMessage::Ready.encode(&mut bytes);
+
+        let mut reader = &bytes[..];
+        assert_eq!(
+            ServerMessage::read_from(&mut reader).unwrap(),
+            ServerMessage::PartnerFound {
+                udp_port: 1234,
+                relay: true,
+                session_id: SessionId(7),
+                token: Token([3; TOKEN_LEN]),
+            }
+        );
+        assert_eq!(
+            ServerMessage::read_from(&mut reader).unwrap(),
+            ServerMessage::Ready
+        );
+        assert!(reader.is_empty());
+    }
+}
@@ -1,15 +1,34 @@
 use std::{
-    io::Write,
-    net::{SocketAddr, TcpStream, UdpSocket},
-    thread,
-    time::Duration,
+    future::{self, Future},
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+    task::Poll,
 };
 
-use crate::utils::new_udp_socket;
+use simple_call_protocol::{
+    ReadError,
+    pake::{Confirmation, Exchange, KeyShare, Pake},
+    relay::{self, SessionId, Token},
+    signal::{Capabilities, ClientMessage, ParticipantInfo, ServerMessage},
+};
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+use crate::{
+    config::Config,
+    error::ServerError,
+    mixer::Mixer,
+    signaling::Signaling,
+    stats::RELAY_STATS,
+    udp::{CallSocket, Datagram, UdpMux, new_session_id, new_token},
+};
 
 // Constants
 
-pub const SIGNAL_PARTNER_FOUND: u8 = 2;
+/// How many datagrams to keep for a relayed call while its handshake is not done.
+const MAX_EARLY_MEDIA: usize = 64;
 
 // Types
 
@@ -17,29 +36,98 @@ pub struct CallSettings {
     pub relay: bool,
 }
 
-pub struct Handshake {
-    tcp1: TcpStream,
-    tcp2: TcpStream,
-
-    udp1: UdpSocket,
-    udp2: UdpSocket,
-
-    client1_udp_addr: Option<SocketAddr>,
-    client2_udp_addr: Option<SocketAddr>,
-
-    retries: u8,
+/// A client joining a call under way, with the settings it asked for.
+pub type Joiner = (Signaling, CallSettings);
+
+/// Someone in the call.
+struct Participant {
+    tcp: Signaling,
+    session_id: SessionId,
+    token: Token,
+
+    /// The only address datagrams from this participant are accepted from, once it registered.
+    addr: Option<SocketAddr>,
+    /// When a datagram last came from `addr`.
+    last_seen: Instant,
+    /// When to give up on it registering its address and proving it knows the room secret.
+    deadline: Instant,
+
+    /// Its share of the exchanges proving it knows the room secret, once it sent it.
+    key_share: Option<KeyShare>,
+    /// Who it proves it knows the room secret to, while it does.
+    verifier: Option<SessionId>,
+    /// Set once its verifier found it knows the room secret.
+    vouched: bool,
+    /// Those it failed to prove anything to before either was known to know the room secret.
+    /// Nothing tells which of the two is wrong, so they are not paired again.
+    mismatched: Vec<SessionId>,
+    /// Set once it is known to know the room secret. Until then, it is sent no media and none
+    /// of its own is relayed.
+    verified: bool,
+
+    /// Whether it may list, mute and kick the others, and lock the room.
+    moderator: bool,
+    /// Our side of the exchange proving it holds the moderator secret, while it claims
+    /// moderation.
+    moderation: Option<Exchange>,
+    /// Set by a moderator, its media is dropped meanwhile.
+    muted: bool,
 }
 
-pub enum CallCoordinatorState {
-    HandshakeBegin(TcpStream, TcpStream),
-    Handshake(Handshake),
-    Relay(UdpSocket, UdpSocket, SocketAddr, SocketAddr),
-    Finished,
+/// Why a participant is no longer in the call.
+#[derive(Clone, Copy)]
+enum Departure {
+    /// It hung up or disconnected.
+    Left,
+    /// It did not register its address, or prove it knows the room secret, in time.
+    TimedOut,
+    /// It does not know the room secret.
+    Unauthorized,
+    /// A moderator removed it.
+    Kicked,
 }
 
 pub struct CallCoordinator {
     pub settings: CallSettings,
-    pub state: CallCoordinatorState,
+    /// The two clients the call starts with, the one that waited in the room first.
+    first: [Signaling; 2],
+    config: Arc<Config>,
+    mux: Option<UdpMux>,
+}
+
+/// A call under way.
+struct Call {
+    participants: Vec<Participant>,
+    /// Gone once a direct call started, the clients then talk without the server.
+    udp: Option<CallSocket>,
+    relay: bool,
+    /// Set if the server mixes the call, instead of relaying each participant to the others.
+    mixer: Option<Mixer>,
+
+    /// Set once two participants registered, so media can flow.
+    started: bool,
+    /// Media that arrived before the call started, forwarded once it does.
+    early_media: Vec<Datagram>,
+    /// Datagrams dropped for coming from somewhere else than their session's address.
+    spoofed: u64,
+    /// Set by a moderator, those joining the room are turned away meanwhile.
+    locked: bool,
+
+    config: Arc<Config>,
+}
+
+/// Something that happened to a call, see [`next_event`].
+enum Event {
+    /// The participant at this index sent a message, or its connection failed.
+    Signal(usize, Result<ClientMessage, ReadError>),
+    /// Someone joined the room.
+    Joined(Joiner),
+    /// A datagram reached the call's UDP socket, `None` once none can anymore.
+    Datagram(Option<Datagram>),
+    /// The mixer's next frame is due.
+    Mix,
+    /// The deadline passed.
+    Timeout,
 }
 
 // Functions
@@ -53,139 +141,879 @@ impl CallSettings {
 }
 
 impl CallCoordinator {
-    pub fn new(stream1: TcpStream, stream2: TcpStream, settings: CallSettings) -> Self {
-        let state = CallCoordinatorState::HandshakeBegin(stream1, stream2);
-        Self { settings, state }
+    pub fn new(
+        stream1: Signaling,
+        stream2: Signaling,
+        settings: CallSettings,
+        config: Arc<Config>,
+        mux: Option<UdpMux>,
+    ) -> Self {
+        Self {
+            settings,
+            first: [stream1, stream2],
+            config,
+            mux,
+        }
     }
 
-    pub fn coordinate(mut self) {
-        'outer: loop {
-            match self.state {
-                CallCoordinatorState::HandshakeBegin(mut stream1, mut stream2) => {
-                    let udp1 = new_udp_socket();
-                    let udp2 = new_udp_socket();
+    /// Runs the call until it is over, taking in those who join the room meanwhile. Every
+    /// participant was told why the call failed, if it did, and their connections are closing
+    /// when this returns: the room is free for the next call without waiting for them.
+    pub async fn coordinate(
+        self,
+        joiners: &mut mpsc::UnboundedReceiver<Joiner>,
+    ) -> Result<(), ServerError> {
+        let [stream1, stream2] = self.first;
+
+        let udp = match CallSocket::open(&self.config, self.mux.as_ref(), &[]) {
+            Ok(udp) => udp,
+            Err(e) => return Err(fail_all([stream1, stream2], ServerError::Udp(e)).await),
+        };
+
+        let mut call = Call {
+            participants: Vec::new(),
+            udp: Some(udp),
+            relay: self.settings.relay,
+            mixer: self
+                .config
+                .mix_audio
+                .then(|| Mixer::new(self.config.mix_bitrate)),
+            started: false,
+            early_media: Vec::new(),
+            spoofed: 0,
+            locked: false,
+            config: self.config,
+        };
+
+        for stream in [stream1, stream2] {
+            call.add(stream).await;
+        }
 
-                    let send_udp_addr = |udp: &UdpSocket, stream: &mut TcpStream| {
-                        let port = udp.local_addr().unwrap().port();
-                        let port = port.to_be_bytes();
+        let result = call.run(joiners).await;
 
-                        stream
-                            .write_all(&[SIGNAL_PARTNER_FOUND, port[0], port[1]])
-                            .expect("Failed to write to stream");
-                    };
+        if call.spoofed > 0 {
+            println!(
+                "Dropped {} spoofed datagrams during the call.",
+                call.spoofed
+            );
+        }
+        println!("Call coordination finished.");
+        result
+    }
+}
 
-                    send_udp_addr(&udp1, &mut stream1);
-                    send_udp_addr(&udp2, &mut stream2);
-
-                    let handshake = Handshake {
-                        tcp1: stream1,
-                        tcp2: stream2,
-                        udp1,
-                        udp2,
-                        client1_udp_addr: None,
-                        client2_udp_addr: None,
-                        retries: 10,
+impl Call {
+    async fn run(
+        &mut self,
+        joiners: &mut mpsc::UnboundedReceiver<Joiner>,
+    ) -> Result<(), ServerError> {
+        loop {
+            if self.participants.len() < 2 {
+                self.end().await;
+                return Ok(());
+            }
+
+            let deadline = self.deadline();
+            // Nobody is there to listen to the mix before the call started
+            let mixer = self.mixer.as_mut().filter(|_| self.started);
+            let event = next_event(
+                &mut self.participants,
+                self.udp.as_mut(),
+                mixer,
+                joiners,
+                deadline,
+            )
+            .await;
+
+            match event {
+                Event::Signal(participant, message) => self.signal(participant, message).await?,
+                Event::Joined((tcp, _)) => {
+                    let refusal = if self.locked {
+                        ServerError::RoomLocked
+                    } else if self.participants.len() >= self.config.max_participants {
+                        ServerError::RoomFull
+                    } else {
+                        println!("A participant joins the call.");
+                        self.add(tcp).await;
+                        continue;
                     };
-                    self.state = CallCoordinatorState::Handshake(handshake);
+                    // Not to hold up the call while it goes
+                    tokio::spawn(async move { tcp.close_with(&refusal).await });
                 }
-                CallCoordinatorState::Handshake(mut handshake) => {
-                    handshake.retries -= 1;
-                    if handshake.retries == 0 {
-                        eprintln!("Failed to receive UDP addresses from clients.");
-                        break 'outer;
+                Event::Datagram(Some(datagram)) => self.receive(datagram).await,
+                Event::Mix => self.send_mixes().await,
+                Event::Datagram(None) => {
+                    self.end().await;
+                    return Err(ServerError::UdpClosed);
+                }
+                Event::Timeout => {
+                    let now = Instant::now();
+
+                    if self.idle_deadline().is_some_and(|deadline| deadline <= now) {
+                        println!("Nothing went through the relay for too long, ending the call.");
+                        self.end().await;
+                        return Ok(());
                     }
 
-                    let get_udp_addr = |udp: &UdpSocket| {
-                        let mut buffer = [0; 0];
-                        udp.set_read_timeout(Some(Duration::from_millis(200)))
-                            .unwrap();
+                    while let Some(late) = self
+                        .participants
+                        .iter()
+                        .position(|p| !p.ready() && p.deadline <= now)
+                    {
+                        self.remove(late, Departure::TimedOut).await?;
+                    }
+                }
+            }
+        }
+    }
 
-                        if let Ok((_, addr)) = udp.recv_from(&mut buffer) {
-                            Some(addr)
-                        } else {
-                            None
-                        }
-                    };
+    /// Brings `tcp` into the call, telling it how to register its address.
+    async fn add(&mut self, mut tcp: Signaling) {
+        let session_id = new_session_id();
+        let token = new_token();
+        let Some(udp) = &mut self.udp else {
+            return;
+        };
+
+        let partner_found = ServerMessage::PartnerFound {
+            udp_port: udp.port(),
+            relay: self.relay,
+            session_id,
+            token,
+        };
+        // A client gone before the call started is as good as never there
+        if let Err(e) = tcp.send(&partner_found).await {
+            println!("A client left before joining the call: {}", e);
+            return;
+        }
 
-                    if handshake.client1_udp_addr.is_none() {
-                        handshake.client1_udp_addr = get_udp_addr(&handshake.udp1);
-                    }
+        udp.add_session(session_id);
+        if let Some(mixer) = &mut self.mixer {
+            mixer.add(session_id);
+        }
+
+        let now = Instant::now();
+        self.participants.push(Participant {
+            tcp,
+            session_id,
+            token,
+            addr: None,
+            last_seen: now,
+            deadline: now + self.config.handshake_interval() * self.config.handshake_retries.into(),
+            key_share: None,
+            verifier: None,
+            vouched: false,
+            mismatched: Vec::new(),
+            verified: false,
+            moderator: false,
+            moderation: None,
+            muted: false,
+        });
+    }
+
+    /// Handles a message from `participant`, or its connection failing.
+    async fn signal(
+        &mut self,
+        participant: usize,
+        message: Result<ClientMessage, ReadError>,
+    ) -> Result<(), ServerError> {
+        match message {
+            Ok(ClientMessage::Hangup) => {
+                println!("Participant {} hung up.", participant + 1);
+                self.remove(participant, Departure::Left).await
+            }
+            // Answer to a ping sent while it was waiting for us
+            Ok(ClientMessage::Ready) => Ok(()),
+            Ok(ClientMessage::KeyShare(key_share)) => {
+                let sender = &mut self.participants[participant];
+                // Others may be using it already
+                if sender.key_share.is_none() {
+                    sender.key_share = Some(key_share);
+                    self.pair().await;
+                }
+                Ok(())
+            }
+            Ok(ClientMessage::KeyConfirmation { peer, confirmation }) => {
+                self.confirm(participant, peer, confirmation).await;
+                Ok(())
+            }
+            Ok(ClientMessage::PeerVerified { peer, verified }) => {
+                self.peer_verified(participant, peer, verified).await
+            }
+            Ok(
+                key_exchange @ (ClientMessage::KeyCommitment { .. }
+                | ClientMessage::PublicKey { .. }
+                | ClientMessage::SenderKey { .. }),
+            ) => {
+                self.forward_key_exchange(participant, key_exchange).await;
+                Ok(())
+            }
+            Ok(
+                command @ (ClientMessage::Moderate(_)
+                | ClientMessage::ModeratorConfirmation(_)
+                | ClientMessage::ListParticipants
+                | ClientMessage::SetMuted { .. }
+                | ClientMessage::Kick(_)
+                | ClientMessage::SetLocked(_)),
+            ) => self.moderate(participant, command).await,
+            Ok(other) => {
+                eprintln!("Ignoring unexpected message during a call: {:?}", other);
+                Ok(())
+            }
+            Err(e) => {
+                println!("Participant {} disconnected: {}", participant + 1, e);
+                self.remove(participant, Departure::Left).await
+            }
+        }
+    }
+
+    /// Gives every participant waiting to prove it knows the room secret someone to prove it
+    /// to: anyone already known to know it, or, before anyone is, another one waiting. Both are
+    /// sent each other's share.
+    async fn pair(&mut self) {
+        let waiting =
+            |p: &Participant| p.key_share.is_some() && !p.verified && p.verifier.is_none();
+
+        loop {
+            let Some(prover) = self.participants.iter().position(waiting) else {
+                return;
+            };
+            let verifier = self
+                .participants
+                .iter()
+                .position(|p| p.verified)
+                .or_else(|| {
+                    let prover_id = self.participants[prover].session_id;
+                    (0..self.participants.len()).find(|&other| {
+                        let other = &self.participants[other];
+                        other.session_id != prover_id
+                            && waiting(other)
+                            && !other.mismatched.contains(&prover_id)
+                    })
+                });
+            let Some(verifier) = verifier else {
+                return;
+            };
+
+            let verifier_id = self.participants[verifier].session_id;
+            let prover_id = self.participants[prover].session_id;
+            self.participants[prover].verifier = Some(verifier_id);
+            // Those starting the call prove it to each other
+            if !self.participants[verifier].verified {
+                self.participants[verifier].verifier = Some(prover_id);
+            }
+
+            for (to, from) in [(prover, verifier), (verifier, prover)] {
+                let from = &self.participants[from];
+                let key_share = ServerMessage::PeerKeyShare {
+                    peer: from.session_id,
+                    key_share: from
+                        .key_share
+                        .expect("Only those who sent theirs are paired"),
+                };
+                // A connection failing shows when reading from it next, and is handled then
+                let _ = self.participants[to].tcp.send(&key_share).await;
+            }
+        }
+    }
+
+    /// Forwards `participant`'s confirmation of the key it derived with `peer`, if they are
+    /// proving anything to each other.
+    async fn confirm(&mut self, participant: usize, peer: SessionId, confirmation: Confirmation) {
+        let sender_id = self.participants[participant].session_id;
+        let Some(receiver) = self.participants.iter().position(|p| {
+            p.session_id == peer
+                && (p.verifier == Some(sender_id)
+                    || self.participants[participant].verifier == Some(peer))
+        }) else {
+            return;
+        };
+
+        let confirmation = ServerMessage::PeerKeyConfirmation {
+            peer: sender_id,
+            confirmation,
+        };
+        let _ = self.participants[receiver].tcp.send(&confirmation).await;
+    }
+
+    /// Takes in whether `peer` proved to `participant` that it knows the room secret. Only the
+    /// one it proves it to has a say, and those starting the call must vouch for each other.
+    ///
+    /// Only someone known to know the secret may turn a peer away. Between two that are not
+    /// yet, either could be lying, so a failure calls the pairing off and each waits for
+    /// someone else until its deadline.
+    async fn peer_verified(
+        &mut self,
+        participant: usize,
+        peer: SessionId,
+        verified: bool,
+    ) -> Result<(), ServerError> {
+        let verifier_id = self.participants[participant].session_id;
+        let Some(prover) = self
+            .participants
+            .iter()
+            .position(|p| p.session_id == peer && p.verifier == Some(verifier_id))
+        else {
+            return Ok(());
+        };
+
+        if !verified && self.participants[participant].verified {
+            println!("Session {} does not know the room secret.", peer);
+            return self.remove(prover, Departure::Unauthorized).await;
+        }
+        if !verified {
+            println!(
+                "Sessions {} and {} do not share the room secret.",
+                verifier_id, peer
+            );
+            for (one, other) in [(prover, verifier_id), (participant, peer)] {
+                let one = &mut self.participants[one];
+                one.verifier = None;
+                one.vouched = false;
+                one.mismatched.push(other);
+            }
+            self.pair().await;
+            return Ok(());
+        }
+
+        self.participants[prover].vouched = true;
+        let verifier = &self.participants[participant];
+        if !(verifier.verified || verifier.vouched && verifier.verifier == Some(peer)) {
+            return Ok(());
+        }
+
+        // The first one admitted moderates the call, the one of the two that was in the room
+        // first
+        if !self.participants.iter().any(|p| p.verified) {
+            self.participants[prover.min(participant)].moderator = true;
+        }
+
+        let mut newly_admitted = Vec::new();
+        for admitted in [prover, participant] {
+            let participant = &mut self.participants[admitted];
+            if participant.verified {
+                continue;
+            }
+            participant.verified = true;
+            participant.verifier = None;
+            let _ = participant.tcp.send(&ServerMessage::Admitted).await;
+            newly_admitted.push(admitted);
+        }
+
+        self.exchange_keys(&newly_admitted).await;
+
+        // Those waiting may prove it to them now
+        self.pair().await;
+        self.try_start().await;
+        Ok(())
+    }
+
+    /// Has those just admitted exchange keys with everyone else admitted, and with each other.
+    /// Only clients encrypting their audio have keys to exchange, which servers mixing calls
+    /// do not let them.
+    async fn exchange_keys(&mut self, admitted: &[usize]) {
+        let encrypts = |p: &Participant| p.tcp.capabilities.contains(Capabilities::ENCRYPTED_MEDIA);
+
+        for (i, &newcomer) in admitted.iter().enumerate() {
+            if !encrypts(&self.participants[newcomer]) {
+                continue;
+            }
+            for other in 0..self.participants.len() {
+                // Pairs of newcomers are only introduced once
+                if other == newcomer
+                    || !self.participants[other].verified
+                    || !encrypts(&self.participants[other])
+                    || admitted[..i].contains(&other)
+                {
+                    continue;
+                }
+
+                for (to, peer) in [(newcomer, other), (other, newcomer)] {
+                    let peer = self.participants[peer].session_id;
+                    let _ = self.participants[to]
+                        .tcp
+                        .send(&ServerMessage::ExchangeKeys(peer))
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Forwards a message of `participant`'s key exchange with the peer it names, if both are
+    /// admitted. The clients check what they receive, whatever the server does.
+    async fn forward_key_exchange(&mut self, participant: usize, message: ClientMessage) {
+        let sender = &self.participants[participant];
+        if !sender.verified {
+            return;
+        }
+        let from = sender.session_id;
+
+        let (peer, forwarded) = match message {
+            ClientMessage::KeyCommitment { peer, commitment } => (
+                peer,
+                ServerMessage::PeerKeyCommitment {
+                    peer: from,
+                    commitment,
+                },
+            ),
+            ClientMessage::PublicKey { peer, public_key } => (
+                peer,
+                ServerMessage::PeerPublicKey {
+                    peer: from,
+                    public_key,
+                },
+            ),
+            ClientMessage::SenderKey { peer, sealed } => {
+                (peer, ServerMessage::PeerSenderKey { peer: from, sealed })
+            }
+            other => unreachable!("Not part of a key exchange: {:?}", other),
+        };
+
+        if let Some(receiver) = self
+            .participants
+            .iter_mut()
+            .find(|p| p.session_id == peer && p.verified && p.session_id != from)
+        {
+            let _ = receiver.tcp.send(&forwarded).await;
+        }
+    }
 
-                    if handshake.client2_udp_addr.is_none() {
-                        handshake.client2_udp_addr = get_udp_addr(&handshake.udp2);
+    /// Carries out a moderation `command` from `participant`, or tells it it may not. Only
+    /// those admitted may moderate, or claim to.
+    ///
+    /// Only relayed calls can be muted, the server has no say over direct ones. Commands about
+    /// someone no longer in the call are ignored, they most likely just left.
+    async fn moderate(
+        &mut self,
+        participant: usize,
+        command: ClientMessage,
+    ) -> Result<(), ServerError> {
+        // A connection failing to send shows when reading from it next, and is handled then
+        let claimant = &mut self.participants[participant];
+        // Nothing in the call is for those who did not prove they know the room secret
+        if !claimant.verified {
+            let _ = claimant.tcp.send(&ServerMessage::NotAllowed).await;
+            return Ok(());
+        }
+        match command {
+            // A new exchange each time, so nothing seen on the way proves anything later
+            ClientMessage::Moderate(key_share) => {
+                let exchange = self.config.moderator_secret.as_ref().and_then(|secret| {
+                    let pake = Pake::moderator(secret.as_bytes());
+                    Some((pake.exchange(&key_share).ok()?, pake.share()))
+                });
+                let answer = match exchange {
+                    Some((exchange, share)) => {
+                        claimant.moderation = Some(exchange);
+                        ServerMessage::ModeratorKeyShare(share)
                     }
+                    None => ServerMessage::NotAllowed,
+                };
+                let _ = claimant.tcp.send(&answer).await;
+                return Ok(());
+            }
+            ClientMessage::ModeratorConfirmation(confirmation) => {
+                let holds_secret = claimant
+                    .moderation
+                    .take()
+                    .is_some_and(|exchange| exchange.finish(&confirmation).is_ok());
+                let answer = if holds_secret {
+                    println!("Session {} moderates the call.", claimant.session_id);
+                    claimant.moderator = true;
+                    ServerMessage::Moderator
+                } else {
+                    ServerMessage::NotAllowed
+                };
+                let _ = claimant.tcp.send(&answer).await;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let allowed = self.participants[participant].moderator
+            && (self.relay || !matches!(command, ClientMessage::SetMuted { .. }));
+        if !allowed {
+            let _ = self.participants[participant]
+                .tcp
+                .send(&ServerMessage::NotAllowed)
+                .await;
+            return Ok(());
+        }
+
+        match command {
+            ClientMessage::ListParticipants => {
+                let participants = self
+                    .participants
+                    .iter()
+                    .map(|p| ParticipantInfo {
+                        session_id: p.session_id,
+                        moderator: p.moderator,
+                        muted: p.muted,
+                    })
+                    .collect();
+                let _ = self.participants[participant]
+                    .tcp
+                    .send(&ServerMessage::Participants(participants))
+                    .await;
+            }
+            ClientMessage::SetMuted { session_id, muted } => {
+                if let Some(target) = self
+                    .participants
+                    .iter_mut()
+                    .find(|p| p.session_id == session_id)
+                {
+                    println!("Session {} muted: {}", session_id, muted);
+                    target.muted = muted;
+                    let _ = target.tcp.send(&ServerMessage::Muted(muted)).await;
+                }
+            }
+            ClientMessage::Kick(session_id) => {
+                if let Some(target) = self
+                    .participants
+                    .iter()
+                    .position(|p| p.session_id == session_id)
+                {
+                    println!("Session {} was kicked.", session_id);
+                    return self.remove(target, Departure::Kicked).await;
+                }
+            }
+            ClientMessage::SetLocked(locked) => {
+                println!("Room locked: {}", locked);
+                self.locked = locked;
+            }
+            _ => unreachable!("Not a moderation command: {:?}", command),
+        }
+        Ok(())
+    }
+
+    /// Takes a participant out of the call, telling the others.
+    ///
+    /// Those left alone in a call that never started were let down by the handshake as much as
+    /// the one that timed out, and are failed the same way.
+    async fn remove(
+        &mut self,
+        participant: usize,
+        departure: Departure,
+    ) -> Result<(), ServerError> {
+        let gone = self.participants.remove(participant);
+        let gone_id = gone.session_id;
+        if let Some(udp) = &mut self.udp {
+            udp.remove_session(gone_id);
+        }
+        if let Some(mixer) = &mut self.mixer {
+            mixer.remove(gone_id);
+        }
+
+        // Closing waits for the client to let go, the others are not held up meanwhile
+        tokio::spawn(async move {
+            match departure {
+                Departure::Left => gone.tcp.close().await,
+                Departure::TimedOut => gone.tcp.close_with(&ServerError::HandshakeTimeout).await,
+                Departure::Kicked => gone.tcp.close_with(&ServerError::Kicked).await,
+                Departure::Unauthorized => gone.tcp.close_with(&ServerError::Unauthorized).await,
+            }
+        });
 
-                    if let (Some(addr1), Some(addr2)) =
-                        (handshake.client1_udp_addr, handshake.client2_udp_addr)
+        // Those proving themselves to it must start over with someone else
+        for p in &mut self.participants {
+            if p.verifier == Some(gone_id) && !p.verified {
+                p.verifier = None;
+                p.vouched = false;
+            }
+        }
+
+        if self.participants.len() < 2 {
+            if matches!(departure, Departure::TimedOut) && !self.started {
+                let tcp = self.participants.drain(..).map(|p| p.tcp);
+                return Err(fail_all(tcp, ServerError::HandshakeTimeout).await);
+            }
+            return Ok(());
+        }
+
+        // Those still there may drop what they kept to play this participant
+        for p in &mut self.participants {
+            let _ = p.tcp.send(&ServerMessage::ParticipantLeft(gone_id)).await;
+        }
+        self.pair().await;
+        Ok(())
+    }
+
+    /// When to wake up for a participant that is not ready yet, or a relay gone quiet.
+    fn deadline(&self) -> Option<Instant> {
+        self.participants
+            .iter()
+            .filter(|p| !p.ready())
+            .map(|p| p.deadline)
+            .chain(self.idle_deadline())
+            .min()
+    }
+
+    /// Only relayed calls can tell whether media still flows.
+    fn idle_deadline(&self) -> Option<Instant> {
+        if !self.relay || !self.started {
+            return None;
+        }
+
+        self.participants
+            .iter()
+            .filter(|p| p.addr.is_some())
+            .map(|p| p.last_seen)
+            .max()
+            .map(|last_seen| last_seen + self.config.relay_idle_timeout())
+    }
+
+    /// Handles a datagram from one of the participants: a registration, or media to relay.
+    async fn receive(&mut self, datagram: Datagram) {
+        let Some(sender) = self
+            .participants
+            .iter()
+            .position(|p| p.session_id == datagram.session_id)
+        else {
+            RELAY_STATS.stray.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        let now = Instant::now();
+        let participant = &mut self.participants[sender];
+        // A participant registers its address with a datagram holding its session id and the
+        // token only it was told
+        let registration = participant.token.matches(&datagram.payload);
+
+        match participant.addr {
+            None if registration => {
+                participant.addr = Some(datagram.from);
+                participant.last_seen = now;
+                self.try_start().await;
+                return;
+            }
+            // Nothing is accepted from a participant before it registered
+            None => return,
+            Some(addr) if addr != datagram.from => {
+                match self.config.migration_after() {
+                    // The participant went quiet and registered again from elsewhere, most
+                    // likely it roamed
+                    Some(after)
+                        if registration && now.duration_since(participant.last_seen) >= after =>
                     {
-                        if self.settings.relay {
-                            self.state = CallCoordinatorState::Relay(
-                                handshake.udp1,
-                                handshake.udp2,
-                                addr1,
-                                addr2,
+                        println!(
+                            "Session {} moved from {} to {}",
+                            participant.session_id, addr, datagram.from
+                        );
+                        participant.addr = Some(datagram.from);
+                    }
+                    _ => {
+                        if self.spoofed == 0 {
+                            eprintln!(
+                                "Dropping datagrams for session {} from {}, it registered {}",
+                                participant.session_id, datagram.from, addr
                             );
-                        } else {
-                            println!("UDP addresses received.");
-                            println!("Client 1 UDP address: {}", addr1);
-                            println!("Client 2 UDP address: {}", addr2);
-
-                            let udp_addr_to_bytes = |addr: &SocketAddr| {
-                                match addr {
-                                    SocketAddr::V6(_) => panic!("IPv6 is not supported"),
-                                    SocketAddr::V4(addr) => {
-                                        // Convert the IP address to bytes
-                                        let ip = addr.ip().octets();
-                                        let port = addr.port().to_be_bytes();
-                                        [ip[0], ip[1], ip[2], ip[3], port[0], port[1]]
-                                    }
-                                }
-                            };
-
-                            let send_udp_addr = |tcp: &mut TcpStream, addr: &SocketAddr| {
-                                let bytes = udp_addr_to_bytes(addr);
-                                tcp.write_all(&bytes)
-                                    .expect("Failed to write UDP address to TCP stream");
-                            };
-
-                            // To each client, send the UDP address of their partner
-                            send_udp_addr(&mut handshake.tcp1, &addr2);
-                            send_udp_addr(&mut handshake.tcp2, &addr1);
-
-                            self.state = CallCoordinatorState::Finished;
                         }
-                    } else {
-                        self.state = CallCoordinatorState::Handshake(handshake);
+                        self.spoofed += 1;
+                        RELAY_STATS.spoofed.fetch_add(1, Ordering::Relaxed);
+                        return;
                     }
                 }
-                CallCoordinatorState::Relay(ref udp1, ref udp2, ref client1_addr, client2_addr) => {
-                    // In relay mode, we simply relay messages between the two UDP sockets
-
-                    udp1.set_nonblocking(true)
-                        .expect("Failed to set non-blocking mode for udp1");
-                    udp2.set_nonblocking(true)
-                        .expect("Failed to set non-blocking mode for udp2");
+            }
+            Some(_) => {}
+        }
+        participant.last_seen = now;
 
-                    let mut buffer = [0; 1024];
+        // Late or repeated registrations carry nothing to forward, direct calls nothing to
+        // relay, and muted participants or those not admitted yet nothing to be heard
+        if registration || !self.relay || participant.muted || !participant.verified {
+            return;
+        }
 
-                    if let Ok((size, _)) = udp1.recv_from(&mut buffer) {
-                        println!("Received message({} bytes) from address: {}", size, client1_addr);
-                        udp2.send_to(&buffer[..size], client2_addr)
-                            .expect("Failed to relay message from client 1 to client 2.");
-                    }
+        if let Some(mixer) = &mut self.mixer {
+            mixer.receive(datagram.session_id, &datagram.payload);
+        } else if self.started {
+            self.forward(sender, &datagram.payload).await;
+        } else if self.early_media.len() < MAX_EARLY_MEDIA {
+            // Relayed clients start sending as soon as they registered
+            self.early_media.push(datagram);
+        }
+    }
 
-                    if let Ok((size, _)) = udp2.recv_from(&mut buffer) {
-                        println!("Received message({} bytes) from address: {}", size, client2_addr);
-                        udp1.send_to(&buffer[..size], client1_addr)
-                            .expect("Failed to relay message from client 2 to client 1.");
-                    }
-                }
-                CallCoordinatorState::Finished => {
-                    println!("Call coordination finished.");
-                    break 'outer;
+    /// Starts the call once two participants are ready.
+    async fn try_start(&mut self) {
+        let addrs: Vec<_> = self
+            .participants
+            .iter()
+            .filter(|p| p.ready())
+            .filter_map(|p| p.addr)
+            .collect();
+        if self.started || addrs.len() < 2 {
+            return;
+        }
+        self.started = true;
+
+        if self.relay {
+            for datagram in std::mem::take(&mut self.early_media) {
+                if let Some(sender) = self
+                    .participants
+                    .iter()
+                    .position(|p| p.session_id == datagram.session_id)
+                {
+                    self.forward(sender, &datagram.payload).await;
                 }
             }
-            thread::sleep(Duration::from_millis(20));
+            return;
+        }
+
+        println!("UDP addresses received.");
+        println!("Client 1 UDP address: {}", addrs[0]);
+        println!("Client 2 UDP address: {}", addrs[1]);
+
+        // To each client, send the UDP address of their partner. Direct calls have exactly two
+        // participants, and need nothing more from the server's socket.
+        self.udp = None;
+        for (participant, peer) in [(0, addrs[1]), (1, addrs[0])] {
+            let _ = self.participants[participant]
+                .tcp
+                .send(&ServerMessage::PeerAddress(peer))
+                .await;
+        }
+    }
+
+    /// Relays a datagram from one participant to every other registered one, prefixed with
+    /// the sender's session id. Takes `&mut self` as a mixer's Opus state cannot be shared
+    /// between threads, so neither can a `&Call` held across an await.
+    async fn forward(&mut self, sender: usize, payload: &[u8]) {
+        let Some(udp) = &self.udp else {
+            return;
+        };
+        let from = &self.participants[sender];
+        let datagram = relay::forwarded(from.session_id, payload);
+
+        for (receiver, participant) in self.participants.iter().enumerate() {
+            let Some(addr) = participant
+                .addr
+                .filter(|_| receiver != sender && participant.verified)
+            else {
+                continue;
+            };
+
+            // Like a lost datagram, one failing to go out is for the clients to cope with
+            if let Err(e) = udp.send_to(&datagram, addr).await {
+                eprintln!("Failed to relay a datagram to {}: {}", addr, e);
+                continue;
+            }
+            RELAY_STATS.forwarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Sends every registered participant the next frame of its mix, from
+    /// [`SessionId::MIXER`].
+    async fn send_mixes(&mut self) {
+        let (Some(udp), Some(mixer)) = (&self.udp, &mut self.mixer) else {
+            return;
+        };
+
+        for (session_id, packet) in mixer.mix() {
+            let Some(addr) = self
+                .participants
+                .iter()
+                .find(|p| p.session_id == session_id && p.verified)
+                .and_then(|p| p.addr)
+            else {
+                continue;
+            };
+
+            let datagram = relay::forwarded(SessionId::MIXER, &packet);
+            // Like a lost datagram, one failing to go out is for the clients to cope with
+            if let Err(e) = udp.send_to(&datagram, addr).await {
+                eprintln!("Failed to send the mix to {}: {}", addr, e);
+                continue;
+            }
+            RELAY_STATS.mixed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Tells everyone still there that the call is over, and closes their connections.
+    async fn end(&mut self) {
+        for mut participant in self.participants.drain(..) {
+            // Those that are gone already cannot be told anything, ignore them failing
+            let _ = participant.tcp.send(&ServerMessage::PartnerLeft).await;
+            tokio::spawn(participant.tcp.close());
         }
     }
 }
+
+impl Participant {
+    /// Whether it registered its address and proved it knows the room secret, so it can take
+    /// part in the call.
+    fn ready(&self) -> bool {
+        self.addr.is_some() && self.verified
+    }
+}
+
+/// Waits for whichever comes first: a message from any participant, someone joining the room,
+/// a datagram on `udp`, the `mixer`'s next frame, or `deadline`. Without a socket, mixer or
+/// deadline, those never happen.
+async fn next_event(
+    participants: &mut [Participant],
+    udp: Option<&mut CallSocket>,
+    mixer: Option<&mut Mixer>,
+    joiners: &mut mpsc::UnboundedReceiver<Joiner>,
+    deadline: Option<Instant>,
+) -> Event {
+    let mut receiving: Vec<_> = participants
+        .iter_mut()
+        .map(|participant| Box::pin(participant.tcp.recv::<ClientMessage>()))
+        .collect();
+    let signal = future::poll_fn(|cx| {
+        for (participant, message) in receiving.iter_mut().enumerate() {
+            if let Poll::Ready(message) = message.as_mut().poll(cx) {
+                return Poll::Ready((participant, message));
+            }
+        }
+        Poll::Pending
+    });
+
+    let datagram = async {
+        match udp {
+            Some(udp) => udp.recv().await,
+            None => future::pending().await,
+        }
+    };
+    let mix = async {
+        match mixer {
+            Some(mixer) => mixer.tick().await,
+            None => future::pending().await,
+        }
+    };
+    let timeout = async {
+        match deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        (participant, message) = signal => Event::Signal(participant, message),
+        // The room keeps its end open for as long as the call runs
+        Some(joiner) = joiners.recv() => Event::Joined(joiner),
+        datagram = datagram => Event::Datagram(datagram),
+        () = mix => Event::Mix,
+        () = timeout => Event::Timeout,
+    }
+}
+
+/// Tells every client in `tcp` why the call failed, closes their connections, and hands
+/// `error` back without waiting for them to let go.
+async fn fail_all(tcp: impl IntoIterator<Item = Signaling>, error: ServerError) -> ServerError {
+    let mut tcp: Vec<_> = tcp.into_iter().collect();
+
+    if let Some(code) = error.code() {
+        for tcp in &mut tcp {
+            // The client may be gone already, closing is all that is left to do anyway
+            let _ = tcp.send(&ServerMessage::Error(code)).await;
+        }
+    }
+
+    for tcp in tcp {
+        tokio::spawn(tcp.close());
+    }
+
+    error
+}
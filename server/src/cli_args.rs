@@ -0,0 +1,269 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use clap::Parser;
+
+use crate::config::{Config, PortRange};
+
+/// Signaling and relay server for simple_call.
+///
+/// Settings given here override the ones in the config file.
+#[derive(Parser, Debug)]
+#[clap(version, about, long_about = None)]
+pub struct Args {
+    /// TOML file to read the settings from.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on. The default `::` listens on every IPv6 and IPv4 interface.
+    #[clap(long)]
+    pub bind_address: Option<IpAddr>,
+
+    /// TCP port for the signaling connections.
+    #[clap(long)]
+    pub tcp_port: Option<u16>,
+
+    /// Ports to take the UDP relay and handshake sockets from, like 40000-40100.
+    #[clap(long)]
+    pub udp_port_range: Option<PortRange>,
+
+    /// A single UDP port shared by every call, instead of one port per call.
+    #[clap(long)]
+    pub relay_port: Option<u16>,
+
+    /// How many times to wait for the clients' UDP addresses before giving up on a call.
+    #[clap(long)]
+    pub handshake_retries: Option<u8>,
+
+    /// How long each of those waits lasts, in milliseconds.
+    #[clap(long)]
+    pub handshake_interval_ms: Option<u64>,
+
+    /// Relay every call through the server, whatever the clients prefer.
+    #[clap(long)]
+    pub force_relay: bool,
+
+    /// Let a relayed client register a new address once its old one has been silent for
+    /// this many milliseconds.
+    #[clap(long)]
+    pub migration_after_ms: Option<u64>,
+
+    /// End a relayed call when nothing went through it for this many milliseconds.
+    #[clap(long)]
+    pub relay_idle_timeout_ms: Option<u64>,
+
+    /// How often to check that a client waiting for a partner is still there, in milliseconds.
+    #[clap(long)]
+    pub waiting_ping_interval_ms: Option<u64>,
+
+    /// Most signaling connections served at once, clients past it are told the server is busy.
+    #[clap(long)]
+    pub max_clients: Option<usize>,
+
+    /// Most people in a call. Calls that may have more than two are always relayed.
+    #[clap(long)]
+    pub max_participants: Option<usize>,
+
+    /// Mix every call on the server, which relays them all, sending each participant a single
+    /// stream with everyone else's voices. Clients stop encrypting their audio end to end.
+    #[clap(long)]
+    pub mix_audio: bool,
+
+    /// Bitrate of the mixed streams, in bits per second.
+    #[clap(long)]
+    pub mix_bitrate: Option<u32>,
+
+    /// Makes whoever proves they know it a moderator of their call, besides the first one to
+    /// join the room. Other users of the machine may see it here, prefer the config file.
+    #[clap(long)]
+    pub moderator_secret: Option<String>,
+
+    /// PEM file with the certificate chain shown to clients switching their signaling
+    /// connection to TLS.
+    #[clap(long)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate.
+    #[clap(long)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Turn away clients that do not switch to TLS, instead of serving them in the clear.
+    #[clap(long)]
+    pub tls_required: bool,
+}
+
+impl Args {
+    /// Builds the final configuration: the config file if any, then the arguments on top.
+    pub fn into_config(self) -> Result<Config, String> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(bind_address) = self.bind_address {
+            config.bind_address = bind_address;
+        }
+        if let Some(tcp_port) = self.tcp_port {
+            config.tcp_port = tcp_port;
+        }
+        if let Some(udp_port_range) = self.udp_port_range {
+            config.udp_port_range = Some(udp_port_range);
+        }
+        if let Some(relay_port) = self.relay_port {
+            config.relay_port = Some(relay_port);
+        }
+        if let Some(handshake_retries) = self.handshake_retries {
+            config.handshake_retries = handshake_retries;
+        }
+        if let Some(handshake_interval_ms) = self.handshake_interval_ms {
+            config.handshake_interval_ms = handshake_interval_ms;
+        }
+        config.force_relay |= self.force_relay;
+        if let Some(migration_after_ms) = self.migration_after_ms {
+            config.migration_after_ms = Some(migration_after_ms);
+        }
+        if let Some(relay_idle_timeout_ms) = self.relay_idle_timeout_ms {
+            config.relay_idle_timeout_ms = relay_idle_timeout_ms;
+        }
+        if let Some(waiting_ping_interval_ms) = self.waiting_ping_interval_ms {
+            config.waiting_ping_interval_ms = waiting_ping_interval_ms;
+        }
+        if let Some(max_clients) = self.max_clients {
+            config.max_clients = Some(max_clients);
+        }
+        if let Some(max_participants) = self.max_participants {
+            config.max_participants = max_participants;
+        }
+        config.mix_audio |= self.mix_audio;
+        if let Some(mix_bitrate) = self.mix_bitrate {
+            config.mix_bitrate = mix_bitrate;
+        }
+        if let Some(moderator_secret) = self.moderator_secret {
+            config.moderator_secret = Some(moderator_secret);
+        }
+        if let Some(tls_cert) = self.tls_cert {
+            config.tls_cert = Some(tls_cert);
+        }
+        if let Some(tls_key) = self.tls_key {
+            config.tls_key = Some(tls_key);
+        }
+        config.tls_required |= self.tls_required;
+
+        if config.handshake_retries == 0 {
+            return Err("handshake_retries must be at least 1".to_string());
+        }
+        if config.handshake_interval_ms == 0 {
+            return Err("handshake_interval_ms must be at least 1".to_string());
+        }
+        if config.relay_idle_timeout_ms == 0 {
+            return Err("relay_idle_timeout_ms must be at least 1".to_string());
+        }
+        if config.waiting_ping_interval_ms == 0 {
+            return Err("waiting_ping_interval_ms must be at least 1".to_string());
+        }
+        if config.max_clients == Some(0) {
+            return Err("max_clients must be at least 1".to_string());
+        }
+        if config.max_participants < 2 {
+            return Err("max_participants must be at least 2".to_string());
+        }
+        // What Opus supports
+        if !(6_000..=510_000).contains(&config.mix_bitrate) {
+            return Err("mix_bitrate must be between 6000 and 510000".to_string());
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("tls_cert and tls_key must be set together".to_string());
+        }
+        if config.tls_required && config.tls_cert.is_none() {
+            return Err("tls_required needs tls_cert and tls_key".to_string());
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        use clap::CommandFactory;
+
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn args_override_defaults() {
+        let config = Args::parse_from([
+            "server",
+            "--tcp-port",
+            "9000",
+            "--udp-port-range",
+            "40000-40010",
+            "--force-relay",
+        ])
+        .into_config()
+        .unwrap();
+
+        assert_eq!(config.tcp_port, 9000);
+        assert_eq!(
+            config.udp_port_range,
+            Some(PortRange {
+                start: 40000,
+                end: 40010
+            })
+        );
+        assert!(config.force_relay);
+        assert_eq!(
+            config.handshake_retries,
+            Config::default().handshake_retries
+        );
+    }
+
+    #[test]
+    fn config_file_format() {
+        let config: Config = toml::from_str(
+            r#"
+            bind_address = "0.0.0.0"
+            udp_port_range = "50000-50100"
+            handshake_retries = 3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind_address, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(config.udp_port_range.unwrap().to_string(), "50000-50100");
+        assert_eq!(config.handshake_retries, 3);
+        assert_eq!(config.tcp_port, Config::default().tcp_port);
+
+        assert!(toml::from_str::<Config>("udp_port_range = \"50100-50000\"").is_err());
+        assert!(toml::from_str::<Config>("unknown_setting = true").is_err());
+    }
+
+    #[test]
+    fn tls_needs_a_certificate_and_its_key() {
+        let config = Args::parse_from([
+            "server",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--tls-required",
+        ])
+        .into_config()
+        .unwrap();
+        assert_eq!(config.tls_cert, Some(PathBuf::from("cert.pem")));
+        assert!(config.tls_required);
+
+        assert!(
+            Args::parse_from(["server", "--tls-cert", "cert.pem"])
+                .into_config()
+                .is_err()
+        );
+        assert!(
+            Args::parse_from(["server", "--tls-required"])
+                .into_config()
+                .is_err()
+        );
+    }
+}
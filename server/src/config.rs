@@ -0,0 +1,159 @@
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+/// Server settings, read from the TOML config file and overridden by command line arguments.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen on. The unspecified IPv6 address listens on IPv4 as well.
+    pub bind_address: IpAddr,
+    /// TCP port for the signaling connections.
+    pub tcp_port: u16,
+    /// Ports the relay and handshake UDP sockets are taken from. Any free port if not set.
+    pub udp_port_range: Option<PortRange>,
+    /// A single UDP port shared by every call. Takes precedence over `udp_port_range`.
+    pub relay_port: Option<u16>,
+    /// How many times to wait for the clients' UDP addresses before giving up on a call.
+    pub handshake_retries: u8,
+    /// How long each of those waits lasts, in milliseconds.
+    pub handshake_interval_ms: u64,
+    /// Relay every call through the server, whatever the clients prefer.
+    pub force_relay: bool,
+    /// Let a relayed client register a new address once its registered one has been silent
+    /// for this long, in milliseconds. Datagrams from any other address are dropped if not set.
+    pub migration_after_ms: Option<u64>,
+    /// End a relayed call when nothing went through it for this long, in milliseconds.
+    pub relay_idle_timeout_ms: u64,
+    /// How often to check that a client waiting for a partner is still there, in milliseconds.
+    /// One that did not answer the previous check by the next one is dropped.
+    pub waiting_ping_interval_ms: u64,
+    /// Most signaling connections served at once. Clients past it are told the server is busy.
+    /// No limit if not set.
+    pub max_clients: Option<usize>,
+    /// Most people in a call. Calls that may have more than two are always relayed.
+    pub max_participants: usize,
+    /// Mix every call on the server, which relays them all: each participant gets a single
+    /// stream with everyone else's voices, instead of one per participant.
+    pub mix_audio: bool,
+    /// Bitrate of the mixed streams, in bits per second.
+    pub mix_bitrate: u32,
+    /// Makes whoever proves they know it a moderator of their call, besides the first one to
+    /// join the room. Only the first one moderates if not set.
+    pub moderator_secret: Option<String>,
+    /// PEM file with the certificate chain shown to clients switching their signaling
+    /// connection to TLS. Clients stay in the clear if not set.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// Turn away clients that do not switch to TLS, instead of serving them in the clear.
+    pub tls_required: bool,
+}
+
+/// An inclusive range of ports, written as `start-end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: Ipv6Addr::UNSPECIFIED.into(),
+            tcp_port: 8383,
+            udp_port_range: None,
+            relay_port: None,
+            handshake_retries: 10,
+            handshake_interval_ms: 200,
+            force_relay: false,
+            migration_after_ms: None,
+            relay_idle_timeout_ms: 60_000,
+            waiting_ping_interval_ms: 10_000,
+            max_clients: None,
+            max_participants: 2,
+            mix_audio: false,
+            mix_bitrate: 32_000,
+            moderator_secret: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_required: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn handshake_interval(&self) -> Duration {
+        Duration::from_millis(self.handshake_interval_ms)
+    }
+
+    pub fn relay_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.relay_idle_timeout_ms)
+    }
+
+    pub fn waiting_ping_interval(&self) -> Duration {
+        Duration::from_millis(self.waiting_ping_interval_ms)
+    }
+
+    pub fn migration_after(&self) -> Option<Duration> {
+        self.migration_after_ms.map(Duration::from_millis)
+    }
+}
+
+impl PortRange {
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected a port range like 40000-40100, got {:?}", s))?;
+
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("invalid port {:?}: {}", port, e))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+
+        if start == 0 || start > end {
+            return Err(format!("invalid port range {}-{}", start, end));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
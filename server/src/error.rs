@@ -0,0 +1,115 @@
+use std::{fmt, io};
+
+use simple_call_protocol::{
+    DecodeError, ReadError,
+    signal::{ClientMessage, ErrorCode},
+};
+
+/// Why the server gave up on a client or a call.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The client's connection failed or closed.
+    Connection(io::Error),
+    /// The client sent bytes that are not a message.
+    Malformed(DecodeError),
+    /// The client sent a message that makes no sense at this point.
+    UnexpectedMessage(ClientMessage),
+    /// The client speaks a protocol version we do not, or did not say which.
+    UnsupportedVersion,
+    /// The client does not switch to TLS, which we require.
+    TlsRequired,
+    /// A client waiting in a room stopped answering pings.
+    Unresponsive,
+    /// The client wants to join a room that already has a call going on.
+    RoomFull,
+    /// The server already serves `max_clients` connections.
+    ServerBusy,
+    /// A moderator removed the client from its call.
+    Kicked,
+    /// The client wants to join a room a moderator locked.
+    RoomLocked,
+    /// The client does not know the room secret.
+    Unauthorized,
+    /// The clients did not register their UDP addresses in time.
+    HandshakeTimeout,
+    /// The call's UDP socket could not be opened.
+    Udp(io::Error),
+    /// The call's UDP socket stopped delivering datagrams.
+    UdpClosed,
+}
+
+impl ServerError {
+    /// What to tell the client about it, if anything.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            // Nobody is listening anymore
+            ServerError::Connection(_) | ServerError::Unresponsive => None,
+            ServerError::Malformed(_) | ServerError::UnexpectedMessage(_) => {
+                Some(ErrorCode::MalformedMessage)
+            }
+            ServerError::UnsupportedVersion => Some(ErrorCode::UnsupportedVersion),
+            ServerError::TlsRequired => Some(ErrorCode::TlsRequired),
+            ServerError::RoomFull => Some(ErrorCode::RoomFull),
+            ServerError::ServerBusy => Some(ErrorCode::ServerBusy),
+            ServerError::Kicked => Some(ErrorCode::Kicked),
+            ServerError::RoomLocked => Some(ErrorCode::RoomLocked),
+            ServerError::Unauthorized => Some(ErrorCode::Unauthorized),
+            ServerError::HandshakeTimeout => Some(ErrorCode::HandshakeTimeout),
+            // Every port the call could use is taken
+            ServerError::Udp(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                Some(ErrorCode::ServerBusy)
+            }
+            ServerError::Udp(_) | ServerError::UdpClosed => Some(ErrorCode::Internal),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Connection(e) => write!(f, "connection failed: {e}"),
+            ServerError::Malformed(e) => write!(f, "{e}"),
+            ServerError::UnexpectedMessage(message) => {
+                write!(f, "unexpected message: {message:?}")
+            }
+            ServerError::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            ServerError::TlsRequired => write!(f, "does not switch to TLS"),
+            ServerError::Unresponsive => write!(f, "stopped answering pings"),
+            ServerError::RoomFull => write!(f, "the room already has a call going on"),
+            ServerError::ServerBusy => write!(f, "too many clients"),
+            ServerError::Kicked => write!(f, "kicked by a moderator"),
+            ServerError::RoomLocked => write!(f, "the room is locked"),
+            ServerError::Unauthorized => write!(f, "does not know the room secret"),
+            ServerError::HandshakeTimeout => {
+                write!(f, "UDP addresses were not received in time")
+            }
+            ServerError::Udp(e) => write!(f, "failed to open a UDP socket: {e}"),
+            ServerError::UdpClosed => write!(f, "the call's UDP socket closed"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Connection(e) | ServerError::Udp(e) => Some(e),
+            ServerError::Malformed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Connection(e)
+    }
+}
+
+impl From<ReadError> for ServerError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::Io(e) => ServerError::Connection(e),
+            ReadError::Decode(e) => ServerError::Malformed(e),
+        }
+    }
+}
@@ -1,29 +1,64 @@
 pub mod call_coordinator;
+pub mod cli_args;
+pub mod config;
+pub mod error;
+pub mod mixer;
 pub mod room_coordinator;
+pub mod signaling;
+pub mod stats;
+pub mod tls;
+pub mod udp;
 pub mod utils;
 
 #[cfg(test)]
 mod tests;
 
-use std::{net::TcpListener, thread};
+use std::{process, sync::Arc};
 
+use clap::Parser;
+use config::Config;
 use room_coordinator::RoomCoordinator;
+use udp::UdpMux;
+use utils::new_tcp_listener;
 
-fn main() {
-    let rooms: RoomCoordinator = RoomCoordinator::default();
+#[tokio::main]
+async fn main() {
+    let config = cli_args::Args::parse().into_config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
 
-    // TCP listener
-    let tcp_listener = TcpListener::bind("0.0.0.0:8383")
-        .expect("Failed to bind TCP listener. Most likely port 8383 is already in use.");
+    run(config).await;
+}
+
+pub async fn run(config: Config) {
+    let tcp_listener = new_tcp_listener(&config).unwrap_or_else(|e| {
+        panic!(
+            "Failed to bind TCP listener on port {}: {}. Most likely it is already in use.",
+            config.tcp_port, e
+        )
+    });
+    println!("Listening on {}", tcp_listener.local_addr().unwrap());
+
+    let mux = config.relay_port.map(|port| {
+        UdpMux::bind(&config, port)
+            .unwrap_or_else(|e| panic!("Failed to bind the shared relay port {}: {}", port, e))
+    });
+
+    tokio::spawn(stats::report_periodically());
+
+    let tls = tls::acceptor(&config).unwrap_or_else(|e| panic!("{}", e));
+
+    let rooms = RoomCoordinator::new(Arc::new(config), mux, tls);
 
-    for stream in tcp_listener.incoming() {
-        match stream {
-            Ok(stream) => {
+    loop {
+        match tcp_listener.accept().await {
+            Ok((stream, _)) => {
                 // Handle the incoming connection
                 println!("New connection established.");
                 let mut rooms_clone = rooms.clone();
-                thread::spawn(move || {
-                    rooms_clone.handle_incoming_conn(stream);
+                tokio::spawn(async move {
+                    rooms_clone.handle_incoming_conn(stream).await;
                 });
             }
             Err(e) => {
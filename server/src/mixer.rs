@@ -0,0 +1,216 @@
+use std::collections::{HashMap, VecDeque};
+
+use audiopus::{
+    Application, Bitrate, Channels, MutSignals, SampleRate,
+    coder::{Decoder, Encoder},
+    packet::Packet,
+};
+use simple_call_protocol::{
+    media::{self, Arrival, MediaHeader, MediaReceiver, MediaSender},
+    relay::SessionId,
+    report,
+};
+use tokio::time::{self, Duration, Interval, MissedTickBehavior};
+
+// Constants
+
+/// The clients' audio: 48 kHz mono Opus.
+const SAMPLE_RATE: SampleRate = SampleRate::Hz48000;
+const CHANNELS: Channels = Channels::Mono;
+
+/// Length of a mixed frame in samples, 60 ms like the frames clients send.
+const FRAME_SIZE: usize = 2880;
+const FRAME_DURATION: Duration = Duration::from_millis(60);
+
+/// How much of a participant's audio is kept before dropping the oldest, in frames, so a
+/// burst does not delay them for the rest of the call.
+const MAX_QUEUED_FRAMES: usize = 8;
+
+/// How many frames in a row a participant's lost packets are concealed for, before it is
+/// taken as silent.
+const MAX_CONCEALED_FRAMES: u32 = 3;
+
+/// Largest packet the encoder may produce, as recommended by Opus.
+const MAX_PACKET_SIZE: usize = 4000;
+
+// Types
+
+/// Mixes a call's audio on the server, sending each participant everyone else's voices as a
+/// single stream, at a fixed bitrate however many they are.
+pub struct Mixer {
+    voices: HashMap<SessionId, Voice>,
+    bitrate: Bitrate,
+    /// When the next frame is due.
+    frames: Interval,
+}
+
+/// A participant's audio, both ways.
+struct Voice {
+    /// Tells which of the participant's packets came late or twice.
+    received: MediaReceiver,
+    decoder: Decoder,
+    /// Decoded samples not mixed yet.
+    samples: VecDeque<f32>,
+    /// How many frames in a row were concealed since a packet last came.
+    concealed: u32,
+
+    /// Its own encoder, Opus keeps state from one frame to the next.
+    encoder: Encoder,
+    /// Numbers the mix sent to the participant.
+    sent: MediaSender,
+}
+
+// Functions
+
+impl Mixer {
+    /// A mixer encoding what it sends at `bitrate` bits per second.
+    pub fn new(bitrate: u32) -> Self {
+        let mut frames = time::interval(FRAME_DURATION);
+        // Late frames are as good as lost, better not to send a burst of them
+        frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        Self {
+            voices: HashMap::new(),
+            bitrate: Bitrate::BitsPerSecond(bitrate.try_into().unwrap_or(i32::MAX)),
+            frames,
+        }
+    }
+
+    pub fn add(&mut self, session_id: SessionId) {
+        let mut encoder = Encoder::new(SAMPLE_RATE, CHANNELS, Application::Voip)
+            .expect("The encoder settings are valid");
+        encoder
+            .set_bitrate(self.bitrate)
+            .expect("The bitrate was validated with the config");
+
+        self.voices.insert(
+            session_id,
+            Voice {
+                received: MediaReceiver::new(),
+                decoder: Decoder::new(SAMPLE_RATE, CHANNELS)
+                    .expect("The decoder settings are valid"),
+                samples: VecDeque::new(),
+                concealed: 0,
+                encoder,
+                sent: MediaSender::new(),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, session_id: SessionId) {
+        self.voices.remove(&session_id);
+    }
+
+    /// Takes in a packet `session_id` sent, media header first. Those that do not decode are
+    /// dropped, like lost ones, and so are those arriving after their turn. Comfort noise
+    /// means the participant went silent, there is nothing to conceal until they speak again.
+    /// Reports are dropped too, the mix does not adapt to them.
+    pub fn receive(&mut self, session_id: SessionId, packet: &[u8]) {
+        let Some(voice) = self.voices.get_mut(&session_id) else {
+            return;
+        };
+        if report::is_report(packet) {
+            return;
+        }
+        let Some((header, packet)) = MediaHeader::split(packet) else {
+            return;
+        };
+        match voice.received.receive(&header) {
+            Arrival::InOrder { .. } => {}
+            Arrival::Late | Arrival::Duplicate => return,
+        }
+        if header.payload_type == media::PAYLOAD_TYPE_COMFORT_NOISE {
+            voice.concealed = MAX_CONCEALED_FRAMES;
+            return;
+        }
+        let Ok(packet) = Packet::try_from(packet) else {
+            return;
+        };
+
+        let mut decoded = [0f32; FRAME_SIZE];
+        let output = MutSignals::try_from(&mut decoded[..]).expect("The buffer is not empty");
+        let Ok(size) = voice.decoder.decode_float(Some(packet), output, false) else {
+            return;
+        };
+
+        voice.samples.extend(&decoded[..size]);
+        voice.concealed = 0;
+
+        let excess = voice
+            .samples
+            .len()
+            .saturating_sub(MAX_QUEUED_FRAMES * FRAME_SIZE);
+        voice.samples.drain(..excess);
+    }
+
+    /// Waits until the next frame is due.
+    pub async fn tick(&mut self) {
+        self.frames.tick().await;
+    }
+
+    /// Mixes the next frame, returning the packet to send each participant: everyone's voice
+    /// but their own, after a media header of the mixer's.
+    pub fn mix(&mut self) -> Vec<(SessionId, Vec<u8>)> {
+        let frames: Vec<_> = self
+            .voices
+            .iter_mut()
+            .map(|(&session_id, voice)| (session_id, voice.next_frame()))
+            .collect();
+
+        let mut everyone = [0f32; FRAME_SIZE];
+        for (_, frame) in &frames {
+            for (mixed, sample) in everyone.iter_mut().zip(frame) {
+                *mixed += sample;
+            }
+        }
+
+        let mut packets = Vec::with_capacity(frames.len());
+        for (session_id, own) in frames {
+            let mut others = everyone;
+            for (mixed, sample) in others.iter_mut().zip(&own) {
+                *mixed = (*mixed - sample).clamp(-1.0, 1.0);
+            }
+
+            let voice = self.voices.get_mut(&session_id).expect("Mixed above");
+            let mut packet = vec![0; media::HEADER_LEN + MAX_PACKET_SIZE];
+            match voice
+                .encoder
+                .encode_float(&others, &mut packet[media::HEADER_LEN..])
+            {
+                Ok(size) => {
+                    let header = voice.sent.next(FRAME_SIZE as u32);
+                    packet[..media::HEADER_LEN].copy_from_slice(&header.to_bytes());
+                    packet.truncate(media::HEADER_LEN + size);
+                    packets.push((session_id, packet));
+                }
+                Err(e) => eprintln!("Failed to encode the mix for session {}: {}", session_id, e),
+            }
+        }
+
+        packets
+    }
+}
+
+impl Voice {
+    /// The participant's next frame of audio, concealed for a while if it did not arrive, and
+    /// silent after that.
+    fn next_frame(&mut self) -> [f32; FRAME_SIZE] {
+        let mut frame = [0f32; FRAME_SIZE];
+
+        if self.samples.len() < FRAME_SIZE && self.concealed < MAX_CONCEALED_FRAMES {
+            self.concealed += 1;
+            let output = MutSignals::try_from(&mut frame[..]).expect("The buffer is not empty");
+            if let Ok(size) = self.decoder.decode_float(None, output, false) {
+                self.samples.extend(&frame[..size]);
+            }
+            frame = [0f32; FRAME_SIZE];
+        }
+
+        let available = self.samples.len().min(FRAME_SIZE);
+        for (sample, queued) in frame.iter_mut().zip(self.samples.drain(..available)) {
+            *sample = queued;
+        }
+
+        frame
+    }
+}
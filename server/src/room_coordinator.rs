@@ -1,78 +1,391 @@
 use std::{
     collections::HashMap,
-    io::{Read, Write},
+    iter,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use simple_call_protocol::{
+    ReadError,
+    signal::{
+        Capabilities, ClientMessage, PROTOCOL_VERSION, RoomHash, ServerMessage,
+        is_supported_version,
+    },
+};
+use tokio::{
     net::TcpStream,
-    sync::{Arc, Mutex},
+    sync::{
+        Semaphore,
+        mpsc::{self, error::SendError},
+        oneshot,
+    },
+    time::{self, MissedTickBehavior},
 };
+use tokio_rustls::TlsAcceptor;
 
-use crate::call_coordinator::{CallCoordinator, CallSettings};
+use crate::{
+    call_coordinator::{CallCoordinator, CallSettings, Joiner},
+    config::Config,
+    error::ServerError,
+    signaling::Signaling,
+    udp::UdpMux,
+    utils::lock,
+};
 
-pub const SIGNAL_WAITING_IN_ROOM: u8 = 1;
+/// Optional protocol features this server implements.
+const SERVER_CAPABILITIES: Capabilities = Capabilities::ENCRYPTED_MEDIA.union(Capabilities::TLS);
 
-type RoomsMap = HashMap<[u8; 64], TcpStream>;
+/// How long a new connection has to introduce itself before it is dropped.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells apart successive clients waiting in the same room.
+static NEXT_WAITING_ID: AtomicU64 = AtomicU64::new(0);
+
+type RoomsMap = HashMap<RoomHash, Room>;
 type SharedRoomsMap = Arc<Mutex<RoomsMap>>;
 
-#[derive(Default, Clone)]
+enum Room {
+    /// One client is there, waiting for a partner.
+    Waiting(WaitingClient),
+    /// A call is going on, those joining the room are sent into it.
+    InCall(mpsc::UnboundedSender<Joiner>),
+}
+
+/// A client alone in its room. Its own task keeps its connection and checks it is still there,
+/// the partner joining the room is handed to that task.
+struct WaitingClient {
+    id: u64,
+    partner: oneshot::Sender<Handoff>,
+}
+
+/// What a client joining a waiting one hands it: itself, and the way into their call for those
+/// joining after them.
+struct Handoff {
+    partner: Joiner,
+    joiners: mpsc::UnboundedReceiver<Joiner>,
+}
+
+#[derive(Clone)]
 pub struct RoomCoordinator {
     rooms: SharedRoomsMap,
+    /// Connections that may still be served, if they are limited.
+    slots: Option<Arc<Semaphore>>,
+    config: Arc<Config>,
+    mux: Option<UdpMux>,
+    /// Switches connections to TLS, if we have a certificate.
+    tls: Option<TlsAcceptor>,
 }
 
 impl RoomCoordinator {
-    pub fn handle_incoming_conn(&mut self, mut stream: TcpStream) {
-        let room_hash = wait_for_room_hash(&mut stream);
+    pub fn new(config: Arc<Config>, mux: Option<UdpMux>, tls: Option<TlsAcceptor>) -> Self {
+        Self {
+            rooms: SharedRoomsMap::default(),
+            slots: config
+                .max_clients
+                .map(|max_clients| Arc::new(Semaphore::new(max_clients))),
+            config,
+            mux,
+            tls,
+        }
+    }
+
+    pub async fn handle_incoming_conn(&mut self, stream: TcpStream) {
+        let peer = stream.peer_addr();
+        let slot = self
+            .slots
+            .as_ref()
+            .map(|slots| slots.clone().try_acquire_owned());
+
+        let result = match slot.transpose() {
+            Ok(slot) => {
+                let mut stream = Signaling::new(stream, slot);
+                match self.introduce(&mut stream).await {
+                    Ok((room_hash, settings)) => {
+                        self.join_room(room_hash, (stream, settings)).await
+                    }
+                    Err(e) => {
+                        stream.close_with(&e).await;
+                        Err(e)
+                    }
+                }
+            }
+            Err(_) => {
+                let e = ServerError::ServerBusy;
+                Signaling::new(stream, None).close_with(&e).await;
+                Err(e)
+            }
+        };
+
+        if let Err(e) = result {
+            match peer {
+                Ok(peer) => eprintln!("Connection from {} failed: {}", peer, e),
+                Err(_) => eprintln!("Connection failed: {}", e),
+            }
+        }
+    }
+
+    /// What we offer clients in our hello.
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = SERVER_CAPABILITIES;
+        if self.config.mix_audio {
+            // Mixing calls means reading their audio
+            capabilities = capabilities.without(Capabilities::ENCRYPTED_MEDIA);
+        }
+        if self.tls.is_none() {
+            capabilities = capabilities.without(Capabilities::TLS);
+        }
+        capabilities
+    }
+
+    /// Greets the client, switching to TLS if we both can, and reads the room it wants to
+    /// join, telling it it waits there.
+    async fn introduce(
+        &self,
+        stream: &mut Signaling,
+    ) -> Result<(RoomHash, CallSettings), ServerError> {
+        wait_for_hello(stream, self.capabilities(), self.config.tls_required).await?;
+        if let Some(tls) = &self.tls
+            && stream.capabilities.contains(Capabilities::TLS)
+        {
+            stream.start_tls(tls).await?;
+        }
+        let joined = wait_for_join_room(stream).await?;
 
         // Always send the waiting signal, even if there is a partner.
-        stream
-            .write_all(&[SIGNAL_WAITING_IN_ROOM])
-            .expect("Failed to write to stream");
+        stream.send(&ServerMessage::WaitingInRoom).await?;
+        println!("Sent WaitingInRoom");
+
+        Ok(joined)
+    }
 
-        println!("Sent SIGNAL_WAITING_IN_ROOM");
+    /// Hands `client` to the one waiting in the room or to the call going on there, or waits
+    /// there for a partner.
+    async fn join_room(&self, room_hash: RoomHash, mut client: Joiner) -> Result<(), ServerError> {
+        let (id, partner) = {
+            let mut rooms = lock(&self.rooms);
 
-        let mut partner_stream = {
-            let mut rooms = self.rooms.lock().expect("Lock should not be poisoned");
-            let partner = rooms.remove(&room_hash);
+            loop {
+                match rooms.remove(&room_hash) {
+                    Some(Room::Waiting(waiting)) => {
+                        let (call, joiners) = mpsc::unbounded_channel();
+                        let handoff = Handoff {
+                            partner: client,
+                            joiners,
+                        };
 
-            match partner {
-                None => {
-                    println!("No partner found, waiting for one");
-                    rooms.insert(room_hash, stream);
-                    return;
+                        // Only fails if the waiting task is gone, then the room is free
+                        match waiting.partner.send(handoff) {
+                            Ok(()) => {
+                                rooms.insert(room_hash, Room::InCall(call));
+                                return Ok(());
+                            }
+                            Err(unsent) => client = unsent.partner,
+                        }
+                    }
+                    Some(Room::InCall(call)) => {
+                        // Only fails once the call is over, then the room is free
+                        match call.send(client) {
+                            Ok(()) => {
+                                rooms.insert(room_hash, Room::InCall(call));
+                                return Ok(());
+                            }
+                            Err(SendError(unsent)) => client = unsent,
+                        }
+                    }
+                    None => {
+                        println!("No partner found, waiting for one");
+                        let id = NEXT_WAITING_ID.fetch_add(1, Ordering::Relaxed);
+                        let (sender, partner) = oneshot::channel();
+                        rooms.insert(
+                            room_hash,
+                            Room::Waiting(WaitingClient {
+                                id,
+                                partner: sender,
+                            }),
+                        );
+                        break (id, partner);
+                    }
                 }
-                Some(partner_stream) => partner_stream,
             }
         };
 
-        let user1_settings = wait_for_preferred_settings(&mut stream);
-        let user2_settings = wait_for_preferred_settings(&mut partner_stream);
-
-        println!("Initiating call with partner");
-        CallCoordinator::new(stream, partner_stream, user1_settings.merge(user2_settings))
-            .coordinate();
+        self.wait_for_partner(room_hash, id, client, partner).await
     }
-}
 
-/// If someone writes more than 64 bytes, we might also consume them.
-fn wait_for_room_hash(stream: &mut TcpStream) -> [u8; 64] {
-    let mut room_hash = [0; 64];
+    /// Keeps a client company until a partner shows up, then runs their call.
+    ///
+    /// The client is pinged every `waiting_ping_interval`, and leaves the room if it hangs up,
+    /// disconnects, or has not answered a ping by the time the next one is due.
+    async fn wait_for_partner(
+        &self,
+        room_hash: RoomHash,
+        id: u64,
+        (mut stream, settings): Joiner,
+        mut partner: oneshot::Receiver<Handoff>,
+    ) -> Result<(), ServerError> {
+        let mut pings = time::interval(self.config.waiting_ping_interval());
+        pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is immediate, the client just heard from us
+        pings.tick().await;
+        let mut answered = true;
+
+        let left = loop {
+            tokio::select! {
+                joined = &mut partner => {
+                    let Ok(Handoff { partner: (partner_stream, partner_settings), mut joiners }) =
+                        joined
+                    else {
+                        // The entry is only dropped by this task or after sending
+                        unreachable!("Waiting entry dropped without a partner");
+                    };
+
+                    println!("Initiating call with partner");
+                    // Anyone may join a group call later on, only the server can take them in,
+                    // and only the server can mix a call
+                    let settings = settings.merge(partner_settings).merge(CallSettings {
+                        relay: self.config.force_relay
+                            || self.config.max_participants > 2
+                            || self.config.mix_audio,
+                    });
+
+                    let result = CallCoordinator::new(
+                        stream,
+                        partner_stream,
+                        settings,
+                        self.config.clone(),
+                        self.mux.clone(),
+                    )
+                    .coordinate(&mut joiners)
+                    .await;
+
+                    self.disband(room_hash, joiners, None);
+                    return result;
+                }
+                message = stream.recv::<ClientMessage>() => match message {
+                    Ok(ClientMessage::Ready) => answered = true,
+                    Ok(ClientMessage::Hangup) => {
+                        println!("Waiting client hung up");
+                        break Ok(());
+                    }
+                    Ok(other) => break Err(ServerError::UnexpectedMessage(other)),
+                    Err(e) => break Err(e.into()),
+                },
+                _ = pings.tick() => {
+                    if !answered {
+                        break Err(ServerError::Unresponsive);
+                    }
+                    if let Err(e) = stream.send(&ServerMessage::Ready).await {
+                        break Err(e.into());
+                    }
+                    answered = false;
+                }
+            }
+        };
+
+        // Leave the room. A partner may have joined meanwhile, they are handed over with the
+        // lock held so it is here by now.
+        let handoff = {
+            let mut rooms = lock(&self.rooms);
+            match rooms.get(&room_hash) {
+                Some(Room::Waiting(waiting)) if waiting.id == id => {
+                    rooms.remove(&room_hash);
+                    None
+                }
+                _ => partner.try_recv().ok(),
+            }
+        };
+        match &left {
+            Ok(()) => stream.close().await,
+            Err(e) => stream.close_with(e).await,
+        }
+
+        // Our call is over before it started, those who were joining it get the room back
+        if let Some(Handoff { partner, joiners }) = handoff {
+            self.disband(room_hash, joiners, Some(partner));
+        }
 
-    stream
-        .read_exact(&mut room_hash)
-        .expect("Failed to read room hash from stream");
+        left
+    }
 
-    room_hash
+    /// Frees the room of a call that is over, and takes whoever was on their way into it
+    /// through the room again.
+    fn disband(
+        &self,
+        room_hash: RoomHash,
+        mut joiners: mpsc::UnboundedReceiver<Joiner>,
+        partner: Option<Joiner>,
+    ) {
+        // From now on, those joining find the room free
+        joiners.close();
+        {
+            let mut rooms = lock(&self.rooms);
+            if matches!(rooms.get(&room_hash), Some(Room::InCall(call)) if call.is_closed()) {
+                rooms.remove(&room_hash);
+            }
+        }
+
+        let stranded = partner
+            .into_iter()
+            .chain(iter::from_fn(|| joiners.try_recv().ok()));
+        for joiner in stranded {
+            let rooms = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = rooms.join_room(room_hash, joiner).await {
+                    eprintln!("Connection failed: {}", e);
+                }
+            });
+        }
+    }
 }
 
-fn wait_for_preferred_settings(stream: &mut TcpStream) -> CallSettings {
-    let mut buffer = [0; 1];
+/// Waits for the client to introduce itself, answering with our own hello if we can talk to it,
+/// with those of our `capabilities` it shares. With `tls_required`, clients that cannot switch
+/// to TLS are turned away instead.
+///
+/// Clients older than the hello send their room hash first, which never decodes as a hello.
+async fn wait_for_hello(
+    stream: &mut Signaling,
+    capabilities: Capabilities,
+    tls_required: bool,
+) -> Result<(), ServerError> {
+    let hello = time::timeout(HELLO_TIMEOUT, stream.recv::<ClientMessage>()).await;
+
+    match hello {
+        Ok(Ok(ClientMessage::Hello {
+            version,
+            capabilities: client_capabilities,
+        })) => {
+            if !is_supported_version(version) {
+                return Err(ServerError::UnsupportedVersion);
+            }
+            if tls_required && !client_capabilities.contains(Capabilities::TLS) {
+                return Err(ServerError::TlsRequired);
+            }
+
+            stream.capabilities = client_capabilities & capabilities;
+            let hello = ServerMessage::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: stream.capabilities,
+            };
+            stream.send(&hello).await?;
 
-    if stream.read_exact(&mut buffer).is_err() {
-        eprintln!("Failed to read preferred settings from stream.");
-        return CallSettings { relay: false };
+            Ok(())
+        }
+        Ok(Err(ReadError::Io(e))) => Err(ServerError::Connection(e)),
+        // Anything else, including a stall, is most likely a client from before the hello.
+        _ => Err(ServerError::UnsupportedVersion),
     }
+}
 
-    if buffer[0] == 1 {
-        CallSettings { relay: true }
-    } else {
-        CallSettings { relay: false }
+/// Reads the room the client wants to join, along with its preferred call settings.
+async fn wait_for_join_room(
+    stream: &mut Signaling,
+) -> Result<(RoomHash, CallSettings), ServerError> {
+    match stream.recv().await? {
+        ClientMessage::JoinRoom { room_hash, relay } => Ok((room_hash, CallSettings { relay })),
+        other => Err(ServerError::UnexpectedMessage(other)),
     }
 }
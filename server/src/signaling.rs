@@ -0,0 +1,172 @@
+use std::{
+    io, mem,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use simple_call_protocol::{
+    DecodeError, Message, ReadError,
+    signal::{Capabilities, ServerMessage},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    sync::OwnedSemaphorePermit,
+    time,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use crate::error::ServerError;
+
+/// How long to wait for a client to close its side once we closed ours.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client's signaling connection.
+///
+/// Receiving is cancel safe: what was read of a message is kept until the rest arrives, so
+/// [`Signaling::recv`] can be raced against other events.
+pub struct Signaling {
+    stream: Stream,
+    buffer: Vec<u8>,
+    /// What the client and we both support, once it introduced itself.
+    pub capabilities: Capabilities,
+    /// Counts the connection against `max_clients` for as long as it lives.
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+/// What a signaling connection runs over.
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    /// Only while the TLS handshake runs, and for good if it fails.
+    Upgrading,
+}
+
+impl Signaling {
+    pub fn new(stream: TcpStream, slot: Option<OwnedSemaphorePermit>) -> Self {
+        Self {
+            stream: Stream::Plain(stream),
+            buffer: Vec::new(),
+            capabilities: Capabilities::NONE,
+            _slot: slot,
+        }
+    }
+
+    /// Waits for the next message. The connection closing is an
+    /// [`io::ErrorKind::UnexpectedEof`] error.
+    pub async fn recv<M: Message>(&mut self) -> Result<M, ReadError> {
+        loop {
+            match M::decode(&self.buffer) {
+                Ok((message, len)) => {
+                    self.buffer.drain(..len);
+                    return Ok(message);
+                }
+                Err(DecodeError::Incomplete(_)) => {
+                    let mut chunk = [0; 1024];
+                    let read = self.stream.read(&mut chunk).await?;
+                    if read == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    self.buffer.extend_from_slice(&chunk[..read]);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: &impl Message) -> io::Result<()> {
+        self.stream.write_all(&message.to_bytes()).await?;
+        // TLS may hold on to what was written until flushed
+        self.stream.flush().await
+    }
+
+    /// Takes the client through the TLS handshake, everything after goes through TLS.
+    pub async fn start_tls(&mut self, acceptor: &TlsAcceptor) -> Result<(), ServerError> {
+        // The client waits for our hello before starting the handshake, anything it sent
+        // in the clear since then is out of turn
+        if !self.buffer.is_empty() {
+            return Err(ServerError::Malformed(DecodeError::Invalid(
+                "data before the TLS handshake",
+            )));
+        }
+
+        let Stream::Plain(stream) = mem::replace(&mut self.stream, Stream::Upgrading) else {
+            unreachable!("TLS started twice");
+        };
+        let stream = acceptor.accept(stream).await?;
+        self.stream = Stream::Tls(Box::new(stream));
+
+        Ok(())
+    }
+
+    /// Closes the connection without discarding what we sent last.
+    ///
+    /// Dropping a socket that still has unread input resets the connection, and the reset can
+    /// reach the client before our last message does.
+    pub async fn close(mut self) {
+        if self.stream.shutdown().await.is_err() {
+            return;
+        }
+
+        let drain = async {
+            let mut buffer = [0; 1024];
+            while let Ok(1..) = self.stream.read(&mut buffer).await {}
+        };
+        let _ = time::timeout(CLOSE_TIMEOUT, drain).await;
+    }
+
+    /// Tells the client why we give up on it, if there is anything to tell, and closes the
+    /// connection.
+    pub async fn close_with(mut self, error: &ServerError) {
+        if let Some(code) = error.code() {
+            // The client may be gone already, closing is all that is left to do anyway
+            let _ = self.send(&ServerMessage::Error(code)).await;
+        }
+        self.close().await;
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How often the relay counters are printed, when they changed.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What happened to the datagrams the server received, across all calls.
+pub static RELAY_STATS: RelayStats = RelayStats::new();
+
+pub struct RelayStats {
+    /// Datagrams handed over to the partner.
+    pub forwarded: AtomicU64,
+    /// Datagrams of audio mixed by the server, sent to participants of mixed calls.
+    pub mixed: AtomicU64,
+    /// Datagrams too large to be received whole, dropped.
+    pub oversized: AtomicU64,
+    /// Datagrams too short to hold a session id, dropped.
+    pub malformed: AtomicU64,
+    /// Datagrams for a session that is not, or no longer, part of a call, dropped.
+    pub stray: AtomicU64,
+    /// Datagrams for a session, but not from its client's address, dropped.
+    pub spoofed: AtomicU64,
+}
+
+/// The counters of [`RelayStats`] at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub forwarded: u64,
+    pub mixed: u64,
+    pub oversized: u64,
+    pub malformed: u64,
+    pub stray: u64,
+    pub spoofed: u64,
+}
+
+impl RelayStats {
+    const fn new() -> Self {
+        Self {
+            forwarded: AtomicU64::new(0),
+            mixed: AtomicU64::new(0),
+            oversized: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            stray: AtomicU64::new(0),
+            spoofed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+            mixed: self.mixed.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+            stray: self.stray.load(Ordering::Relaxed),
+            spoofed: self.spoofed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} forwarded, {} mixed, {} oversized, {} malformed, {} stray, {} spoofed",
+            self.forwarded, self.mixed, self.oversized, self.malformed, self.stray, self.spoofed
+        )
+    }
+}
+
+/// Prints the relay counters every [`REPORT_INTERVAL`], skipping the quiet ones.
+pub async fn report_periodically() {
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    let mut last = RELAY_STATS.snapshot();
+
+    loop {
+        interval.tick().await;
+
+        let current = RELAY_STATS.snapshot();
+        if current != last {
+            println!("Relay datagrams: {}", current);
+            last = current;
+        }
+    }
+}
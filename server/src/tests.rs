@@ -1,36 +1,328 @@
 use std::{
+    fs,
     io::{Read, Write},
-    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::{Arc, Once},
 };
 
 use sha2::{Digest, Sha512};
+use simple_call_protocol::{
+    Message,
+    key_exchange::{
+        COMMITMENT_LEN, Commitment, PUBLIC_KEY_LEN, PublicKey, SEALED_KEY_LEN, SealedKey,
+    },
+    media::{MediaHeader, MediaSender},
+    pake::Pake,
+    relay::{self, SessionId, Token},
+    report::Report,
+    signal::{
+        Capabilities, ClientMessage, ErrorCode, PROTOCOL_VERSION, ParticipantInfo, RoomHash,
+        ServerMessage,
+    },
+};
+
+use tokio_rustls::rustls::{
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName, pem::PemObject},
+};
 
 use crate::{
-    call_coordinator::SIGNAL_PARTNER_FOUND, main, room_coordinator::SIGNAL_WAITING_IN_ROOM,
+    config::{Config, PortRange},
+    run,
+    stats::RELAY_STATS,
+};
+
+/// What every test client knows the rooms it joins by.
+const ROOM_SECRET: &[u8] = b"open sesame";
+
+/// TCP port of the server running with the default config.
+const DEFAULT_PORT: u16 = 8383;
+/// TCP port of the server relaying every call, through a few known UDP ports.
+const RELAY_PORT: u16 = 8384;
+const RELAY_UDP_PORTS: PortRange = PortRange {
+    start: 47000,
+    end: 47009,
 };
+/// TCP port of the server relaying every call through a single UDP port.
+const SHARED_PORT: u16 = 8385;
+const SHARED_UDP_PORT: u16 = 47100;
+/// TCP port of the server relaying every call, letting quiet clients move after a while.
+const MIGRATION_PORT: u16 = 8386;
+const MIGRATION_AFTER_MS: u64 = 100;
+/// TCP port of the server relaying every call, with short timeouts: calls end as soon as they
+/// go quiet or their handshake takes too long, and waiting clients are pinged often.
+const IDLE_PORT: u16 = 8387;
+const WAITING_PING_INTERVAL_MS: u64 = 100;
+/// TCP port of the server serving a single client at once.
+const BUSY_PORT: u16 = 8388;
+/// TCP port of the server taking up to three participants in a call.
+const GROUP_PORT: u16 = 8389;
+/// TCP port of the server mixing every call.
+const MIX_PORT: u16 = 8390;
+/// TCP port of the server taking up to three participants in a call, which those knowing
+/// `MODERATOR_SECRET` may moderate.
+const MODERATED_PORT: u16 = 8391;
+const MODERATOR_SECRET: &str = "let me moderate";
+/// TCP port of the server switching clients that can to TLS, serving the others in the clear.
+const TLS_PORT: u16 = 8392;
+/// TCP port of the server only serving clients switching to TLS.
+const TLS_ONLY_PORT: u16 = 8393;
+
+/// Starts the servers once for all tests, they share them.
+fn start_servers() {
+    static START: Once = Once::new();
+
+    START.call_once(|| {
+        spawn_server(Config::default());
+        spawn_server(Config {
+            tcp_port: RELAY_PORT,
+            udp_port_range: Some(RELAY_UDP_PORTS),
+            force_relay: true,
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: SHARED_PORT,
+            relay_port: Some(SHARED_UDP_PORT),
+            force_relay: true,
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: MIGRATION_PORT,
+            force_relay: true,
+            migration_after_ms: Some(MIGRATION_AFTER_MS),
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: IDLE_PORT,
+            force_relay: true,
+            relay_idle_timeout_ms: 200,
+            waiting_ping_interval_ms: WAITING_PING_INTERVAL_MS,
+            handshake_retries: 2,
+            handshake_interval_ms: 150,
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: BUSY_PORT,
+            max_clients: Some(1),
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: GROUP_PORT,
+            max_participants: 3,
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: MIX_PORT,
+            max_participants: 3,
+            mix_audio: true,
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: MODERATED_PORT,
+            max_participants: 3,
+            moderator_secret: Some(MODERATOR_SECRET.to_string()),
+            ..Config::default()
+        });
+        let (tls_cert, tls_key) = write_tls_certificate();
+        spawn_server(Config {
+            tcp_port: TLS_PORT,
+            tls_cert: Some(tls_cert.clone()),
+            tls_key: Some(tls_key.clone()),
+            ..Config::default()
+        });
+        spawn_server(Config {
+            tcp_port: TLS_ONLY_PORT,
+            tls_cert: Some(tls_cert),
+            tls_key: Some(tls_key),
+            tls_required: true,
+            ..Config::default()
+        });
+        // Give them time to bind their listeners
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    });
+}
 
-fn addr_from_bytes(buffer: &[u8]) -> SocketAddr {
-    let ip = IpAddr::from([buffer[0], buffer[1], buffer[2], buffer[3]]);
-    let port = u16::from_be_bytes([buffer[4], buffer[5]]);
-    SocketAddr::new(ip, port)
+fn spawn_server(config: Config) {
+    std::thread::spawn(|| {
+        tokio::runtime::Runtime::new()
+            .expect("Failed to start the async runtime.")
+            .block_on(run(config));
+    });
 }
 
-fn conn(room: &[u8], send_msg: &[u8], recv_msg: &[u8]) {
-    let mut tcp_stream = TcpStream::connect("127.0.0.1:8383")
-        .expect("Failed to connect to TCP listener. Is the server running?");
+/// Where the TLS servers' certificate is written, in PEM.
+fn tls_certificate_path() -> PathBuf {
+    std::env::temp_dir().join(format!("simple_call_test_cert_{}.pem", std::process::id()))
+}
 
-    let room_hash = Sha512::digest(room);
+/// Makes a certificate for 127.0.0.1, and writes it and its key where the TLS servers read
+/// them.
+fn write_tls_certificate() -> (PathBuf, PathBuf) {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()])
+        .expect("Failed to generate a certificate.");
 
-    tcp_stream
-        .write_all(&room_hash)
-        .expect("Failed to write to TCP stream.");
-    tcp_stream
-        .write_all(&[0])
-        .expect("Failed to write to TCP stream.");
+    let cert_path = tls_certificate_path();
+    let key_path = cert_path.with_extension("key");
+    fs::write(&cert_path, certified.cert.pem()).expect("Failed to write the certificate.");
+    fs::write(&key_path, certified.signing_key.serialize_pem()).expect("Failed to write the key.");
+
+    (cert_path, key_path)
+}
+
+fn localhost(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port)
+}
+
+fn connect_to(server: SocketAddr) -> TcpStream {
+    start_servers();
+
+    let tcp_stream = TcpStream::connect(server)
+        .expect("Failed to connect to TCP listener. Is the server running?");
 
     tcp_stream
         .set_read_timeout(Some(std::time::Duration::from_secs(1)))
         .expect("Failed to set read timeout.");
+
+    tcp_stream
+}
+
+fn connect() -> TcpStream {
+    connect_to(localhost(DEFAULT_PORT))
+}
+
+fn send_hello(tcp_stream: &mut TcpStream, version: u16) -> ServerMessage {
+    send_hello_with(tcp_stream, version, Capabilities::NONE)
+}
+
+fn send_hello_with(
+    tcp_stream: &mut TcpStream,
+    version: u16,
+    capabilities: Capabilities,
+) -> ServerMessage {
+    ClientMessage::Hello {
+        version,
+        capabilities,
+    }
+    .write_to(tcp_stream)
+    .expect("Failed to write to TCP stream.");
+
+    ServerMessage::read_from(tcp_stream).expect("Failed to read from TCP stream.")
+}
+
+/// Says hello offering TLS, and switches to it, trusting the test certificate.
+fn connect_tls(server: SocketAddr) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut tcp_stream = connect_to(server);
+
+    let ServerMessage::Hello { capabilities, .. } =
+        send_hello_with(&mut tcp_stream, PROTOCOL_VERSION, Capabilities::TLS)
+    else {
+        panic!("Expected the server's hello");
+    };
+    assert!(capabilities.contains(Capabilities::TLS));
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(tls_certificate_path()).unwrap())
+        .unwrap();
+    let tls_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connection = ClientConnection::new(
+        Arc::new(tls_config),
+        ServerName::IpAddress(IpAddr::from([127, 0, 0, 1]).into()),
+    )
+    .unwrap();
+
+    StreamOwned::new(connection, tcp_stream)
+}
+
+/// A client's end of a call, once the server told it where to send its audio.
+struct Media {
+    /// The call lasts as long as this stays open.
+    tcp_stream: TcpStream,
+    udp_sock: UdpSocket,
+    peer_udp_addr: SocketAddr,
+    /// What goes in front of every datagram, our session id when relayed.
+    header: Vec<u8>,
+    /// The datagram registering our address.
+    registration: Vec<u8>,
+    session_id: SessionId,
+    server_udp_port: u16,
+    relay: bool,
+    /// What we prove we know the room secret with, to those joining after us too.
+    pake: Pake,
+}
+
+impl Media {
+    fn send(&self, msg: &[u8]) {
+        let datagram = [&self.header[..], msg].concat();
+
+        self.udp_sock
+            .send_to(&datagram, self.peer_udp_addr)
+            .expect("Failed to send UDP packet.");
+    }
+
+    /// Receives one datagram from the call, failing the test if none comes.
+    fn recv(&self) -> Vec<u8> {
+        self.recv_from_participant().1
+    }
+
+    /// Receives one datagram from the call, along with who sent it when relayed.
+    fn recv_from_participant(&self) -> (Option<SessionId>, Vec<u8>) {
+        let datagram = recv(&self.udp_sock);
+        if !self.relay {
+            return (None, datagram);
+        }
+
+        // The relay tells who sent what
+        let (sender, payload) = relay::split(&datagram).expect("Relayed datagrams name the sender");
+        assert_ne!(sender, self.session_id, "The relay sent our own media back");
+        (Some(sender), payload.to_vec())
+    }
+}
+
+/// What the server tells a client once it has a partner.
+struct PartnerFound {
+    tcp_stream: TcpStream,
+    room_hash: RoomHash,
+    server_udp_port: u16,
+    relay: bool,
+    session_id: SessionId,
+    token: Token,
+}
+
+/// Joins `room` as a client asking for a direct call, up to the point media can flow.
+fn join(server: SocketAddr, room: &[u8]) -> Media {
+    register(server, find_partner(server, room))
+}
+
+/// Joins `room` as a client asking for a direct call, and waits for a partner.
+fn find_partner(server: SocketAddr, room: &[u8]) -> PartnerFound {
+    find_partner_with(server, room, Capabilities::NONE)
+}
+
+/// Like [`find_partner`], for a client supporting `capabilities`.
+fn find_partner_with(server: SocketAddr, room: &[u8], capabilities: Capabilities) -> PartnerFound {
+    let mut tcp_stream = connect_to(server);
+
+    assert!(matches!(
+        send_hello_with(&mut tcp_stream, PROTOCOL_VERSION, capabilities),
+        ServerMessage::Hello { .. }
+    ));
+
+    let room_hash = Sha512::digest(room).into();
+
+    ClientMessage::JoinRoom {
+        room_hash,
+        relay: false,
+    }
+    .write_to(&mut tcp_stream)
+    .expect("Failed to write to TCP stream.");
+
     tcp_stream
         .set_write_timeout(Some(std::time::Duration::from_secs(1)))
         .expect("Failed to set write timeout.");
@@ -40,64 +332,151 @@ fn conn(room: &[u8], send_msg: &[u8], recv_msg: &[u8]) {
 
     // Read the response from the stream
 
-    let mut buffer = [0; 1024];
+    let read_message = |tcp_stream: &mut TcpStream| {
+        ServerMessage::read_from(tcp_stream).expect("Failed to read from TCP stream.")
+    };
 
-    tcp_stream
-        .read_exact(&mut buffer[..4])
-        .expect("Failed to read from TCP stream.");
+    assert_eq!(read_message(&mut tcp_stream), ServerMessage::WaitingInRoom);
 
-    assert!(matches!(
-        buffer[0..4],
-        [SIGNAL_WAITING_IN_ROOM, SIGNAL_PARTNER_FOUND, _, _]
-    ));
+    // Answer the server's pings while waiting, like the client does
+    let (server_udp_port, relay, session_id, token) = loop {
+        match read_message(&mut tcp_stream) {
+            ServerMessage::Ready => ClientMessage::Ready
+                .write_to(&mut tcp_stream)
+                .expect("Failed to write to TCP stream."),
+            ServerMessage::PartnerFound {
+                udp_port,
+                relay,
+                session_id,
+                token,
+            } => break (udp_port, relay, session_id, token),
+            other => panic!("Expected PartnerFound, got {:?}", other),
+        }
+    };
+
+    PartnerFound {
+        tcp_stream,
+        room_hash,
+        server_udp_port,
+        relay,
+        session_id,
+        token,
+    }
+}
 
-    let server_udp_port = u16::from_be_bytes([buffer[2], buffer[3]]);
+/// Registers a new UDP socket's address with the server, as the client `found` is.
+fn register(server: SocketAddr, found: PartnerFound) -> Media {
+    let PartnerFound {
+        mut tcp_stream,
+        room_hash,
+        server_udp_port,
+        relay,
+        session_id,
+        token,
+    } = found;
 
-    let udp_sock =
-        UdpSocket::bind("0.0.0.0:0").expect("Failed to bind UDP socket. All UDP ports are in use?");
+    let udp_sock = UdpSocket::bind(SocketAddr::new(server.ip(), 0))
+        .expect("Failed to bind UDP socket. All UDP ports are in use?");
 
     udp_sock
         .set_read_timeout(Some(std::time::Duration::from_secs(1)))
         .expect("Failed to set read timeout.");
 
-    let server_udp_addr = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), server_udp_port);
+    let pake = Pake::new(&room_hash, ROOM_SECRET);
+    if let Err(code) = prove_secret(&mut tcp_stream, &pake) {
+        panic!("Expected to be admitted, got {:?}", code);
+    }
+
+    let server_udp_addr = SocketAddr::new(server.ip(), server_udp_port);
+    let registration = relay::registration(session_id, &token).to_vec();
 
     udp_sock
-        .send_to(&[], server_udp_addr)
+        .send_to(&registration, server_udp_addr)
         .expect("Failed to send UDP packet.");
 
     // Wait for a bit to ensure the server has processed the request
     std::thread::sleep(std::time::Duration::from_millis(10));
 
-    let peer_udp_addr = {
-        let size = tcp_stream
-            .read(&mut buffer)
-            .expect("Failed to read from TCP stream.");
-
-        assert_eq!(
-            size, 6,
-            "Expected to receive 6 bytes, ipv4 addr + port, but received {} bytes",
-            size
-        );
+    let peer_udp_addr = if relay {
+        server_udp_addr
+    } else {
+        match ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream.") {
+            ServerMessage::PeerAddress(addr) => addr,
+            other => panic!("Expected PeerAddress, received {:?}", other),
+        }
+    };
 
-        addr_from_bytes(&buffer[0..6])
+    // What goes through the server starts with our session id
+    let header = if relay {
+        session_id.to_bytes().to_vec()
+    } else {
+        Vec::new()
     };
 
-    udp_sock
-        .send_to(send_msg, peer_udp_addr)
-        .expect("Failed to send UDP packet.");
+    Media {
+        tcp_stream,
+        udp_sock,
+        peer_udp_addr,
+        header,
+        registration,
+        session_id,
+        server_udp_port,
+        relay,
+        pake,
+    }
+}
+
+/// Proves we know the room secret `pake` was made with, until the server admits us or gives
+/// up on us.
+fn prove_secret(tcp_stream: &mut TcpStream, pake: &Pake) -> Result<(), ErrorCode> {
+    ClientMessage::KeyShare(pake.share())
+        .write_to(tcp_stream)
+        .expect("Failed to write to TCP stream.");
+
+    let mut exchange = None;
+    loop {
+        let reply =
+            match ServerMessage::read_from(tcp_stream).expect("Failed to read from TCP stream.") {
+                ServerMessage::PeerKeyShare { peer, key_share } => {
+                    let started = pake.exchange(&key_share).expect("Invalid key share");
+                    let confirmation = started.confirmation();
+                    exchange = Some(started);
+                    ClientMessage::KeyConfirmation { peer, confirmation }
+                }
+                ServerMessage::PeerKeyConfirmation { peer, confirmation } => {
+                    let exchange = exchange.take().expect("Confirmation before the key share");
+                    ClientMessage::PeerVerified {
+                        peer,
+                        verified: exchange.finish(&confirmation).is_ok(),
+                    }
+                }
+                ServerMessage::Admitted => return Ok(()),
+                ServerMessage::Error(code) => return Err(code),
+                other => panic!("Expected the exchange to go on, got {:?}", other),
+            };
+        reply
+            .write_to(tcp_stream)
+            .expect("Failed to write to TCP stream.");
+    }
+}
+
+/// Joins `room` as a client asking for a direct call, and exchanges one datagram with the
+/// partner. Returns the server UDP port it was given, and whether the call was relayed.
+fn conn(server: SocketAddr, room: &[u8], send_msg: &[u8], recv_msg: &[u8]) -> (u16, bool) {
+    let media = join(server, room);
+
+    media.send(send_msg);
 
     // Wait for a bit to ensure the server has processed the request
     std::thread::sleep(std::time::Duration::from_millis(10));
 
-    let (size, _) = udp_sock
-        .recv_from(&mut buffer)
-        .expect("Failed to receive UDP packet.");
+    let buffer = media.recv();
 
     assert_eq!(
-        size, 1,
+        buffer.len(),
+        1,
         "Expected to receive 1 byte, but received {} bytes",
-        size
+        buffer.len()
     );
 
     assert_eq!(
@@ -105,31 +484,1026 @@ fn conn(room: &[u8], send_msg: &[u8], recv_msg: &[u8]) {
         "Expected to receive {}, but received {}",
         recv_msg[0], buffer[0]
     );
+
+    (media.server_udp_port, media.relay)
+}
+
+/// Runs a call between two clients, returning what each of them got from `conn`.
+fn call(server: SocketAddr, room: &str) -> [(u16, bool); 2] {
+    let room1 = room.as_bytes().to_vec();
+    let client1 = std::thread::spawn(move || conn(server, &room1, &[42], &[24]));
+
+    let room2 = room.as_bytes().to_vec();
+    let client2 = std::thread::spawn(move || conn(server, &room2, &[24], &[42]));
+
+    while !client1.is_finished() || !client2.is_finished() {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+
+    [
+        client1.join().expect("Client 1 failed"),
+        client2.join().expect("Client 2 failed"),
+    ]
 }
 
 #[test]
 fn end_to_end() {
     println!("Running end-to-end test...");
-    // Call main in a different thread
-    std::thread::spawn(|| {
-        main();
-    });
 
     // Run several times to detect race conditions, has happened before
     for _ in 0..20 {
-        let client1 = std::thread::spawn(move || {
-            conn(b"room", &[42], &[24]);
-        });
+        call(localhost(DEFAULT_PORT), "room");
+    }
+}
+
+#[test]
+fn room_is_free_while_the_last_call_closes() {
+    let server = localhost(DEFAULT_PORT);
+    let first = std::thread::spawn(move || join(server, b"redialled room"));
+    let mut second = join(server, b"redialled room");
+    let mut first = first.join().expect("First participant failed");
+
+    // The server waits for the one still holding on to let go, not the next call
+    first.signal(ClientMessage::Hangup);
+    second.expect_signal(ServerMessage::PartnerLeft);
+    call(server, "redialled room");
+}
+
+#[test]
+fn end_to_end_ipv6() {
+    for _ in 0..5 {
+        call(
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), DEFAULT_PORT),
+            "ipv6 room",
+        );
+    }
+}
+
+#[test]
+fn forced_relay_uses_configured_ports() {
+    for _ in 0..5 {
+        for (udp_port, relay) in call(localhost(RELAY_PORT), "relay room") {
+            assert!(relay, "The server should have relayed the call");
+            assert!(
+                RELAY_UDP_PORTS.ports().any(|port| port == udp_port),
+                "UDP port {} is outside of {}",
+                udp_port,
+                RELAY_UDP_PORTS
+            );
+        }
+    }
+}
+
+#[test]
+fn rejects_unsupported_version() {
+    let mut tcp_stream = connect();
+
+    assert_eq!(
+        send_hello(&mut tcp_stream, PROTOCOL_VERSION + 1),
+        ServerMessage::Error(ErrorCode::UnsupportedVersion)
+    );
+}
+
+#[test]
+fn rejects_clients_without_hello() {
+    let mut tcp_stream = connect();
+
+    // What clients did before the hello existed: the raw room hash and the relay flag.
+    let mut old_join = Sha512::digest(b"old room").to_vec();
+    old_join.push(0);
+    tcp_stream
+        .write_all(&old_join)
+        .expect("Failed to write to TCP stream.");
+
+    assert_eq!(
+        ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::Error(ErrorCode::UnsupportedVersion)
+    );
+}
 
-        let client2 = std::thread::spawn(move || {
-            conn(b"room", &[24], &[42]);
+#[test]
+fn misbehaving_client_is_told_and_others_carry_on() {
+    let mut tcp_stream = connect();
+    send_hello(&mut tcp_stream, PROTOCOL_VERSION);
+
+    // A hangup where the room should be
+    ClientMessage::Hangup
+        .write_to(&mut tcp_stream)
+        .expect("Failed to write to TCP stream.");
+
+    assert_eq!(
+        ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::Error(ErrorCode::MalformedMessage)
+    );
+
+    // Bytes that are no message at all, while waiting in a room
+    let mut tcp_stream = wait_in_room(localhost(DEFAULT_PORT), b"garbage room");
+    tcp_stream
+        .write_all(&[0xff])
+        .expect("Failed to write to TCP stream.");
+
+    assert_eq!(
+        ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::Error(ErrorCode::MalformedMessage)
+    );
+
+    call(localhost(DEFAULT_PORT), "after the misbehaving clients");
+}
+
+#[test]
+fn shared_relay_port_carries_concurrent_calls() {
+    let calls: Vec<_> = (0..5)
+        .map(|i| {
+            std::thread::spawn(move || call(localhost(SHARED_PORT), &format!("shared room {}", i)))
+        })
+        .collect();
+
+    for call in calls {
+        for (udp_port, relay) in call.join().expect("Call failed") {
+            assert!(relay, "The server should have relayed the call");
+            assert_eq!(udp_port, SHARED_UDP_PORT);
+        }
+    }
+}
+
+#[test]
+fn relay_is_not_paced_by_a_timer() {
+    const ROUND_TRIPS: u32 = 50;
+
+    let server = localhost(SHARED_PORT);
+    let echo = std::thread::spawn(move || {
+        let media = join(server, b"ping pong room");
+
+        for _ in 0..ROUND_TRIPS {
+            media.send(&media.recv());
+        }
+
+        // Hanging up could cut the last pong
+        media
+    });
+
+    let media = join(server, b"ping pong room");
+
+    // The first exchange may wait for the partner to finish joining
+    let start = std::time::Instant::now();
+    for i in 0..ROUND_TRIPS {
+        media.send(&[i as u8]);
+        assert_eq!(media.recv(), [i as u8]);
+    }
+    let elapsed = start.elapsed();
+
+    drop(echo.join().expect("Echo client failed"));
+
+    // Each round trip is two hops through the relay, a loopback one takes microseconds
+    assert!(
+        elapsed < std::time::Duration::from_millis(500),
+        "{} round trips through the relay took {:?}",
+        ROUND_TRIPS,
+        elapsed
+    );
+}
+
+#[test]
+fn relay_forwards_datagrams_of_any_size() {
+    // Up to the largest a loopback IPv4 datagram can be, once the session id is added
+    const SIZES: [usize; 4] = [1, 1500, 9000, 65_507 - relay::HEADER_LEN];
+
+    for server in [localhost(RELAY_PORT), localhost(SHARED_PORT)] {
+        let sender = std::thread::spawn(move || {
+            let media = join(server, b"large datagrams room");
+            for size in SIZES {
+                media.send(&vec![size as u8; size]);
+            }
+
+            // Hanging up could cut what is still on its way
+            media
         });
 
-        while !client1.is_finished() || !client2.is_finished() {
-            std::thread::sleep(std::time::Duration::from_millis(1));
+        let media = join(server, b"large datagrams room");
+
+        for size in SIZES {
+            let received = media.recv();
+
+            assert_eq!(
+                received.len(),
+                size,
+                "The relay changed the datagram's size"
+            );
+            assert!(received.iter().all(|&byte| byte == size as u8));
         }
 
-        client1.join().expect("Client 1 failed");
-        client2.join().expect("Client 2 failed");
+        drop(sender.join().expect("Sender failed"));
+    }
+}
+
+#[test]
+fn relay_counts_malformed_datagrams() {
+    start_servers();
+
+    let before = RELAY_STATS.snapshot();
+
+    // Too short to hold a session id
+    let udp_sock = UdpSocket::bind(localhost(0)).expect("Failed to bind UDP socket.");
+    udp_sock
+        .send_to(&[1, 2], localhost(SHARED_UDP_PORT))
+        .expect("Failed to send UDP packet.");
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+    while RELAY_STATS.snapshot().malformed == before.malformed {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "The malformed datagram was not counted"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(1));
     }
 }
+
+/// Receives one datagram on `udp_sock`, failing the test if none comes.
+fn recv(udp_sock: &UdpSocket) -> Vec<u8> {
+    let mut buffer = vec![0; 1 << 16];
+    let (size, _) = udp_sock
+        .recv_from(&mut buffer)
+        .expect("Failed to receive UDP packet.");
+    buffer[..size].to_vec()
+}
+
+#[test]
+fn relay_drops_spoofed_datagrams() {
+    let server = localhost(RELAY_PORT);
+    let partner = std::thread::spawn(move || join(server, b"spoofed room").recv());
+
+    let media = join(server, b"spoofed room");
+    let before = RELAY_STATS.snapshot();
+
+    // Someone else using our session id, through the same relay port
+    let spoofer = UdpSocket::bind(localhost(0)).expect("Failed to bind UDP socket.");
+    spoofer
+        .send_to(&[&media.header[..], &[66]].concat(), media.peer_udp_addr)
+        .expect("Failed to send UDP packet.");
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    media.send(&[1]);
+
+    assert_eq!(partner.join().expect("Partner failed"), [1]);
+    assert!(RELAY_STATS.snapshot().spoofed > before.spoofed);
+}
+
+#[test]
+fn relay_lets_quiet_clients_migrate() {
+    let server = localhost(MIGRATION_PORT);
+    let partner = std::thread::spawn(move || {
+        let media = join(server, b"roaming room");
+        let received = media.recv();
+        media.send(&[10]);
+        // Staying in the call, so the server does not drop the reply with it
+        (received, media)
+    });
+
+    let media = join(server, b"roaming room");
+    let roamed = UdpSocket::bind(localhost(0)).expect("Failed to bind UDP socket.");
+    roamed
+        .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+        .expect("Failed to set read timeout.");
+    let send_from_roamed = |msg: &[u8]| {
+        roamed
+            .send_to(&[&media.header[..], msg].concat(), media.peer_udp_addr)
+            .expect("Failed to send UDP packet.");
+    };
+
+    let register_roamed = || {
+        roamed
+            .send_to(&media.registration, media.peer_udp_addr)
+            .expect("Failed to send UDP packet.");
+    };
+
+    // Too early, the client was just heard from its registered address
+    register_roamed();
+    send_from_roamed(&[7]);
+
+    std::thread::sleep(std::time::Duration::from_millis(MIGRATION_AFTER_MS * 3 / 2));
+
+    // Media alone does not move the session, a registration does
+    send_from_roamed(&[8]);
+    register_roamed();
+    send_from_roamed(&[9]);
+
+    let (received, _partner) = partner.join().expect("Partner failed");
+    assert_eq!(received, [9]);
+    assert_eq!(
+        relay::split(&recv(&roamed)).map(|(_, payload)| payload.to_vec()),
+        Some(vec![10]),
+        "The partner should reach the new address"
+    );
+}
+
+#[test]
+fn registration_needs_the_token() {
+    let server = localhost(DEFAULT_PORT);
+    let partner = std::thread::spawn(move || join(server, b"hijacked room").peer_udp_addr);
+
+    let found = find_partner(server, b"hijacked room");
+
+    // Someone who guessed our session id, racing us to register
+    let hijacker = UdpSocket::bind(localhost(0)).expect("Failed to bind UDP socket.");
+    hijacker
+        .send_to(
+            &relay::registration(found.session_id, &Token([0; relay::TOKEN_LEN])),
+            localhost(found.server_udp_port),
+        )
+        .expect("Failed to send UDP packet.");
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    let media = register(server, found);
+
+    assert_eq!(
+        partner.join().expect("Partner failed"),
+        media.udp_sock.local_addr().unwrap(),
+        "The partner should have been given our address"
+    );
+}
+
+impl Media {
+    /// Waits for the server to say the call is over, and to close the connection.
+    fn expect_partner_left(mut self) {
+        assert_eq!(
+            ServerMessage::read_from(&mut self.tcp_stream)
+                .expect("Failed to read from TCP stream."),
+            ServerMessage::PartnerLeft
+        );
+
+        let mut rest = Vec::new();
+        self.tcp_stream
+            .read_to_end(&mut rest)
+            .expect("The server should close the connection.");
+        assert!(rest.is_empty());
+    }
+}
+
+#[test]
+fn hangup_ends_the_call_for_the_partner() {
+    for server in [localhost(DEFAULT_PORT), localhost(RELAY_PORT)] {
+        let partner = std::thread::spawn(move || join(server, b"hangup room"));
+        let mut media = join(server, b"hangup room");
+        let partner = partner.join().expect("Partner failed");
+
+        ClientMessage::Hangup
+            .write_to(&mut media.tcp_stream)
+            .expect("Failed to write to TCP stream.");
+
+        partner.expect_partner_left();
+    }
+}
+
+#[test]
+fn disconnecting_ends_the_call_for_the_partner() {
+    for server in [localhost(DEFAULT_PORT), localhost(SHARED_PORT)] {
+        let partner = std::thread::spawn(move || join(server, b"disconnect room"));
+        let media = join(server, b"disconnect room");
+        let partner = partner.join().expect("Partner failed");
+
+        drop(media);
+
+        partner.expect_partner_left();
+    }
+}
+
+#[test]
+fn idle_relay_ends_the_call() {
+    let server = localhost(IDLE_PORT);
+    let partner = std::thread::spawn(move || join(server, b"idle room"));
+    let media = join(server, b"idle room");
+    let partner = partner.join().expect("Partner failed");
+
+    // Neither sends anything, both are told once the relay gives up
+    media.expect_partner_left();
+    partner.expect_partner_left();
+}
+
+/// Joins `room` and returns as soon as the server says we wait there.
+fn wait_in_room(server: SocketAddr, room: &[u8]) -> TcpStream {
+    let mut tcp_stream = connect_to(server);
+    send_hello(&mut tcp_stream, PROTOCOL_VERSION);
+
+    ClientMessage::JoinRoom {
+        room_hash: Sha512::digest(room).into(),
+        relay: false,
+    }
+    .write_to(&mut tcp_stream)
+    .expect("Failed to write to TCP stream.");
+
+    assert_eq!(
+        ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::WaitingInRoom
+    );
+
+    tcp_stream
+}
+
+#[test]
+fn disconnected_waiting_client_leaves_the_room() {
+    let server = localhost(DEFAULT_PORT);
+    drop(wait_in_room(server, b"abandoned room"));
+
+    // Give the server time to notice
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    // Otherwise, the first of these would be paired with the client that left
+    let partner = std::thread::spawn(move || join(server, b"abandoned room"));
+    let media = join(server, b"abandoned room");
+    let partner = partner.join().expect("Partner failed");
+
+    media.send(b"still here");
+    assert_eq!(partner.recv(), b"still here");
+}
+
+#[test]
+fn silent_waiting_client_is_dropped() {
+    let server = localhost(IDLE_PORT);
+    let mut tcp_stream = wait_in_room(server, b"silent room");
+
+    // Never answer the pings, the server hangs up once the second one is due
+    let mut received = Vec::new();
+    tcp_stream
+        .read_to_end(&mut received)
+        .expect("Failed to read until the server closed the connection.");
+    assert_eq!(
+        ServerMessage::decode(&received).map(|(message, _)| message),
+        Ok(ServerMessage::Ready)
+    );
+
+    let partner = std::thread::spawn(move || join(server, b"silent room"));
+    let media = join(server, b"silent room");
+    let partner = partner.join().expect("Partner failed");
+
+    media.send(b"still here");
+    assert_eq!(partner.recv(), b"still here");
+}
+
+#[test]
+fn waiting_client_answering_pings_stays_in_the_room() {
+    let server = localhost(IDLE_PORT);
+    let partner = std::thread::spawn(move || join(server, b"patient room"));
+
+    // Long enough for several pings
+    std::thread::sleep(std::time::Duration::from_millis(
+        WAITING_PING_INTERVAL_MS * 5,
+    ));
+
+    let media = join(server, b"patient room");
+    let partner = partner.join().expect("Partner failed");
+
+    media.send(b"still here");
+    assert_eq!(partner.recv(), b"still here");
+}
+
+#[test]
+fn room_with_a_call_is_full() {
+    let server = localhost(DEFAULT_PORT);
+    let partner = std::thread::spawn(move || join(server, b"full room"));
+    let media = join(server, b"full room");
+    let partner = partner.join().expect("Partner failed");
+
+    let mut tcp_stream = wait_in_room(server, b"full room");
+    assert_eq!(
+        ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::Error(ErrorCode::RoomFull)
+    );
+
+    // The call is not disturbed
+    media.send(b"still here");
+    assert_eq!(partner.recv(), b"still here");
+}
+
+#[test]
+fn clients_past_the_limit_are_told_the_server_is_busy() {
+    let server = localhost(BUSY_PORT);
+    let first = wait_in_room(server, b"busy room");
+
+    let mut tcp_stream = connect_to(server);
+    assert_eq!(
+        send_hello(&mut tcp_stream, PROTOCOL_VERSION),
+        ServerMessage::Error(ErrorCode::ServerBusy)
+    );
+
+    // The slot frees up as soon as the first client leaves
+    drop(first);
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut tcp_stream = connect_to(server);
+    assert!(matches!(
+        send_hello(&mut tcp_stream, PROTOCOL_VERSION),
+        ServerMessage::Hello { .. }
+    ));
+}
+
+#[test]
+fn handshake_timeout_is_reported() {
+    let server = localhost(IDLE_PORT);
+    let partner = std::thread::spawn(move || find_partner(server, b"unregistered room"));
+    let mut found = find_partner(server, b"unregistered room");
+    let mut partner = partner.join().expect("Partner failed");
+
+    // Neither registers its UDP address
+    for tcp_stream in [&mut found.tcp_stream, &mut partner.tcp_stream] {
+        assert_eq!(
+            ServerMessage::read_from(tcp_stream).expect("Failed to read from TCP stream."),
+            ServerMessage::Error(ErrorCode::HandshakeTimeout)
+        );
+    }
+}
+
+impl Media {
+    /// Checks that the one joining the call knows the room secret, as the server asks those
+    /// admitted first.
+    fn verify_joiner(&mut self) {
+        let (peer, key_share) = match ServerMessage::read_from(&mut self.tcp_stream)
+            .expect("Failed to read from TCP stream.")
+        {
+            ServerMessage::PeerKeyShare { peer, key_share } => (peer, key_share),
+            other => panic!("Expected the joiner's key share, got {:?}", other),
+        };
+        let exchange = self.pake.exchange(&key_share).expect("Invalid key share");
+        ClientMessage::KeyConfirmation {
+            peer,
+            confirmation: exchange.confirmation(),
+        }
+        .write_to(&mut self.tcp_stream)
+        .expect("Failed to write to TCP stream.");
+
+        let confirmation = match ServerMessage::read_from(&mut self.tcp_stream)
+            .expect("Failed to read from TCP stream.")
+        {
+            ServerMessage::PeerKeyConfirmation { confirmation, .. } => confirmation,
+            other => panic!("Expected the joiner's confirmation, got {:?}", other),
+        };
+        ClientMessage::PeerVerified {
+            peer,
+            verified: exchange.finish(&confirmation).is_ok(),
+        }
+        .write_to(&mut self.tcp_stream)
+        .expect("Failed to write to TCP stream.");
+    }
+}
+
+/// Starts a call of three in `room`, returning its participants in the order they joined it.
+fn group_call(server: SocketAddr, room: &'static [u8]) -> [Media; 3] {
+    let first = std::thread::spawn(move || join(server, room));
+    // Let it be the one waiting in the room, the others prove themselves to it
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let second = join(server, room);
+    let mut first = first.join().expect("First participant failed");
+
+    let third = std::thread::spawn(move || join(server, room));
+    first.verify_joiner();
+    let third = third.join().expect("Third participant failed");
+
+    [first, second, third]
+}
+
+#[test]
+fn group_call_relays_everyone_to_everyone() {
+    let server = localhost(GROUP_PORT);
+    let participants = group_call(server, b"group room");
+
+    for (i, sender) in participants.iter().enumerate() {
+        assert!(sender.relay, "Group calls go through the server");
+        sender.send(&[i as u8]);
+
+        for receiver in participants
+            .iter()
+            .filter(|media| media.session_id != sender.session_id)
+        {
+            assert_eq!(
+                receiver.recv_from_participant(),
+                (Some(sender.session_id), vec![i as u8])
+            );
+        }
+    }
+}
+
+#[test]
+fn group_call_goes_on_when_a_participant_leaves() {
+    let server = localhost(GROUP_PORT);
+    let [mut first, mut second, mut third] = group_call(server, b"shrinking group room");
+
+    ClientMessage::Hangup
+        .write_to(&mut second.tcp_stream)
+        .expect("Failed to write to TCP stream.");
+
+    // Only the one who left is gone
+    for media in [&mut first, &mut third] {
+        assert_eq!(
+            ServerMessage::read_from(&mut media.tcp_stream)
+                .expect("Failed to read from TCP stream."),
+            ServerMessage::ParticipantLeft(second.session_id)
+        );
+    }
+
+    first.send(b"still here");
+    assert_eq!(
+        third.recv_from_participant(),
+        (Some(first.session_id), b"still here".to_vec())
+    );
+}
+
+#[test]
+fn group_call_past_the_limit_is_full() {
+    let server = localhost(GROUP_PORT);
+    let participants = group_call(server, b"full group room");
+
+    let mut tcp_stream = wait_in_room(server, b"full group room");
+    assert_eq!(
+        ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::Error(ErrorCode::RoomFull)
+    );
+
+    // The call is not disturbed
+    participants[2].send(b"still here");
+    assert_eq!(participants[0].recv(), b"still here");
+    assert_eq!(participants[1].recv(), b"still here");
+}
+
+#[test]
+fn mixed_call_sends_a_single_stream() {
+    let server = localhost(MIX_PORT);
+    let participants = group_call(server, b"mixed room");
+
+    // Undecodable audio is dropped like lost packets, the mix goes on, and so is audio
+    // without a media header, or reports
+    let mut media_sender = MediaSender::new();
+    let header = media_sender.next(2880).to_bytes();
+    participants[0].send(&[&header[..], b"not opus"].concat());
+    participants[1].send(b"no header");
+    let report = Report::Receiver {
+        ssrc: media_sender.ssrc(),
+        blocks: vec![],
+    };
+    participants[2].send(&report.to_bytes());
+
+    for media in &participants {
+        assert!(media.relay, "Mixed calls go through the server");
+        let mut previous: Option<MediaHeader> = None;
+        for _ in 0..3 {
+            let (sender, packet) = media.recv_from_participant();
+            assert_eq!(sender, Some(SessionId::MIXER));
+            let (header, payload) = MediaHeader::split(&packet).expect("A media header first");
+            assert!(!payload.is_empty(), "The mix is an Opus packet");
+
+            // One frame after the other, in a single stream
+            if let Some(previous) = previous {
+                assert_eq!(header.ssrc, previous.ssrc);
+                assert_eq!(header.sequence, previous.sequence.wrapping_add(1));
+                assert_eq!(header.timestamp, previous.timestamp.wrapping_add(2880));
+            }
+            previous = Some(header);
+        }
+    }
+}
+
+#[test]
+fn only_servers_not_mixing_offer_encrypted_media() {
+    for (port, offered) in [(DEFAULT_PORT, true), (MIX_PORT, false)] {
+        let mut tcp_stream = connect_to(localhost(port));
+
+        let ServerMessage::Hello { capabilities, .. } = send_hello_with(
+            &mut tcp_stream,
+            PROTOCOL_VERSION,
+            Capabilities::ENCRYPTED_MEDIA,
+        ) else {
+            panic!("Expected the server's hello");
+        };
+        assert_eq!(
+            capabilities.contains(Capabilities::ENCRYPTED_MEDIA),
+            offered
+        );
+    }
+}
+
+#[test]
+fn encrypting_clients_exchange_keys_through_the_server() {
+    let server = localhost(RELAY_PORT);
+    let join_encrypted = move || {
+        register(
+            server,
+            find_partner_with(server, b"encrypted room", Capabilities::ENCRYPTED_MEDIA),
+        )
+    };
+    let partner = std::thread::spawn(join_encrypted);
+    let mut media = join_encrypted();
+    let mut partner = partner.join().expect("Partner failed");
+    let (us, them) = (media.session_id, partner.session_id);
+
+    // Once admitted, each is told to exchange keys with the other
+    media.expect_signal(ServerMessage::ExchangeKeys(them));
+    partner.expect_signal(ServerMessage::ExchangeKeys(us));
+
+    // What they send each other goes through, saying who it comes from
+    let commitment = Commitment([1; COMMITMENT_LEN]);
+    media.signal(ClientMessage::KeyCommitment {
+        peer: them,
+        commitment,
+    });
+    partner.expect_signal(ServerMessage::PeerKeyCommitment {
+        peer: us,
+        commitment,
+    });
+
+    let public_key = PublicKey([2; PUBLIC_KEY_LEN]);
+    partner.signal(ClientMessage::PublicKey {
+        peer: us,
+        public_key,
+    });
+    media.expect_signal(ServerMessage::PeerPublicKey {
+        peer: them,
+        public_key,
+    });
+
+    // Nothing is sent back to whoever names itself
+    let sealed = SealedKey([3; SEALED_KEY_LEN]);
+    media.signal(ClientMessage::SenderKey { peer: us, sealed });
+    media.signal(ClientMessage::SenderKey { peer: them, sealed });
+    partner.expect_signal(ServerMessage::PeerSenderKey { peer: us, sealed });
+    // Answered whether we moderate or not, so nothing else came before
+    media.signal(ClientMessage::Moderate(Pake::moderator(b"").share()));
+    media.expect_signal(ServerMessage::NotAllowed);
+}
+
+impl Media {
+    fn signal(&mut self, message: ClientMessage) {
+        message
+            .write_to(&mut self.tcp_stream)
+            .expect("Failed to write to TCP stream.");
+    }
+
+    fn expect_signal(&mut self, expected: ServerMessage) {
+        assert_eq!(self.next_signal(), expected);
+    }
+
+    fn next_signal(&mut self) -> ServerMessage {
+        ServerMessage::read_from(&mut self.tcp_stream).expect("Failed to read from TCP stream.")
+    }
+
+    /// Claims moderation, proving we hold `secret`, and returns the server's answer.
+    fn moderate(&mut self, secret: &[u8]) -> ServerMessage {
+        let pake = Pake::moderator(secret);
+        self.signal(ClientMessage::Moderate(pake.share()));
+        let ServerMessage::ModeratorKeyShare(key_share) = self.next_signal() else {
+            panic!("Expected the server's share of the exchange");
+        };
+        let exchange = pake.exchange(&key_share).expect("Invalid key share");
+        self.signal(ClientMessage::ModeratorConfirmation(
+            exchange.confirmation(),
+        ));
+        self.next_signal()
+    }
+}
+
+/// Starts a call of three on the moderated server. The first one waits in `room` for the
+/// others, so it moderates the call.
+fn moderated_call(room: &'static [u8]) -> [Media; 3] {
+    group_call(localhost(MODERATED_PORT), room)
+}
+
+#[test]
+fn first_joiner_moderates_the_call() {
+    let [mut first, mut second, third] = moderated_call(b"moderated room");
+
+    second.signal(ClientMessage::ListParticipants);
+    second.expect_signal(ServerMessage::NotAllowed);
+
+    first.signal(ClientMessage::ListParticipants);
+    first.expect_signal(ServerMessage::Participants(
+        [(&first, true), (&second, false), (&third, false)]
+            .map(|(media, moderator)| ParticipantInfo {
+                session_id: media.session_id,
+                moderator,
+                muted: false,
+            })
+            .to_vec(),
+    ));
+
+    first.signal(ClientMessage::SetMuted {
+        session_id: second.session_id,
+        muted: true,
+    });
+    second.expect_signal(ServerMessage::Muted(true));
+
+    // Only what the others say goes through
+    second.send(b"muted");
+    first.send(b"audible");
+    assert_eq!(
+        third.recv_from_participant(),
+        (Some(first.session_id), b"audible".to_vec())
+    );
+}
+
+#[test]
+fn moderator_kicks_and_locks_the_room() {
+    let [mut first, mut second, mut third] = moderated_call(b"locked room");
+
+    first.signal(ClientMessage::Kick(second.session_id));
+    second.expect_signal(ServerMessage::Error(ErrorCode::Kicked));
+    first.expect_signal(ServerMessage::ParticipantLeft(second.session_id));
+    third.expect_signal(ServerMessage::ParticipantLeft(second.session_id));
+
+    first.signal(ClientMessage::SetLocked(true));
+    // Give the server time to lock it
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut tcp_stream = wait_in_room(localhost(MODERATED_PORT), b"locked room");
+    assert_eq!(
+        ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::Error(ErrorCode::RoomLocked)
+    );
+
+    third.send(b"still here");
+    assert_eq!(first.recv(), b"still here");
+}
+
+#[test]
+fn moderator_secret_makes_a_moderator() {
+    let [_first, mut second, mut third] = moderated_call(b"secret room");
+
+    assert_eq!(second.moderate(b"a guess"), ServerMessage::NotAllowed);
+
+    let pake = Pake::moderator(MODERATOR_SECRET.as_bytes());
+    second.signal(ClientMessage::Moderate(pake.share()));
+    let ServerMessage::ModeratorKeyShare(key_share) = second.next_signal() else {
+        panic!("Expected the server's share of the exchange");
+    };
+    let confirmation = pake.exchange(&key_share).unwrap().confirmation();
+    second.signal(ClientMessage::ModeratorConfirmation(confirmation));
+    second.expect_signal(ServerMessage::Moderator);
+
+    // Replaying what went by proves nothing, the server's share is new every time
+    third.signal(ClientMessage::Moderate(pake.share()));
+    assert!(matches!(
+        third.next_signal(),
+        ServerMessage::ModeratorKeyShare(other) if other != key_share
+    ));
+    third.signal(ClientMessage::ModeratorConfirmation(confirmation));
+    third.expect_signal(ServerMessage::NotAllowed);
+
+    // Neither is refused
+    second.signal(ClientMessage::SetLocked(true));
+    second.signal(ClientMessage::ListParticipants);
+    assert!(matches!(
+        ServerMessage::read_from(&mut second.tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::Participants(participants) if participants.len() == 3
+    ));
+}
+
+#[test]
+fn joiner_without_the_room_secret_is_refused() {
+    let server = localhost(GROUP_PORT);
+    let first = std::thread::spawn(move || join(server, b"secret group room"));
+    // Let it be the one waiting in the room, the intruder proves itself to it
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let mut second = join(server, b"secret group room");
+    let mut first = first.join().expect("First participant failed");
+
+    let intruder = std::thread::spawn(move || {
+        let mut found = find_partner(server, b"secret group room");
+        let pake = Pake::new(&found.room_hash, b"a guess");
+        prove_secret(&mut found.tcp_stream, &pake)
+    });
+    first.verify_joiner();
+    assert_eq!(
+        intruder.join().expect("Intruder failed"),
+        Err(ErrorCode::Unauthorized)
+    );
+
+    // The call goes on without it
+    assert!(matches!(
+        ServerMessage::read_from(&mut second.tcp_stream).expect("Failed to read from TCP stream."),
+        ServerMessage::ParticipantLeft(_)
+    ));
+    first.send(b"still here");
+    assert_eq!(second.recv(), b"still here");
+}
+
+#[test]
+fn intruder_cannot_turn_away_the_one_waiting() {
+    let server = localhost(GROUP_PORT);
+    let first = std::thread::spawn(move || join(server, b"contested room"));
+    // Let it be the one waiting in the room, the intruder is paired with it
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    // It claims the one waiting failed, before anything could be checked
+    let mut intruder = find_partner(server, b"contested room");
+    let pake = Pake::new(&intruder.room_hash, b"a guess");
+    ClientMessage::KeyShare(pake.share())
+        .write_to(&mut intruder.tcp_stream)
+        .expect("Failed to write to TCP stream.");
+    let ServerMessage::PeerKeyShare { peer, .. } =
+        ServerMessage::read_from(&mut intruder.tcp_stream)
+            .expect("Failed to read from TCP stream.")
+    else {
+        panic!("Expected the key share of the one waiting");
+    };
+    ClientMessage::PeerVerified {
+        peer,
+        verified: false,
+    }
+    .write_to(&mut intruder.tcp_stream)
+    .expect("Failed to write to TCP stream.");
+
+    // The one waiting is still there for someone who knows the secret
+    let third = join(server, b"contested room");
+    let first = first.join().expect("First participant failed");
+    assert_eq!(first.session_id, peer);
+
+    first.send(b"still here");
+    assert_eq!(
+        third.recv_from_participant(),
+        (Some(first.session_id), b"still here".to_vec())
+    );
+}
+
+#[test]
+fn intruder_waiting_first_does_not_moderate() {
+    let server = localhost(GROUP_PORT);
+    let intruder = std::thread::spawn(move || find_partner(server, b"usurped room"));
+    // Let it be the one waiting in the room, as the first one there would moderate
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let joiner = find_partner(server, b"usurped room");
+    let mut intruder = intruder.join().expect("Intruder failed").tcp_stream;
+    for command in [
+        ClientMessage::Kick(joiner.session_id),
+        ClientMessage::SetLocked(true),
+        ClientMessage::ListParticipants,
+    ] {
+        command
+            .write_to(&mut intruder)
+            .expect("Failed to write to TCP stream.");
+        assert_eq!(
+            ServerMessage::read_from(&mut intruder).expect("Failed to read from TCP stream."),
+            ServerMessage::NotAllowed
+        );
+    }
+
+    // Neither kicked nor locked out, and the first one admitted moderates instead
+    let joiner = std::thread::spawn(move || register(server, joiner));
+    let third = join(server, b"usurped room");
+    let mut joiner = joiner.join().expect("Joiner failed");
+
+    joiner.signal(ClientMessage::ListParticipants);
+    let ServerMessage::Participants(participants) = joiner.next_signal() else {
+        panic!("Expected the list of participants");
+    };
+    let moderators: Vec<_> = participants
+        .iter()
+        .filter(|p| p.moderator)
+        .map(|p| p.session_id)
+        .collect();
+    assert_eq!(moderators, [joiner.session_id]);
+    assert!(
+        participants
+            .iter()
+            .any(|p| p.session_id == third.session_id)
+    );
+}
+
+#[test]
+fn servers_with_a_certificate_switch_clients_to_tls() {
+    // Those without one do not offer it
+    let mut tcp_stream = connect();
+    let ServerMessage::Hello { capabilities, .. } =
+        send_hello_with(&mut tcp_stream, PROTOCOL_VERSION, Capabilities::TLS)
+    else {
+        panic!("Expected the server's hello");
+    };
+    assert!(!capabilities.contains(Capabilities::TLS));
+
+    for (port, room) in [(TLS_PORT, b"tls room"), (TLS_ONLY_PORT, b"tls only")] {
+        let mut tls_stream = connect_tls(localhost(port));
+
+        ClientMessage::JoinRoom {
+            room_hash: Sha512::digest(room).into(),
+            relay: false,
+        }
+        .write_to(&mut tls_stream)
+        .expect("Failed to write to TLS stream.");
+        assert_eq!(
+            ServerMessage::read_from(&mut tls_stream).expect("Failed to read from TLS stream."),
+            ServerMessage::WaitingInRoom
+        );
+    }
+}
+
+#[test]
+fn only_tls_only_servers_turn_plaintext_clients_away() {
+    let mut tcp_stream = connect_to(localhost(TLS_PORT));
+    let ServerMessage::Hello { capabilities, .. } = send_hello(&mut tcp_stream, PROTOCOL_VERSION)
+    else {
+        panic!("Expected the server's hello");
+    };
+    assert!(!capabilities.contains(Capabilities::TLS));
+
+    let mut tcp_stream = connect_to(localhost(TLS_ONLY_PORT));
+    assert_eq!(
+        send_hello(&mut tcp_stream, PROTOCOL_VERSION),
+        ServerMessage::Error(ErrorCode::TlsRequired)
+    );
+}
@@ -0,0 +1,39 @@
+use std::{path::Path, sync::Arc};
+
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+use crate::config::Config;
+
+/// Builds what takes clients through the TLS handshake, if the config has a certificate.
+pub fn acceptor(config: &Config) -> Result<Option<TlsAcceptor>, String> {
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert, &config.tls_key) else {
+        return Ok(None);
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+
+    // The only provider built in, whatever other crates enable
+    let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+fn pem_error(path: &Path, e: impl std::fmt::Display) -> String {
+    format!("Failed to read {}: {}", path.display(), e)
+}
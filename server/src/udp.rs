@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use simple_call_protocol::relay::{self, SessionId, TOKEN_LEN, Token};
+use tokio::{net::UdpSocket, sync::mpsc, time};
+
+use crate::{
+    config::Config,
+    stats::RELAY_STATS,
+    utils::{lock, new_udp_socket, new_udp_socket_on},
+};
+
+/// How many datagrams may wait for a call on the shared port before new ones are dropped.
+const SESSION_QUEUE_LEN: usize = 64;
+
+/// The largest payload a UDP datagram can carry, leaving IPv6 jumbograms aside.
+const MAX_DATAGRAM_LEN: usize = u16::MAX as usize;
+
+/// How long the shared port waits before receiving again after an error.
+const ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A datagram a client sent to one of the call's sessions.
+pub struct Datagram {
+    pub session_id: SessionId,
+    pub payload: Vec<u8>,
+    pub from: SocketAddr,
+}
+
+/// Where a call sends and receives its UDP traffic.
+pub enum CallSocket {
+    /// A socket only this call uses.
+    Dedicated {
+        socket: UdpSocket,
+        buffer: Box<[u8]>,
+    },
+    /// The shared relay socket, along with the datagrams addressed to this call's sessions.
+    Shared {
+        socket: Arc<UdpSocket>,
+        incoming: mpsc::Receiver<Datagram>,
+        registration: Registration,
+    },
+}
+
+type SessionsMap = HashMap<SessionId, mpsc::Sender<Datagram>>;
+type SharedSessionsMap = Arc<Mutex<SessionsMap>>;
+
+/// The single UDP port all calls share when `relay_port` is configured.
+///
+/// A dispatcher task reads every datagram and hands it to the call owning its session.
+#[derive(Clone)]
+pub struct UdpMux {
+    socket: Arc<UdpSocket>,
+    sessions: SharedSessionsMap,
+}
+
+/// Keeps sessions routed to a call, until dropped.
+pub struct Registration {
+    session_ids: Vec<SessionId>,
+    sessions: SharedSessionsMap,
+    /// Where the call's datagrams go, for sessions added later on.
+    call: mpsc::Sender<Datagram>,
+}
+
+/// Hands out session ids, unique for the lifetime of the server.
+pub fn new_session_id() -> SessionId {
+    static NEXT: AtomicU32 = AtomicU32::new(1);
+    loop {
+        let id = SessionId(NEXT.fetch_add(1, Ordering::Relaxed));
+        // Once the counter wraps around
+        if id != SessionId::MIXER {
+            return id;
+        }
+    }
+}
+
+/// Draws a token no one can guess from anything they saw.
+pub fn new_token() -> Token {
+    let mut token = [0; TOKEN_LEN];
+    getrandom::fill(&mut token).expect("The system should provide random numbers");
+    Token(token)
+}
+
+impl CallSocket {
+    /// Opens the socket for a call made of `session_ids`, shared if there is a mux.
+    pub fn open(
+        config: &Config,
+        mux: Option<&UdpMux>,
+        session_ids: &[SessionId],
+    ) -> io::Result<Self> {
+        match mux {
+            Some(mux) => Ok(mux.open(session_ids)),
+            None => Ok(CallSocket::Dedicated {
+                socket: new_udp_socket(config)?,
+                buffer: new_buffer(),
+            }),
+        }
+    }
+
+    fn socket(&self) -> &UdpSocket {
+        match self {
+            CallSocket::Dedicated { socket, .. } => socket,
+            CallSocket::Shared { socket, .. } => socket,
+        }
+    }
+
+    /// Routes datagrams of a participant joining the call to it.
+    pub fn add_session(&mut self, session_id: SessionId) {
+        if let CallSocket::Shared { registration, .. } = self {
+            registration.add(session_id);
+        }
+    }
+
+    /// Stops routing datagrams of a participant that left the call.
+    pub fn remove_session(&mut self, session_id: SessionId) {
+        if let CallSocket::Shared { registration, .. } = self {
+            registration.remove(session_id);
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.socket().local_addr().unwrap().port()
+    }
+
+    pub async fn send_to(&self, payload: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket().send_to(payload, addr).await
+    }
+
+    /// Waits for the next datagram. Returns `None` once nothing can arrive anymore, or the
+    /// call's own socket failed for good.
+    pub async fn recv(&mut self) -> Option<Datagram> {
+        match self {
+            CallSocket::Dedicated { socket, buffer } => loop {
+                match recv_datagram(socket, buffer).await {
+                    Ok(Some(datagram)) => return Some(datagram),
+                    Ok(None) => continue,
+                    Err(e) if is_transient(&e) => continue,
+                    Err(e) => {
+                        eprintln!("Failed to receive on the call's UDP socket: {}", e);
+                        return None;
+                    }
+                }
+            },
+            CallSocket::Shared { incoming, .. } => incoming.recv().await,
+        }
+    }
+}
+
+/// Whether receiving failed only because of this attempt: an ICMP error about an earlier
+/// datagram, or a signal.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// A buffer any datagram fits in, with a byte to spare to tell when one did not.
+fn new_buffer() -> Box<[u8]> {
+    vec![0; MAX_DATAGRAM_LEN + 1].into_boxed_slice()
+}
+
+/// Reads one datagram, returning `None` if it has to be dropped.
+///
+/// Datagrams that did not fit `buffer` or are too short to tell who sent them are counted
+/// in [`RELAY_STATS`], never forwarded cut or mangled.
+async fn recv_datagram(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<Option<Datagram>> {
+    let (size, from) = socket.recv_from(buffer).await?;
+
+    // The datagram filled the whole buffer, so the system may have cut the rest.
+    if size == buffer.len() {
+        RELAY_STATS.oversized.fetch_add(1, Ordering::Relaxed);
+        return Ok(None);
+    }
+
+    let Some((session_id, payload)) = relay::split(&buffer[..size]) else {
+        RELAY_STATS.malformed.fetch_add(1, Ordering::Relaxed);
+        return Ok(None);
+    };
+
+    Ok(Some(Datagram {
+        session_id,
+        payload: payload.to_vec(),
+        from,
+    }))
+}
+
+impl UdpMux {
+    /// Binds the shared relay port and starts dispatching what arrives on it.
+    pub fn bind(config: &Config, port: u16) -> io::Result<Self> {
+        let mux = Self {
+            socket: Arc::new(new_udp_socket_on(config, port)?),
+            sessions: SharedSessionsMap::default(),
+        };
+
+        tokio::spawn(mux.clone().dispatch());
+
+        Ok(mux)
+    }
+
+    fn open(&self, session_ids: &[SessionId]) -> CallSocket {
+        let (sender, incoming) = mpsc::channel(SESSION_QUEUE_LEN);
+
+        let mut sessions = lock(&self.sessions);
+        for session_id in session_ids {
+            sessions.insert(*session_id, sender.clone());
+        }
+
+        CallSocket::Shared {
+            socket: self.socket.clone(),
+            incoming,
+            registration: Registration {
+                session_ids: session_ids.to_vec(),
+                sessions: self.sessions.clone(),
+                call: sender,
+            },
+        }
+    }
+
+    async fn dispatch(self) {
+        let mut buffer = new_buffer();
+
+        loop {
+            let datagram = match recv_datagram(&self.socket, &mut buffer).await {
+                Ok(Some(datagram)) => datagram,
+                Ok(None) => continue,
+                Err(e) if is_transient(&e) => continue,
+                // Every call on the port depends on it, so wait for the error to pass
+                Err(e) => {
+                    eprintln!("Failed to receive on the shared relay port: {}", e);
+                    time::sleep(ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let sessions = lock(&self.sessions);
+            match sessions.get(&datagram.session_id) {
+                // A call that just ended or fell behind loses the datagram, as the network could.
+                Some(call) => {
+                    let _ = call.try_send(datagram);
+                }
+                None => {
+                    RELAY_STATS.stray.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+impl Registration {
+    fn add(&mut self, session_id: SessionId) {
+        lock(&self.sessions).insert(session_id, self.call.clone());
+        self.session_ids.push(session_id);
+    }
+
+    fn remove(&mut self, session_id: SessionId) {
+        lock(&self.sessions).remove(&session_id);
+        self.session_ids.retain(|id| *id != session_id);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut sessions = lock(&self.sessions);
+        for session_id in &self.session_ids {
+            sessions.remove(session_id);
+        }
+    }
+}
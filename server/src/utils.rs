@@ -1,5 +1,93 @@
-use std::net::UdpSocket;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU32, Ordering},
+    },
+};
 
-pub fn new_udp_socket() -> UdpSocket {
-    UdpSocket::bind("0.0.0.0:0").expect("Failed to bind UDP socket. All UDP ports are in use?")
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::Config;
+
+fn bind(ty: Type, addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        // Only the unspecified address can take IPv4 traffic too, and only if asked to.
+        socket.set_only_v6(addr.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED))?;
+    }
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds a socket on `ip`. When it is the unspecified IPv6 address the socket accepts both
+/// IPv6 and IPv4 traffic, and hosts without IPv6 get a plain IPv4 socket instead.
+fn bind_dual_stack(ty: Type, ip: IpAddr, port: u16) -> io::Result<Socket> {
+    match bind(ty, SocketAddr::new(ip, port)) {
+        Err(e)
+            if ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && e.kind() != io::ErrorKind::AddrInUse =>
+        {
+            eprintln!(
+                "Failed to bind an IPv6 socket ({}), falling back to IPv4 only.",
+                e
+            );
+            bind(ty, SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+        }
+        result => result,
+    }
+}
+
+pub fn new_tcp_listener(config: &Config) -> io::Result<TcpListener> {
+    let socket = bind_dual_stack(Type::STREAM, config.bind_address, config.tcp_port)?;
+    socket.listen(128)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds a UDP socket on exactly `port`.
+pub fn new_udp_socket_on(config: &Config, port: u16) -> io::Result<UdpSocket> {
+    let socket = bind_dual_stack(Type::DGRAM, config.bind_address, port)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Binds a UDP socket on a port from the configured range, or any free port if there is none.
+pub fn new_udp_socket(config: &Config) -> io::Result<UdpSocket> {
+    let Some(range) = config.udp_port_range else {
+        return new_udp_socket_on(config, 0);
+    };
+
+    // Start each search where the previous one left off, so that ports freed by a call
+    // are not immediately reused by the next one.
+    static NEXT_OFFSET: AtomicU32 = AtomicU32::new(0);
+
+    let len = u32::from(range.end - range.start) + 1;
+    let first = NEXT_OFFSET.fetch_add(1, Ordering::Relaxed);
+
+    for i in 0..len {
+        let port = range.start + ((first + i) % len) as u16;
+
+        match new_udp_socket_on(config, port) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("All UDP ports in {} are in use", range),
+    ))
+}
+
+/// Locks `mutex`, even if a task panicked while holding it.
+///
+/// The maps shared between tasks are only changed by single inserts and removals, so they are
+/// never left half updated, and one failing connection must not lock everyone else out.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
@@ -0,0 +1,373 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use simple_call_protocol::media::MediaHeader;
+
+use super::FRAME_SIZE;
+
+/// Samples per second, what media timestamps count.
+const SAMPLE_RATE: f64 = 48000.0;
+/// Length of a frame, as extended timestamps count it.
+const FRAME_SAMPLES: i64 = FRAME_SIZE as i64;
+
+/// Bounds of the delay the buffer keeps, in frames from the one due to the newest received:
+/// 60 ms to 480 ms.
+const MIN_DELAY_FRAMES: i64 = 1;
+const MAX_DELAY_FRAMES: i64 = 8;
+
+/// How many frames past its target the delay may grow before the oldest audio is dropped.
+const DELAY_SLACK_FRAMES: i64 = 2;
+
+/// How many times the measured jitter the delay covers, besides the frame playing.
+const JITTER_FACTOR: f64 = 2.0;
+
+/// Share of packets missing past which the delay covers the packet after the one due, so a
+/// lost one can be recovered from the copy the next carries with in-band FEC.
+const FEC_LOSS_THRESHOLD: f64 = 0.02;
+const FEC_DELAY_FRAMES: i64 = 2;
+
+/// Holds a participant's packets until their turn comes, putting them back in order and waiting
+/// for those held up on the way.
+///
+/// How long it waits follows the jitter measured as RFC 3550 does: it plays a packet when the
+/// newest received is about enough ahead to cover it, catching up by dropping the oldest audio
+/// when more piles up, and waiting longer when packets arrive after their turn.
+pub(crate) struct JitterBuffer<T> {
+    /// The stream buffered, a new one starts over.
+    ssrc: Option<u32>,
+    /// Packets by timestamp, extended past the 32 bits of media headers, with their sequence
+    /// numbers.
+    packets: BTreeMap<i64, (u16, T)>,
+    /// Some timestamp and its extended value, to extend those close to it.
+    reference: (u32, i64),
+    /// Timestamp of the frame to play next, once a packet came.
+    next: Option<i64>,
+    /// Timestamp and sequence number of the last packet played.
+    played: Option<(i64, u16)>,
+
+    /// Interarrival jitter in samples, smoothed as in RFC 3550.
+    jitter: f64,
+    /// Share of the packets due that were missing, smoothed the same way.
+    loss: f64,
+    /// Previous packet's arrival time minus its timestamp, in samples.
+    transit: Option<u32>,
+    /// What arrival times are measured from.
+    start: Instant,
+}
+
+/// What to play for the next frame.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Playout<T> {
+    /// The packet due.
+    Packet(T),
+    /// The packet due did not arrive, lost or still on its way.
+    Missing,
+    /// The packet due was lost, this one after it may carry a copy of its audio.
+    Recover(T),
+    /// Nothing was sent for this frame: the sender was silent, or did not start yet.
+    Silent,
+}
+
+impl<T: Clone> JitterBuffer<T> {
+    pub fn new() -> Self {
+        Self {
+            ssrc: None,
+            packets: BTreeMap::new(),
+            reference: (0, 0),
+            next: None,
+            played: None,
+            jitter: 0.0,
+            loss: 0.0,
+            transit: None,
+            start: Instant::now(),
+        }
+    }
+
+    /// Takes in a packet with `header`, which came at `arrival`.
+    ///
+    /// Returns `false` if its turn is over, the packet is dropped.
+    pub fn push(&mut self, header: &MediaHeader, payload: T, arrival: Instant) -> bool {
+        if self.ssrc != Some(header.ssrc) {
+            // The sender started over, its timestamps have nothing to do with the last ones
+            *self = Self {
+                ssrc: Some(header.ssrc),
+                reference: (header.timestamp, 0),
+                start: self.start,
+                ..Self::new()
+            };
+        }
+
+        self.measure_jitter(header.timestamp, arrival);
+
+        let (reference, extended) = self.reference;
+        let timestamp = extended + header.timestamp.wrapping_sub(reference) as i32 as i64;
+        self.reference = (header.timestamp, timestamp);
+
+        let target = self.target_delay();
+        let newest = self
+            .packets
+            .last_key_value()
+            .map_or(timestamp, |(&newest, _)| newest);
+        match self.next {
+            // The first packet plays once the delay is reached
+            None => self.next = Some(timestamp - (target - 1) * FRAME_SAMPLES),
+            // The start of a talkspurt is the time to adjust the delay, nothing plays meanwhile
+            Some(_) if header.marker && self.packets.is_empty() => {
+                let next = timestamp - (target - 1) * FRAME_SAMPLES;
+                if self.played.is_none_or(|(played, _)| next > played) {
+                    self.next = Some(next);
+                }
+            }
+            // Late, but the frames since the last packet played were only concealed: wait for
+            // it, and longer from now on
+            Some(next)
+                if timestamp < next
+                    && self.played.is_none_or(|(played, _)| timestamp > played)
+                    && (newest - timestamp) / FRAME_SAMPLES < MAX_DELAY_FRAMES =>
+            {
+                self.next = Some(timestamp);
+            }
+            Some(next) if timestamp < next => return false,
+            Some(_) => {}
+        }
+
+        self.packets.insert(timestamp, (header.sequence, payload));
+        true
+    }
+
+    /// What to play for the next frame.
+    pub fn pop(&mut self) -> Playout<T> {
+        let Some(mut next) = self.next else {
+            return Playout::Silent;
+        };
+
+        // Drop what piled up past the delay needed, latency would only grow
+        if let Some((&newest, _)) = self.packets.last_key_value() {
+            let target = self.target_delay();
+            if (newest - next) / FRAME_SAMPLES + 1 > target + DELAY_SLACK_FRAMES {
+                next = newest - (target - 1) * FRAME_SAMPLES;
+                self.packets = self.packets.split_off(&next);
+            }
+        }
+        self.next = Some(next + FRAME_SAMPLES);
+
+        if let Some((sequence, payload)) = self.packets.remove(&next) {
+            self.played = Some((next, sequence));
+            self.loss -= self.loss / 16.0;
+            return Playout::Packet(payload);
+        }
+
+        let playout = match (self.packets.first_key_value(), self.played) {
+            // The sender numbers only what it sends: if the next one follows the last played,
+            // the frames between were silent
+            (Some((_, &(sequence, _))), Some((_, played)))
+                if sequence == played.wrapping_add(1) =>
+            {
+                return Playout::Silent;
+            }
+            // Waiting for the first packet
+            (Some(_), None) => return Playout::Silent,
+            // Lost right before this one, which may carry a copy of it
+            (Some((&timestamp, (_, payload))), Some(_)) if timestamp == next + FRAME_SAMPLES => {
+                Playout::Recover(payload.clone())
+            }
+            _ => Playout::Missing,
+        };
+        self.loss += (1.0 - self.loss) / 16.0;
+        playout
+    }
+
+    /// Interarrival jitter, in samples.
+    pub fn jitter(&self) -> u32 {
+        self.jitter as u32
+    }
+
+    /// Updates the interarrival jitter with a packet stamped `timestamp` that came at
+    /// `arrival`.
+    fn measure_jitter(&mut self, timestamp: u32, arrival: Instant) {
+        let arrival = (arrival.duration_since(self.start).as_secs_f64() * SAMPLE_RATE) as u64;
+        let transit = (arrival as u32).wrapping_sub(timestamp);
+
+        if let Some(previous) = self.transit {
+            let difference = transit.wrapping_sub(previous) as i32;
+            self.jitter += (difference.unsigned_abs() as f64 - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+
+    /// The delay to keep, in frames.
+    fn target_delay(&self) -> i64 {
+        let mut delay = (JITTER_FACTOR * self.jitter / FRAME_SIZE as f64).ceil() as i64 + 1;
+        if self.loss > FEC_LOSS_THRESHOLD {
+            delay = delay.max(FEC_DELAY_FRAMES);
+        }
+        delay.clamp(MIN_DELAY_FRAMES, MAX_DELAY_FRAMES)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use simple_call_protocol::media::MediaSender;
+
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(60);
+
+    /// Sends packets numbered by their payload.
+    struct Sender {
+        media: MediaSender,
+        count: u8,
+    }
+
+    impl Sender {
+        fn new() -> Self {
+            Self {
+                media: MediaSender::new(),
+                count: 0,
+            }
+        }
+
+        fn next(&mut self) -> (MediaHeader, Vec<u8>) {
+            self.count += 1;
+            (self.media.next(FRAME_SIZE as u32), vec![self.count])
+        }
+
+        fn skip(&mut self) {
+            self.media.skip(FRAME_SIZE as u32);
+        }
+    }
+
+    #[test]
+    fn reordered_packets_are_played_in_order() {
+        let mut buffer = JitterBuffer::new();
+        let mut sender = Sender::new();
+        let now = Instant::now();
+
+        let (first, first_payload) = sender.next();
+        let (second, second_payload) = sender.next();
+        assert!(buffer.push(&second, second_payload, now));
+        assert!(buffer.push(&first, first_payload, now));
+
+        assert_eq!(buffer.pop(), Playout::Packet(vec![1]));
+        assert_eq!(buffer.pop(), Playout::Packet(vec![2]));
+        assert_eq!(buffer.pop(), Playout::Missing);
+    }
+
+    #[test]
+    fn silence_is_told_from_loss() {
+        let mut buffer = JitterBuffer::new();
+        let mut sender = Sender::new();
+        let now = Instant::now();
+
+        let (header, payload) = sender.next();
+        buffer.push(&header, payload, now);
+        assert_eq!(buffer.pop(), Playout::Packet(vec![1]));
+
+        // A silent frame, then one lost
+        sender.skip();
+        let (header, payload) = sender.next();
+        buffer.push(&header, payload, now + FRAME);
+        sender.next();
+        let (header, payload) = sender.next();
+        buffer.push(&header, payload, now + 3 * FRAME);
+
+        assert_eq!(buffer.pop(), Playout::Silent);
+        assert_eq!(buffer.pop(), Playout::Packet(vec![2]));
+        assert_eq!(buffer.pop(), Playout::Recover(vec![4]));
+        assert_eq!(buffer.pop(), Playout::Packet(vec![4]));
+    }
+
+    #[test]
+    fn only_the_last_loss_before_a_packet_is_recovered() {
+        let mut buffer = JitterBuffer::new();
+        let mut sender = Sender::new();
+        let now = Instant::now();
+
+        let (header, payload) = sender.next();
+        buffer.push(&header, payload, now);
+        assert_eq!(buffer.pop(), Playout::Packet(vec![1]));
+
+        // Two lost in a row, the packet after them only has a copy of the second
+        sender.next();
+        sender.next();
+        let (header, payload) = sender.next();
+        buffer.push(&header, payload, now + FRAME);
+
+        assert_eq!(buffer.pop(), Playout::Missing);
+        assert_eq!(buffer.pop(), Playout::Recover(vec![4]));
+        assert_eq!(buffer.pop(), Playout::Packet(vec![4]));
+        assert_eq!(
+            buffer.target_delay(),
+            FEC_DELAY_FRAMES,
+            "With losses, the packet after the one due is waited for"
+        );
+    }
+
+    #[test]
+    fn late_packets_are_waited_for() {
+        let mut buffer = JitterBuffer::new();
+        let mut sender = Sender::new();
+        let now = Instant::now();
+
+        let (header, payload) = sender.next();
+        buffer.push(&header, payload, now);
+        assert_eq!(buffer.pop(), Playout::Packet(vec![1]));
+
+        // Held up for two frames, concealed meanwhile, then played late rather than dropped
+        assert_eq!(buffer.pop(), Playout::Missing);
+        assert_eq!(buffer.pop(), Playout::Missing);
+        let (header, payload) = sender.next();
+        assert!(buffer.push(&header, payload, now + 3 * FRAME));
+        assert_eq!(buffer.pop(), Playout::Packet(vec![2]));
+        assert!(
+            buffer.target_delay() > MIN_DELAY_FRAMES,
+            "The delay grows with jitter"
+        );
+
+        // Once something after it played, its turn is over
+        let (late, late_payload) = sender.next();
+        let (header, payload) = sender.next();
+        buffer.push(&header, payload, now + 4 * FRAME);
+        assert_eq!(buffer.pop(), Playout::Recover(vec![4]));
+        assert_eq!(buffer.pop(), Playout::Packet(vec![4]));
+        assert!(!buffer.push(&late, late_payload, now + 5 * FRAME));
+    }
+
+    #[test]
+    fn stale_audio_is_dropped() {
+        let mut buffer = JitterBuffer::new();
+        let mut sender = Sender::new();
+        let now = Instant::now();
+
+        // A burst, at a steady pace otherwise
+        for _ in 0..MAX_DELAY_FRAMES * 2 {
+            let (header, payload) = sender.next();
+            buffer.push(&header, payload, now);
+        }
+
+        let Playout::Packet(payload) = buffer.pop() else {
+            panic!("Expected a packet");
+        };
+        let behind = MAX_DELAY_FRAMES * 2 - payload[0] as i64 + 1;
+        assert!(
+            behind <= MAX_DELAY_FRAMES,
+            "{} frames behind the newest",
+            behind
+        );
+    }
+
+    #[test]
+    fn a_new_stream_starts_over() {
+        let mut buffer = JitterBuffer::new();
+        let now = Instant::now();
+
+        let (header, payload) = Sender::new().next();
+        buffer.push(&header, payload, now);
+        assert_eq!(buffer.pop(), Playout::Packet(vec![1]));
+
+        let (header, payload) = Sender::new().next();
+        assert!(buffer.push(&header, payload, now + FRAME));
+        assert_eq!(buffer.pop(), Playout::Packet(vec![1]));
+    }
+}
@@ -1,12 +1,25 @@
+mod jitter;
 mod receive;
 mod send;
 
 use std::{
-    net::{SocketAddr, UdpSocket},
+    io::Write,
+    net::{Shutdown, SocketAddr, UdpSocket},
+    process,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, atomic::AtomicU8, mpsc},
     thread,
     time::Duration,
 };
 
+use simple_call_protocol::{
+    Message,
+    encryption::{MediaKey, Sealer},
+    media::MediaSender,
+    relay::SessionId,
+    report::{self, Report, ReportBlock},
+    signal::ClientMessage,
+};
+
 use cpal::{
     BufferSize, SampleRate, StreamConfig,
     traits::{DeviceTrait, HostTrait, StreamTrait},
@@ -15,15 +28,73 @@ use cpal::{
 use receive::create_speaker_callback;
 use send::create_microphone_callback;
 
+use crate::{
+    coordination::{Signaling, read_commands, watch_signaling},
+    keys::AudioKeys,
+};
+
 /// Length of a single packet's audio frame in samples.
 /// This is 60ms of audio at 48kHz sample rate.
 const FRAME_SIZE: usize = 960 * 3;
 
-pub fn handle_call(udp_sock: UdpSocket, peer_udp_addr: SocketAddr) {
+/// How often we report on what we send and how we hear the others, RTCP's usual interval.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How someone in the call hears us, from their latest report.
+struct Feedback {
+    /// Who reported, in a relayed call.
+    from: Option<SessionId>,
+    block: ReportBlock,
+    /// When the report came, as an NTP time.
+    arrival: u64,
+}
+
+/// Our sealer, shared by the audio and the reports we send, and the keys the others hand us
+/// for theirs.
+struct Encryption {
+    sealer: Arc<Mutex<Sealer>>,
+    sender_keys: mpsc::Receiver<(Option<SessionId>, MediaKey)>,
+}
+
+/// Why a call ended.
+pub enum CallEnd {
+    /// The user pressed Ctrl-C.
+    HungUp,
+    /// The server said the partner is gone, or everyone else in a group call.
+    PartnerLeft,
+    /// The signaling connection broke.
+    ServerLost,
+}
+
+/// Streams audio with `peer_udp_addr` until the call ends. With a `session_id`, the peer is
+/// the server's relay, and every datagram is prefixed with it, the ones it forwards us with the
+/// sender's. With `keys`, the audio is encrypted end to end. Reports on what we send and how
+/// we hear the others go along with it, every [`REPORT_INTERVAL`], sealed like the audio.
+///
+/// `signaling` is the connection to the server, told when we hang up.
+pub fn handle_call(
+    udp_sock: UdpSocket,
+    peer_udp_addr: SocketAddr,
+    session_id: Option<SessionId>,
+    keys: Option<AudioKeys>,
+    signaling: Option<Signaling>,
+) {
     udp_sock
         .set_nonblocking(true)
         .expect("Error setting non-blocking");
 
+    let encryption = keys.map(|AudioKeys { own, received }| Encryption {
+        sealer: Arc::new(Mutex::new(Sealer::new(&own))),
+        sender_keys: received,
+    });
+
+    // Measured on what we receive, the best guess of how much of what we send is lost until
+    // the others report how they hear us
+    let packet_loss = Arc::new(AtomicU8::new(0));
+    let (feedback_sender, feedback) = mpsc::channel();
+    let media_sender = MediaSender::new();
+    let ssrc = media_sender.ssrc();
+
     let host = cpal::default_host();
 
     // INPUT
@@ -42,7 +113,15 @@ pub fn handle_call(udp_sock: UdpSocket, peer_udp_addr: SocketAddr) {
         input_device
             .build_input_stream(
                 &input_config,
-                create_microphone_callback(udp_sock.try_clone().unwrap(), peer_udp_addr),
+                create_microphone_callback(
+                    udp_sock.try_clone().unwrap(),
+                    peer_udp_addr,
+                    session_id,
+                    encryption.as_ref().map(|e| e.sealer.clone()),
+                    media_sender,
+                    packet_loss.clone(),
+                    feedback,
+                ),
                 |e| {
                     panic!("Error in input stream: {}", e);
                 },
@@ -65,7 +144,15 @@ pub fn handle_call(udp_sock: UdpSocket, peer_udp_addr: SocketAddr) {
         output_device
             .build_output_stream(
                 &output_config,
-                create_speaker_callback(udp_sock.try_clone().unwrap()),
+                create_speaker_callback(
+                    udp_sock.try_clone().unwrap(),
+                    peer_udp_addr,
+                    session_id,
+                    encryption,
+                    ssrc,
+                    packet_loss,
+                    feedback_sender,
+                ),
                 |e| {
                     panic!("Error in output stream: {}", e);
                 },
@@ -74,12 +161,82 @@ pub fn handle_call(udp_sock: UdpSocket, peer_udp_addr: SocketAddr) {
             .unwrap()
     };
 
+    let (end_sender, end) = mpsc::channel();
+
+    let hang_up = end_sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = hang_up.send(CallEnd::HungUp);
+    })
+    .expect("Failed to set the Ctrl-C handler.");
+
+    let signaling = signaling.map(|signaling| {
+        let tcp_stream = signaling
+            .tcp_stream
+            .try_clone()
+            .expect("Failed to clone TCP stream.");
+        let commands = tcp_stream.try_clone().expect("Failed to clone TCP stream.");
+        let (moderation_sender, moderation) = mpsc::channel();
+        thread::spawn(move || watch_signaling(signaling, end_sender, moderation));
+        thread::spawn(move || read_commands(commands, moderation_sender));
+        tcp_stream
+    });
+
     input_stream.play().expect("Error playing input stream");
     thread::sleep(Duration::from_millis(40));
     output_stream.play().expect("Error playing output stream");
 
-    // Keep the streams alive
-    loop {
-        thread::sleep(Duration::from_secs(1));
+    // Keep the streams alive until the call ends
+    let reason = end
+        .recv()
+        .expect("The Ctrl-C handler keeps the channel open.");
+    drop(input_stream);
+    drop(output_stream);
+
+    match reason {
+        CallEnd::HungUp => {
+            if let Some(mut tcp_stream) = signaling {
+                // We are leaving anyway, the server notices the connection closing too
+                let _ = ClientMessage::Hangup.write_to(&mut tcp_stream);
+                let _ = tcp_stream.flush();
+                let _ = tcp_stream.shutdown(Shutdown::Write);
+            }
+            println!("Call ended.");
+        }
+        CallEnd::PartnerLeft => println!("Your partner left the call."),
+        CallEnd::ServerLost => {
+            eprintln!("Lost the connection to the server.");
+            process::exit(1);
+        }
+    }
+}
+
+/// Sends `report` to `peer_udp_addr`, after our session id in a relayed call. With a `sealer`,
+/// all but its header is sealed, along with the headers.
+fn send_report(
+    udp_sock: &UdpSocket,
+    peer_udp_addr: SocketAddr,
+    session_id: Option<SessionId>,
+    sealer: Option<&Mutex<Sealer>>,
+    report: &Report,
+) {
+    let mut datagram = session_id.map_or(vec![], |session_id| session_id.to_bytes().to_vec());
+    let bytes = report.to_bytes();
+    let (header, rest) = bytes.split_at(report::HEADER_LEN);
+    datagram.extend_from_slice(header);
+    match sealer {
+        Some(sealer) => {
+            let sealed = lock(sealer).seal(&datagram, rest);
+            datagram.extend_from_slice(&sealed);
+        }
+        None => datagram.extend_from_slice(rest),
+    }
+
+    if let Err(e) = udp_sock.send_to(&datagram, peer_udp_addr) {
+        eprintln!("Error sending report: {}", e);
     }
 }
+
+fn lock(sealer: &Mutex<Sealer>) -> MutexGuard<'_, Sealer> {
+    // A panic in either callback ends the call anyway
+    sealer.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -1,8 +1,27 @@
-use std::{net::UdpSocket, time::Instant};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+        mpsc::Sender,
+    },
+    time::{Duration, Instant},
+};
 
 use cpal::OutputCallbackInfo;
+use simple_call_protocol::{
+    encryption::Opener,
+    media::{self, Arrival, MediaHeader, MediaReceiver, MediaStats},
+    relay::{self, SessionId},
+    report::{self, Report, SenderReport},
+};
 
-use super::FRAME_SIZE;
+use super::{
+    Encryption, FRAME_SIZE, Feedback, REPORT_INTERVAL,
+    jitter::{JitterBuffer, Playout},
+    send_report,
+};
 
 fn bytes_human_readable(bytes: usize) -> String {
     if bytes < 1024 {
@@ -22,14 +41,215 @@ fn duration_human_readable(duration: std::time::Duration) -> String {
     )
 }
 
+/// How long we wait for a participant's packets before taking them for gone. Even while
+/// silent, they send comfort noise more often.
+const SILENT_PARTICIPANT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The audio of one of those we hear, with a decoder of its own. Dropped once they go quiet,
+/// unlike the [`Opener`] of their packets: it remembers those opened already for the whole
+/// call, so none can be replayed after.
+struct Participant {
+    /// Tells which of its packets were lost, late or duplicated.
+    media: MediaReceiver,
+    decoder: opus::Decoder,
+    jitter: JitterBuffer<Frame>,
+    /// While they are silent, the level of their background noise in -dBov.
+    noise_level: Option<u8>,
+    comfort_noise: ComfortNoise,
+    last_heard: Instant,
+}
+
+/// What a participant sent for a frame.
+#[derive(Clone)]
+enum Frame {
+    Opus(Vec<u8>),
+    /// They went silent, with background noise at this level in -dBov.
+    ComfortNoise(u8),
+}
+
+/// Makes up background noise while a participant is silent, so that the call does not sound
+/// dead.
+struct ComfortNoise {
+    /// Of a xorshift generator, good enough for noise.
+    state: u32,
+}
+
+impl ComfortNoise {
+    fn new() -> Self {
+        Self { state: 0x9e37_79b9 }
+    }
+
+    /// Fills `frame` with white noise at `level`, in -dBov.
+    fn fill(&mut self, frame: &mut [f32], level: u8) {
+        // Uniform noise has an RMS of its amplitude over √3
+        let amplitude = 10f32.powf(-(level as f32) / 20.0) * 3f32.sqrt();
+        for sample in frame {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            *sample = (self.state as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude;
+        }
+    }
+}
+
+impl Participant {
+    fn new() -> Self {
+        Self {
+            media: MediaReceiver::new(),
+            decoder: opus::Decoder::new(48000, opus::Channels::Mono).unwrap(),
+            jitter: JitterBuffer::new(),
+            noise_level: None,
+            comfort_noise: ComfortNoise::new(),
+            last_heard: Instant::now(),
+        }
+    }
+
+    /// Opens one of its reports with `opener`, if the call is encrypted end to end, sealed like
+    /// audio along with the `headers` naming who sent it and the report's own. `None` if it was
+    /// forged or replayed, or does not parse.
+    fn open_report(opener: Option<&mut Opener>, headers: &[u8], rest: &[u8]) -> Option<Report> {
+        let opened;
+        let rest = match opener {
+            Some(opener) => {
+                opened = opener.open(headers, rest).ok()?;
+                &opened[..]
+            }
+            None => rest,
+        };
+        Report::parse(&[&headers[headers.len() - report::HEADER_LEN..], rest].concat())
+    }
+
+    /// Takes in its next packet, opened with `opener` if the call is encrypted end to end,
+    /// sealed along with the `headers` naming who sent it and when, `media_header` being the
+    /// last of them.
+    fn receive(
+        &mut self,
+        opener: Option<&mut Opener>,
+        headers: &[u8],
+        media_header: &MediaHeader,
+        packet: &[u8],
+    ) {
+        // Forged or replayed packets are dropped like lost ones
+        let opened;
+        let packet = match opener {
+            Some(opener) => match opener.open(headers, packet) {
+                Ok(frame) => {
+                    opened = frame;
+                    &opened[..]
+                }
+                Err(_) => return,
+            },
+            None => packet,
+        };
+
+        if self.media.receive(media_header) == Arrival::Duplicate {
+            return;
+        }
+
+        let frame = match media_header.payload_type {
+            media::PAYLOAD_TYPE_OPUS => Frame::Opus(packet.to_vec()),
+            media::PAYLOAD_TYPE_COMFORT_NOISE => match packet.first() {
+                Some(level) => Frame::ComfortNoise(level & 0x7f),
+                None => return,
+            },
+            _ => return,
+        };
+
+        let now = Instant::now();
+        self.jitter.push(media_header, frame, now);
+        self.last_heard = now;
+    }
+
+    /// Adds this participant's next frame to `mix`, recovering or concealing it if it did not
+    /// arrive, or making up background noise while they are silent.
+    fn mix_into(&mut self, mix: &mut [f32]) -> usize {
+        let mut frame = vec![0f32; FRAME_SIZE];
+        let decoded = match (self.jitter.pop(), self.noise_level) {
+            (Playout::Packet(Frame::Opus(packet)), _) => {
+                self.noise_level = None;
+                self.decoder.decode_float(&packet, &mut frame, false)
+            }
+            // Until they speak again, whatever is missing was silent too
+            (Playout::Packet(Frame::ComfortNoise(level)), _) | (_, Some(level)) => {
+                self.noise_level = Some(level);
+                self.comfort_noise.fill(&mut frame, level);
+                Ok(FRAME_SIZE)
+            }
+            // Decodes the copy of the lost frame in the next packet, the next is decoded again
+            // in its turn
+            (Playout::Recover(Frame::Opus(next)), None) => {
+                self.decoder.decode_float(&next, &mut frame, true)
+            }
+            (Playout::Recover(Frame::ComfortNoise(_)) | Playout::Missing, None) => {
+                self.decoder.decode_float(&[], &mut frame, false)
+            }
+            (Playout::Silent, None) => Ok(FRAME_SIZE),
+        };
+        // Whoever sent a packet that does not decode, the others are still heard: it is
+        // concealed like a lost one
+        let decoded = decoded
+            .or_else(|_| self.decoder.decode_float(&[], &mut frame, false))
+            .unwrap_or_else(|_| {
+                frame.fill(0.0);
+                FRAME_SIZE
+            });
+        frame.truncate(decoded);
+
+        for (mixed, sample) in mix.iter_mut().zip(&frame) {
+            *mixed += sample;
+        }
+        frame.len()
+    }
+}
+
+/// Drops those we have not heard from in a while: they left, or will be back with new packets.
+/// Their counts go to `past_stats`.
+fn evict_silent(
+    participants: &mut HashMap<Option<SessionId>, Participant>,
+    past_stats: &mut MediaStats,
+) {
+    participants.retain(|_, participant| {
+        let heard = participant.last_heard.elapsed() < SILENT_PARTICIPANT_TIMEOUT;
+        if !heard {
+            past_stats.add(&participant.media.stats());
+        }
+        heard
+    });
+}
+
+/// Plays what the others in the call send us. In a relayed call, every datagram starts with
+/// the session id of who sent it, and each participant is decoded on its own and mixed in.
+/// The media header after it numbers their packets, which wait in a jitter buffer for their
+/// turn.
+/// With `encryption`, their audio is decrypted first, with the keys they handed us as they
+/// arrive, and ours are sealed with its sealer.
+///
+/// The share of their packets lost, in percent, goes to `packet_loss` every second. We report
+/// how we hear them to `peer_udp_addr` as the stream `ssrc`, and pass on to `feedback` how they
+/// report hearing us. Reports are sealed like audio, those we cannot open are dropped.
 pub(crate) fn create_speaker_callback(
     udp_sock: UdpSocket,
+    peer_udp_addr: SocketAddr,
+    session_id: Option<SessionId>,
+    encryption: Option<Encryption>,
+    ssrc: u32,
+    packet_loss: Arc<AtomicU8>,
+    feedback: Sender<Feedback>,
 ) -> impl FnMut(&mut [f32], &OutputCallbackInfo) {
     udp_sock
         .set_nonblocking(true)
         .expect("Error setting non-blocking");
 
-    let mut decoder = opus::Decoder::new(48000, opus::Channels::Mono).unwrap();
+    let (sealer, sender_keys) = encryption
+        .map(|encryption| (encryption.sealer, encryption.sender_keys))
+        .unzip();
+    // Direct calls have a single participant, known as `None`
+    let mut participants: HashMap<Option<SessionId>, Participant> = HashMap::new();
+    // Kept for the whole call, by who sends with them
+    let mut openers: HashMap<Option<SessionId>, Opener> = HashMap::new();
+    // The latest from each stream, with when it came
+    let mut sender_reports: HashMap<u32, (SenderReport, Instant)> = HashMap::new();
+    let relayed = session_id.is_some();
 
     let mut recv_buff = [0; 4096];
 
@@ -38,20 +258,65 @@ pub(crate) fn create_speaker_callback(
     let mut out_buff_filled_r = 0;
 
     let mut bytes_received: usize = 0;
+    // Of the participants who left
+    let mut past_stats = MediaStats::default();
+    let mut last_stats = MediaStats::default();
+    let mut loss = 0f32;
 
     let start_time = Instant::now();
     let mut last_metrics_time = start_time;
+    let mut last_report_time = start_time;
 
     move |mut data: &mut [f32], _: &OutputCallbackInfo| {
         let elapsed = last_metrics_time.elapsed();
         if elapsed.as_secs() >= 1 {
             last_metrics_time = Instant::now();
+            let mut stats = past_stats;
+            for participant in participants.values() {
+                stats.add(&participant.media.stats());
+            }
+
+            // Smoothed, one lost packet in a second should not swing it much
+            let lost = stats.lost.saturating_sub(last_stats.lost);
+            let expected = lost + stats.received.saturating_sub(last_stats.received);
+            if expected > 0 {
+                loss += (lost as f32 / expected as f32 - loss) / 4.0;
+                packet_loss.store((loss * 100.0).round() as u8, Ordering::Relaxed);
+            }
+            last_stats = stats;
             println!(
-                "Received: {} in {}.",
+                "Received: {} in {}, {} packets lost, {} out of order.",
                 bytes_human_readable(bytes_received),
                 duration_human_readable(start_time.elapsed()),
+                stats.lost,
+                stats.reordered,
             );
         }
+        if last_report_time.elapsed() >= REPORT_INTERVAL {
+            last_report_time = Instant::now();
+            let blocks: Vec<_> = participants
+                .values_mut()
+                .filter_map(|participant| {
+                    let sender_report = participant
+                        .media
+                        .ssrc()
+                        .and_then(|ssrc| sender_reports.get(&ssrc))
+                        .map(|(report, arrival)| (report, arrival.elapsed()));
+                    let jitter = participant.jitter.jitter();
+                    participant.media.report(jitter, sender_report)
+                })
+                .collect();
+            if !blocks.is_empty() {
+                let report = Report::Receiver { ssrc, blocks };
+                send_report(
+                    &udp_sock,
+                    peer_udp_addr,
+                    session_id,
+                    sealer.as_deref(),
+                    &report,
+                );
+            }
+        }
         while !data.is_empty() {
             // Copy all that's possible from out_buff to data
             let to_copy = data.len().min(out_buff_filled_r - out_buff_filled_l);
@@ -61,30 +326,158 @@ pub(crate) fn create_speaker_callback(
             data = &mut data[to_copy..];
 
             if out_buff_filled_l == out_buff_filled_r {
-                let received = udp_sock.recv_from(&mut recv_buff);
-                match received {
-                    Ok((size, _)) => {
-                        bytes_received += size + 24;
+                if let Some(sender_keys) = &sender_keys {
+                    // A sender's key coming again, however it did, must not start its
+                    // packets over
+                    for (sender, key) in sender_keys.try_iter() {
+                        openers.entry(sender).or_insert_with(|| Opener::new(&key));
+                    }
+                }
 
-                        let decoded = decoder
-                            .decode_float(&recv_buff[0..size], &mut out_buff, false)
-                            .unwrap();
+                // Take in everything that arrived since the last frame
+                loop {
+                    match udp_sock.recv_from(&mut recv_buff) {
+                        Ok((size, _)) => {
+                            bytes_received += size + 24;
 
-                        out_buff_filled_l = 0;
-                        out_buff_filled_r = decoded;
-                    }
-                    Err(e) => {
-                        if e.kind() != std::io::ErrorKind::WouldBlock {
-                            eprintln!("Error receiving data: {}", e);
-                        }
+                            let datagram = &recv_buff[..size];
+                            let (sender, relay_header_len) = if relayed {
+                                match relay::split(datagram) {
+                                    Some((sender, _)) => (Some(sender), relay::HEADER_LEN),
+                                    None => continue,
+                                }
+                            } else {
+                                (None, 0)
+                            };
+
+                            let packet = &datagram[relay_header_len..];
+                            let opener = match &sender_keys {
+                                Some(_) => match openers.get_mut(&sender) {
+                                    Some(opener) => Some(opener),
+                                    // Their key is still on its way
+                                    None => continue,
+                                },
+                                None => None,
+                            };
+                            let participant =
+                                participants.entry(sender).or_insert_with(Participant::new);
 
-                        let decoded = decoder.decode_float(&[], &mut out_buff, false).unwrap();
+                            if report::is_report(packet) {
+                                // What the sender sealed the rest of its report along with
+                                let Some(headers) =
+                                    datagram.get(..relay_header_len + report::HEADER_LEN)
+                                else {
+                                    continue;
+                                };
+                                let rest = &datagram[headers.len()..];
+                                match Participant::open_report(opener, headers, rest) {
+                                    Some(Report::Sender(report)) => {
+                                        sender_reports
+                                            .insert(report.ssrc, (report, Instant::now()));
+                                    }
+                                    Some(Report::Receiver { blocks, .. }) => {
+                                        let arrival = report::ntp_time();
+                                        // Only fails once the call is over
+                                        for block in blocks.into_iter().filter(|b| b.ssrc == ssrc) {
+                                            let _ = feedback.send(Feedback {
+                                                from: sender,
+                                                block,
+                                                arrival,
+                                            });
+                                        }
+                                    }
+                                    None => {}
+                                }
+                                continue;
+                            }
 
-                        out_buff_filled_l = 0;
-                        out_buff_filled_r = decoded;
+                            let Some((media_header, packet)) = MediaHeader::split(packet) else {
+                                continue;
+                            };
+                            // What the sender sealed its audio along with
+                            let headers = &datagram[..relay_header_len + media::HEADER_LEN];
+                            participant.receive(opener, headers, &media_header, packet);
+                        }
+                        Err(e) => {
+                            if e.kind() != std::io::ErrorKind::WouldBlock {
+                                eprintln!("Error receiving data: {}", e);
+                            }
+                            break;
+                        }
                     }
                 }
+
+                evict_silent(&mut participants, &mut past_stats);
+
+                out_buff.fill(0.0);
+                let mut mixed = 0;
+                for participant in participants.values_mut() {
+                    mixed = mixed.max(participant.mix_into(&mut out_buff));
+                }
+                for sample in &mut out_buff {
+                    *sample = sample.clamp(-1.0, 1.0);
+                }
+
+                out_buff_filled_l = 0;
+                // Play a frame of silence while nobody is there
+                out_buff_filled_r = if mixed == 0 { FRAME_SIZE } else { mixed };
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use simple_call_protocol::{
+        encryption::{MediaKey, Sealer},
+        media::MediaSender,
+    };
+
+    use super::*;
+
+    #[test]
+    fn packets_opened_before_an_eviction_are_refused_after() {
+        let key = MediaKey::random();
+        let mut sealer = Sealer::new(&key);
+        let mut opener = Opener::new(&key);
+        let media_header = MediaSender::new().next(FRAME_SIZE as u32);
+        let headers = media_header.to_bytes();
+        let sealed = sealer.seal(&headers, &[0xf8, 0xff, 0xfe]);
+
+        let mut participants = HashMap::new();
+        let mut past_stats = MediaStats::default();
+        let participant = participants.entry(None).or_insert_with(Participant::new);
+        participant.receive(Some(&mut opener), &headers, &media_header, &sealed);
+        assert_eq!(participant.media.stats().received, 1);
+
+        participant.last_heard -= SILENT_PARTICIPANT_TIMEOUT;
+        evict_silent(&mut participants, &mut past_stats);
+        assert!(participants.is_empty());
+        assert_eq!(past_stats.received, 1);
+
+        // Held back while they were gone, then sent again
+        let participant = participants.entry(None).or_insert_with(Participant::new);
+        participant.receive(Some(&mut opener), &headers, &media_header, &sealed);
+        assert_eq!(participant.media.stats().received, 0);
+    }
+
+    #[test]
+    fn packets_that_do_not_decode_are_concealed() {
+        let mut sender = MediaSender::new();
+        let mut participant = Participant::new();
+        // More frames than a packet may hold
+        let malformed = [0xff; 3];
+
+        for lost in [false, false, true, false, false, false] {
+            let media_header = sender.next(FRAME_SIZE as u32);
+            // The one after a loss is decoded for its copy of the lost frame first
+            if !lost {
+                participant.receive(None, &media_header.to_bytes(), &media_header, &malformed);
+            }
+        }
+        for _ in 0..8 {
+            let mut mix = [0f32; FRAME_SIZE];
+            assert_eq!(participant.mix_into(&mut mix), FRAME_SIZE);
+        }
+    }
+}
@@ -1,10 +1,25 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU8, Ordering},
+        mpsc::Receiver,
+    },
+    time::{Duration, Instant},
+};
 
 use cpal::InputCallbackInfo;
 use nnnoiseless::DenoiseState;
 use opus::{Bitrate, Encoder};
+use simple_call_protocol::{
+    encryption::{self, Sealer},
+    media::{self, MediaSender},
+    relay::{self, SessionId},
+    report::{self, Report, ReportBlock, SenderReport},
+};
 
-use super::FRAME_SIZE;
+use super::{FRAME_SIZE, Feedback, REPORT_INTERVAL, lock, send_report};
 
 /// Threshold for silence detection in dBFS
 const SILENCE_THRESHOLD_DBFS: f32 = -50.0;
@@ -12,6 +27,9 @@ const SILENCE_THRESHOLD_DBFS: f32 = -50.0;
 const GAIN: f32 = 2.0;
 /// The desired bitrate for the Opus encoder.
 const BITRATE: Bitrate = Bitrate::Bits(16_000); // 16kbps
+/// How often comfort noise is sent while silent, in frames: every 300ms, well within the
+/// second receivers wait before taking us for gone.
+const COMFORT_NOISE_INTERVAL: u32 = 5;
 
 /// Calculate the RMS (Root Mean Square) of the samples
 fn rms(samples: &[f32]) -> f32 {
@@ -32,6 +50,30 @@ fn is_silent(samples: &[f32]) -> bool {
     dbfs(samples) < SILENCE_THRESHOLD_DBFS
 }
 
+/// The level of the background noise in `samples`, in -dBov as comfort noise carries it.
+fn noise_level(samples: &[f32]) -> u8 {
+    (-dbfs(samples)).clamp(0.0, 127.0) as u8
+}
+
+/// Tells the user how `from` hears us.
+fn print_feedback(from: Option<SessionId>, block: &ReportBlock, round_trip: Option<Duration>) {
+    let who = match from {
+        Some(session_id) => format!("Participant {}", session_id),
+        None => "Your partner".to_string(),
+    };
+    let round_trip = match round_trip {
+        Some(round_trip) => format!(", {} ms round trip", round_trip.as_millis()),
+        None => String::new(),
+    };
+    println!(
+        "{} hears you with {}% of packets lost, {} ms of jitter{}.",
+        who,
+        block.fraction_lost as u32 * 100 / 256,
+        block.jitter / 48,
+        round_trip,
+    );
+}
+
 fn clean_audio(samples: &mut [f32], denoiser: &mut DenoiseState, denoiser_buff: &mut [f32]) {
     for sample in samples.iter_mut() {
         *sample *= 32768.0 * GAIN; // Scale to i16 range
@@ -50,13 +92,27 @@ fn clean_audio(samples: &mut [f32], denoiser: &mut DenoiseState, denoiser_buff:
     }
 }
 
+/// Records, encodes and sends our audio to `peer_udp_addr`, sealing it with `sealer` if any,
+/// numbered by `media_sender`.
+///
+/// Each packet carries a copy of the previous one's audio at a lower bitrate, Opus' in-band
+/// FEC, sized for the share of packets the others report losing on `feedback`, or else that
+/// `packet_loss` expects to be lost, in percent. While we are silent, comfort noise goes out
+/// instead every few frames.
 pub(crate) fn create_microphone_callback(
     udp_sock: UdpSocket,
     peer_udp_addr: SocketAddr,
+    session_id: Option<SessionId>,
+    sealer: Option<Arc<Mutex<Sealer>>>,
+    mut media_sender: MediaSender,
+    packet_loss: Arc<AtomicU8>,
+    feedback: Receiver<Feedback>,
 ) -> impl FnMut(&[f32], &InputCallbackInfo) {
     // Initialize OPUS Encoder to encode input and send through socket
     let mut encoder = Encoder::new(48000, opus::Channels::Mono, opus::Application::Voip).unwrap();
     encoder.set_bitrate(BITRATE).unwrap();
+    encoder.set_inband_fec(true).unwrap();
+    let mut expected_loss = 0;
 
     let mut denoiser = DenoiseState::new();
 
@@ -64,6 +120,26 @@ pub(crate) fn create_microphone_callback(
     let mut noise_red_buff = [0f32; DenoiseState::FRAME_SIZE];
     let mut in_buff_filled = 0;
     let mut buff = [0; 4096];
+    // Leaves room for the headers and what sealing adds
+    let mut encoded = [0; 4096 - relay::HEADER_LEN - media::HEADER_LEN - encryption::OVERHEAD];
+    let mut silent_frames = 0;
+
+    // For sender reports
+    let mut packets_sent: u32 = 0;
+    let mut octets_sent: u32 = 0;
+    let mut last_report_time = Instant::now();
+    // The latest share of our packets lost, out of 256, by who reported it and when
+    let mut reported_loss = HashMap::new();
+
+    // Relayed datagrams start with our session id, the media header and audio go after it
+    let relay_header_len = match session_id {
+        Some(session_id) => {
+            buff[..relay::HEADER_LEN].copy_from_slice(&session_id.to_bytes());
+            relay::HEADER_LEN
+        }
+        None => 0,
+    };
+    let header_len = relay_header_len + media::HEADER_LEN;
 
     move |mut data: &[f32], _meta: &InputCallbackInfo| {
         while !data.is_empty() {
@@ -78,15 +154,82 @@ pub(crate) fn create_microphone_callback(
                 // Clean audio samples
                 clean_audio(&mut in_buff, &mut denoiser, &mut noise_red_buff);
 
-                if is_silent(&in_buff) {
-                    // udp_sock.send_to(&[id], peer_udp_addr).unwrap();
-                } else {
-                    let encoded_size = encoder.encode_float(&in_buff, &mut buff[0..]).unwrap();
-
-                    udp_sock
-                        .send_to(&buff[..encoded_size], peer_udp_addr)
-                        .unwrap();
+                // Reports go out and come in whether we speak or not
+                if last_report_time.elapsed() >= REPORT_INTERVAL {
+                    last_report_time = Instant::now();
+                    let report = Report::Sender(SenderReport {
+                        ssrc: media_sender.ssrc(),
+                        ntp_time: report::ntp_time(),
+                        timestamp: media_sender.timestamp(),
+                        packets: packets_sent,
+                        octets: octets_sent,
+                    });
+                    send_report(
+                        &udp_sock,
+                        peer_udp_addr,
+                        session_id,
+                        sealer.as_deref(),
+                        &report,
+                    );
                 }
+                for Feedback {
+                    from,
+                    block,
+                    arrival,
+                } in feedback.try_iter()
+                {
+                    print_feedback(from, &block, block.round_trip(arrival));
+                    reported_loss.insert(from, (block.fraction_lost, Instant::now()));
+                }
+                // Those who stopped reporting may have left
+                reported_loss
+                    .retain(|_, &mut (_, reported)| reported.elapsed() < 3 * REPORT_INTERVAL);
+
+                let (header, encoded_size) = if is_silent(&in_buff) {
+                    // Comfort noise as silence starts and every so often after, nothing
+                    // otherwise: the next packet's timestamp tells receivers how long
+                    silent_frames += 1;
+                    if silent_frames % COMFORT_NOISE_INTERVAL != 1 {
+                        media_sender.skip(FRAME_SIZE as u32);
+                        continue;
+                    }
+                    encoded[0] = noise_level(&in_buff);
+                    (media_sender.comfort_noise(FRAME_SIZE as u32), 1)
+                } else {
+                    silent_frames = 0;
+
+                    // Without expected losses, the encoder leaves FEC out
+                    let loss = match reported_loss.values().map(|&(lost, _)| lost).max() {
+                        Some(lost) => (lost as u32 * 100 / 256) as u8,
+                        None => packet_loss.load(Ordering::Relaxed).min(100),
+                    };
+                    if loss != expected_loss {
+                        encoder.set_packet_loss_perc(loss.into()).unwrap();
+                        expected_loss = loss;
+                    }
+
+                    let encoded_size = encoder.encode_float(&in_buff, &mut encoded).unwrap();
+                    (media_sender.next(FRAME_SIZE as u32), encoded_size)
+                };
+                buff[relay_header_len..header_len].copy_from_slice(&header.to_bytes());
+
+                // The headers tell receivers who sent it and when, sealing binds the audio to
+                // them
+                let sealed;
+                let payload = match &sealer {
+                    Some(sealer) => {
+                        sealed = lock(sealer).seal(&buff[..header_len], &encoded[..encoded_size]);
+                        &sealed[..]
+                    }
+                    None => &encoded[..encoded_size],
+                };
+                buff[header_len..header_len + payload.len()].copy_from_slice(payload);
+
+                udp_sock
+                    .send_to(&buff[..header_len + payload.len()], peer_udp_addr)
+                    .unwrap();
+                packets_sent = packets_sent.wrapping_add(1);
+                octets_sent = octets_sent.wrapping_add(encoded_size as u32);
             }
         }
     }
@@ -1,12 +1,14 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, path::PathBuf};
 
 use clap::Parser;
 
+use crate::tls::{FINGERPRINT_LEN, parse_fingerprint};
+
 /// A simple client to call using the opus protocol.
 #[derive(Parser, Debug)] // requires `derive` feature
 #[clap(version, about, long_about = None)]
 pub struct Args {
-    /// The host address of the server to connect to. Ipv6 not supported for now.
+    /// The host address of the server to connect to, either IPv4 or IPv6.
     pub host: IpAddr,
 
     /// The TCP port of the server to connect to.
@@ -15,12 +17,34 @@ pub struct Args {
     /// The room to join. Your partner must join the same room to connect with you.
     pub room: String,
 
+    /// The room secret. Only those who know it can join the room, the server never learns it.
+    pub secret: String,
+
     /// Whether to relay the UDP packets through the server.
     ///
     /// The alternative is to connect directly to the partner, which might not always work.
     #[clap(long, default_value_t = false)]
     pub relay: bool,
 
+    /// Refuse to go on unless the signaling connection switches to TLS. Without it, the
+    /// connection only switches if the server offers to.
+    #[clap(long, default_value_t = false)]
+    pub tls: bool,
+
+    /// PEM file with the certificate authorities to trust for the server's TLS certificate,
+    /// instead of the usual ones of the web. Implies --tls.
+    #[clap(long)]
+    pub tls_ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of the server's TLS certificate, in hex. Only that certificate is
+    /// accepted, whoever signed it. Implies --tls.
+    #[clap(long, value_parser = parse_fingerprint, conflicts_with = "tls_ca")]
+    pub tls_pin: Option<[u8; FINGERPRINT_LEN]>,
+
+    /// Name the server's TLS certificate must be for, its address by default.
+    #[clap(long)]
+    pub tls_name: Option<String>,
+
     #[cfg(debug_assertions)]
     #[clap(long, default_value_t = false)]
     pub test: bool,
@@ -36,4 +60,18 @@ mod test {
 
         Args::command().debug_assert();
     }
+
+    #[test]
+    fn tls_pins_read_as_hex() {
+        let hex = "ab".repeat(FINGERPRINT_LEN);
+        let args = Args::parse_from(["client", "::1", "8383", "room", "secret", "--tls-pin", &hex]);
+        assert_eq!(args.tls_pin, Some([0xab; FINGERPRINT_LEN]));
+
+        // Like OpenSSL prints them
+        let colons = vec!["AB"; FINGERPRINT_LEN].join(":");
+        assert_eq!(parse_fingerprint(&colons), Ok([0xab; FINGERPRINT_LEN]));
+
+        assert!(parse_fingerprint("abab").is_err());
+        assert!(parse_fingerprint(&"+b".repeat(FINGERPRINT_LEN)).is_err());
+    }
 }
@@ -1,100 +1,510 @@
 use std::{
-    io::{Read, Write},
-    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
-    thread::sleep,
-    time::Duration,
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    process,
+    sync::mpsc::{Receiver, Sender},
 };
 
 use sha2::{Digest, Sha512};
+use simple_call_protocol::{
+    Message,
+    encryption::MediaKey,
+    pake::{Confirmation, Exchange, KeyShare, Pake},
+    relay::{self, SessionId},
+    signal::{
+        Capabilities, ClientMessage, ErrorCode, PROTOCOL_VERSION, ServerMessage,
+        is_supported_version,
+    },
+};
+
+use crate::{
+    call::{CallEnd, handle_call},
+    keys::CallKeys,
+    tls::{SignalingStream, TlsSettings},
+};
 
-use crate::call::handle_call;
-use crate::utils::addr_from_bytes;
+/// Optional protocol features this client implements.
+const CLIENT_CAPABILITIES: Capabilities = Capabilities::ENCRYPTED_MEDIA.union(Capabilities::TLS);
 
-pub const SIGNAL_WAITING_IN_ROOM: u8 = 1;
-pub const SIGNAL_PARTNER_FOUND: u8 = 2;
-pub const SIGNAL_READY: u8 = 3;
+/// What can be typed during a call.
+const COMMANDS_HELP: &str = "Commands: list, mute <id>, unmute <id>, kick <id>, lock, unlock, \
+                             moderate <secret>, help. Only moderators may use the first six.";
 
-pub fn handle_coordination(host: IpAddr, host_tcp_port: u16, room: String, relay: bool) {
+pub fn handle_coordination(
+    host: IpAddr,
+    host_tcp_port: u16,
+    room: String,
+    secret: String,
+    relay: bool,
+    tls: TlsSettings,
+) {
     // Create a TCP connection to the server
-    let mut tcp_stream = TcpStream::connect((host, host_tcp_port))
-        .expect("Failed to connect to TCP listener. Is the server running?");
+    let mut tcp_stream = SignalingStream::new(
+        TcpStream::connect((host, host_tcp_port))
+            .expect("Failed to connect to TCP listener. Is the server running?"),
+    );
+
+    // Introduce ourselves, so the server knows how to talk to us
+    ClientMessage::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CLIENT_CAPABILITIES,
+    }
+    .write_to(&mut tcp_stream)
+    .expect("Failed to write to TCP stream.");
+
+    let capabilities =
+        match ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream.") {
+            ServerMessage::Hello { version, .. } if !is_supported_version(version) => {
+                eprintln!(
+                    "The server speaks protocol version {}, which this client does not support.",
+                    version
+                );
+                process::exit(1);
+            }
+            ServerMessage::Hello { capabilities, .. } => capabilities,
+            ServerMessage::Error(code) => exit_on_error(code),
+            other => panic!("Expected the server's hello, but received {:?}", other),
+        };
 
-    // Send server what room we want to join
-    let room_hash = Sha512::digest(room);
+    // Keep the rooms we join and the addresses of our partners between us and the server
+    if capabilities.contains(Capabilities::TLS) {
+        if let Err(e) = tcp_stream.start_tls(&tls, host) {
+            eprintln!(
+                "Could not switch the connection to the server to TLS: {}. If the server's \
+                 certificate is self-signed, pass its fingerprint with --tls-pin.",
+                e
+            );
+            process::exit(11);
+        }
+    } else if tls.required {
+        eprintln!("The server does not offer TLS, not going on in the clear.");
+        process::exit(11);
+    }
+
+    // Send server what room we want to join. Its name is no secret, knowing the room secret is
+    // proven to the others in it
+    let room_hash = Sha512::digest(room).into();
+
+    // Everyone in the room derives the same key from its secret. Slow, better done before
+    // anyone waits for us
+    let room_key = if capabilities.contains(Capabilities::ENCRYPTED_MEDIA) {
+        Some(MediaKey::derive(&room_hash, secret.as_bytes()))
+    } else {
+        println!("The server mixes calls, so your audio is not encrypted end to end.");
+        None
+    };
 
-    tcp_stream
-        .write_all(&room_hash)
+    ClientMessage::JoinRoom { room_hash, relay }
+        .write_to(&mut tcp_stream)
         .expect("Failed to write to TCP stream.");
 
-    // Relay true
-    tcp_stream
-        .write_all(&[if relay { 1 } else { 0 }])
+    // Receive server udp port, whether the call ends up relayed, and how to register with it
+    let (server_udp_port, relay, session_id, token) = loop {
+        let message =
+            ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream.");
+
+        match message {
+            ServerMessage::WaitingInRoom => {
+                println!("Waiting for a partner to join the room.");
+            }
+            ServerMessage::PartnerFound {
+                udp_port,
+                relay,
+                session_id,
+                token,
+            } => break (udp_port, relay, session_id, token),
+            ServerMessage::Ready => {
+                ClientMessage::Ready
+                    .write_to(&mut tcp_stream)
+                    .expect("Failed to write to TCP stream.");
+            }
+            ServerMessage::Error(code) => exit_on_error(code),
+            other => panic!("Unexpected signal from server: {:?}", other),
+        }
+    };
+
+    // Use the same address family the server is reachable through
+    let unspecified: IpAddr = if host.is_ipv6() {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+    let udp_sock = UdpSocket::bind((unspecified, 0))
+        .expect("Failed to bind UDP socket. All UDP ports are in use?");
+
+    let server_udp_addr = SocketAddr::new(host, server_udp_port);
+
+    // Prove we know the room secret to whoever the server pairs us with, and check they do
+    let mut proof = SecretProof::new(Pake::new(&room_hash, secret.as_bytes()));
+    ClientMessage::KeyShare(proof.pake.share())
+        .write_to(&mut tcp_stream)
         .expect("Failed to write to TCP stream.");
 
-    // Receive server udp port
-    let mut buffer = [0; 1024];
-    let mut patience = 100;
+    loop {
+        let message =
+            ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream.");
+
+        match message {
+            ServerMessage::PeerKeyShare { peer, key_share } => proof
+                .key_share(&mut tcp_stream, peer, &key_share)
+                .expect("Failed to write to TCP stream."),
+            // The server turns us away if our own secret is the wrong one
+            ServerMessage::PeerKeyConfirmation { peer, confirmation } => {
+                proof
+                    .confirmation(&mut tcp_stream, peer, &confirmation)
+                    .expect("Failed to write to TCP stream.");
+            }
+            ServerMessage::Admitted => break,
+            ServerMessage::ParticipantLeft(session_id) => {
+                println!("Participant {} left the call.", session_id);
+            }
+            ServerMessage::Error(code) => exit_on_error(code),
+            ServerMessage::PartnerLeft => {
+                println!("Your partner left before the call started.");
+                return;
+            }
+            other => panic!("Unexpected signal from server: {:?}", other),
+        }
+    }
+
+    // Hand everyone in the call a key of our own for our audio, sealed through exchanges the
+    // server cannot read
+    let (mut keys, audio_keys) = match room_key {
+        Some(room_key) => {
+            let (keys, audio_keys) = CallKeys::new(room_key, relay);
+            (Some(keys), Some(audio_keys))
+        }
+        None => (None, None),
+    };
 
-    let server_udp_port = 'udp_loop: loop {
-        sleep(Duration::from_millis(10));
+    // Send our session id and token to the server so that it knows our address
+    udp_sock
+        .send_to(&relay::registration(session_id, &token), server_udp_addr)
+        .expect("Failed to send UDP packet.");
 
-        let size = tcp_stream
-            .read(&mut buffer[0..1])
-            .expect("Failed to read from TCP stream.");
+    // Relayed media goes through the server, which needs to know who it comes from
+    let (peer_udp_addr, session_id): (SocketAddr, Option<SessionId>) = if relay {
+        (server_udp_addr, Some(session_id))
+    } else {
+        // Get peer's UDP address, exchanging keys with them meanwhile
+        loop {
+            let message =
+                ServerMessage::read_from(&mut tcp_stream).expect("Failed to read from TCP stream.");
 
-        if size == 1 {
-            match buffer[0] {
-                SIGNAL_WAITING_IN_ROOM => {
-                    patience = 6000;
+            match message {
+                ServerMessage::PeerAddress(addr) if addr.is_ipv6() != host.is_ipv6() => {
+                    eprintln!(
+                        "Your partner ({}) uses a different IP version than you, try again with --relay.",
+                        addr
+                    );
+                    process::exit(1);
                 }
-                SIGNAL_PARTNER_FOUND => {
-                    tcp_stream
-                        .read_exact(&mut buffer[0..2])
-                        .expect("Failed to read from TCP stream.");
-                    break 'udp_loop u16::from_be_bytes([buffer[0], buffer[1]]);
+                ServerMessage::PeerAddress(addr) => break (addr, None),
+                message @ (ServerMessage::ExchangeKeys(_)
+                | ServerMessage::PeerKeyCommitment { .. }
+                | ServerMessage::PeerPublicKey { .. }
+                | ServerMessage::PeerSenderKey { .. })
+                    if keys.is_some() =>
+                {
+                    if let Some(keys) = &mut keys {
+                        keys.handle(&mut tcp_stream, message)
+                            .expect("Failed to write to TCP stream.");
+                    }
                 }
-                SIGNAL_READY => {
-                    tcp_stream
-                        .write_all(&[SIGNAL_READY])
-                        .expect("Failed to write to TCP stream.");
+                ServerMessage::Error(code) => exit_on_error(code),
+                ServerMessage::PartnerLeft => {
+                    println!("Your partner left before the call started.");
+                    return;
                 }
-                _ => panic!("Unexpected signal from server: {}", buffer[0]),
+                other => panic!("Expected the peer's address, but received {:?}", other),
             }
-        } else {
-            patience -= 1;
         }
+    };
+
+    handle_call(
+        udp_sock,
+        peer_udp_addr,
+        session_id,
+        audio_keys,
+        Some(Signaling {
+            tcp_stream,
+            proof,
+            keys,
+        }),
+    );
+}
+
+/// Our side of the signaling connection during a call.
+pub struct Signaling {
+    pub tcp_stream: SignalingStream,
+    /// Checks that those joining know the room secret.
+    proof: SecretProof,
+    /// Exchanges keys with everyone in the call, when the audio is encrypted end to end.
+    keys: Option<CallKeys>,
+}
+
+/// Our side of the exchanges proving to our peers that we know the room secret, and checking
+/// that they do.
+pub struct SecretProof {
+    pake: Pake,
+    /// Exchanges waiting for the peer's confirmation.
+    exchanges: HashMap<SessionId, Exchange>,
+}
+
+impl SecretProof {
+    fn new(pake: Pake) -> Self {
+        Self {
+            pake,
+            exchanges: HashMap::new(),
+        }
+    }
+
+    /// Starts the exchange with `peer`, sending it our confirmation of the key. A share that
+    /// is not valid is answered by telling the server `peer` failed.
+    fn key_share(
+        &mut self,
+        tcp_stream: &mut SignalingStream,
+        peer: SessionId,
+        key_share: &KeyShare,
+    ) -> io::Result<()> {
+        let Ok(exchange) = self.pake.exchange(key_share) else {
+            return ClientMessage::PeerVerified {
+                peer,
+                verified: false,
+            }
+            .write_to(tcp_stream);
+        };
 
-        if patience == 0 {
-            panic!("Server is not responding.");
+        let confirmation = exchange.confirmation();
+        self.exchanges.insert(peer, exchange);
+        ClientMessage::KeyConfirmation { peer, confirmation }.write_to(tcp_stream)
+    }
+
+    /// Checks `peer`'s confirmation, telling the server whether it knows the room secret.
+    /// Returns whether it does.
+    fn confirmation(
+        &mut self,
+        tcp_stream: &mut SignalingStream,
+        peer: SessionId,
+        confirmation: &Confirmation,
+    ) -> io::Result<bool> {
+        let verified = self
+            .exchanges
+            .remove(&peer)
+            .is_some_and(|exchange| exchange.finish(confirmation).is_ok());
+
+        ClientMessage::PeerVerified { peer, verified }.write_to(tcp_stream)?;
+        Ok(verified)
+    }
+}
+
+/// Explains why the server gave up on us, and exits with a status telling the reasons apart.
+///
+/// Exit statuses: 3 unsupported version, 4 malformed message, 5 handshake timeout, 6 room full,
+/// 7 unauthorized, 8 server busy, 9 kicked, 10 room locked, 11 no TLS, 1 anything else.
+fn exit_on_error(code: ErrorCode) -> ! {
+    let (message, status) = match code {
+        ErrorCode::UnsupportedVersion => (
+            "The server does not support this client's version, try updating it.".to_string(),
+            3,
+        ),
+        ErrorCode::MalformedMessage => (
+            "The server did not understand this client, try updating it.".to_string(),
+            4,
+        ),
+        ErrorCode::HandshakeTimeout => (
+            "The call could not be set up: the server did not receive UDP packets from you \
+             or your partner. A firewall may be blocking them, try again with --relay."
+                .to_string(),
+            5,
+        ),
+        ErrorCode::RoomFull => (
+            "This room already has a call going on, pick another room.".to_string(),
+            6,
+        ),
+        ErrorCode::Unauthorized => (
+            "Your room secret does not match the one of the others in the room.".to_string(),
+            7,
+        ),
+        ErrorCode::ServerBusy => (
+            "The server is too busy to take the call, try again later.".to_string(),
+            8,
+        ),
+        ErrorCode::Kicked => ("A moderator removed you from the call.".to_string(), 9),
+        ErrorCode::RoomLocked => (
+            "A moderator locked this room, nobody can join it for now.".to_string(),
+            10,
+        ),
+        ErrorCode::TlsRequired => (
+            "The server only takes connections through TLS, try updating this client.".to_string(),
+            11,
+        ),
+        ErrorCode::Internal | ErrorCode::Unknown(_) => {
+            (format!("The server ran into a problem: {}.", code), 1)
         }
     };
 
-    let udp_sock =
-        UdpSocket::bind("0.0.0.0:0").expect("Failed to bind UDP socket. All UDP ports are in use?");
+    eprintln!("{}", message);
+    process::exit(status);
+}
 
-    let server_udp_addr = SocketAddr::new(host, server_udp_port);
+/// Follows the signaling connection during a call, reporting on `end` how it ends. Checks that
+/// those joining know the room secret, and exchanges keys with them. Answers the server's
+/// shares of the exchanges about the moderator secret with the sides `moderation` hands over,
+/// in the order they were claimed.
+pub fn watch_signaling(signaling: Signaling, end: Sender<CallEnd>, moderation: Receiver<Pake>) {
+    let Signaling {
+        mut tcp_stream,
+        mut proof,
+        mut keys,
+    } = signaling;
 
-    // Send an empty UDP packet to the server so that it knows our address
-    udp_sock
-        .send_to(&[], server_udp_addr)
-        .expect("Failed to send UDP packet.");
+    loop {
+        match ServerMessage::read_from(&mut tcp_stream) {
+            // A write failing means the connection broke, the next read tells
+            Ok(ServerMessage::PeerKeyShare { peer, key_share }) => {
+                let _ = proof.key_share(&mut tcp_stream, peer, &key_share);
+            }
+            Ok(ServerMessage::PeerKeyConfirmation { peer, confirmation }) => {
+                match proof.confirmation(&mut tcp_stream, peer, &confirmation) {
+                    Ok(true) => println!("Participant {} joined the call.", peer),
+                    Ok(false) => println!(
+                        "Participant {} does not know the room secret, it was turned away.",
+                        peer
+                    ),
+                    Err(_) => {}
+                }
+            }
+            Ok(
+                message @ (ServerMessage::ExchangeKeys(_)
+                | ServerMessage::PeerKeyCommitment { .. }
+                | ServerMessage::PeerPublicKey { .. }
+                | ServerMessage::PeerSenderKey { .. }),
+            ) if keys.is_some() => {
+                if let Some(keys) = &mut keys {
+                    let _ = keys.handle(&mut tcp_stream, message);
+                }
+            }
+            Ok(ServerMessage::PartnerLeft) => {
+                let _ = end.send(CallEnd::PartnerLeft);
+                return;
+            }
+            Ok(ServerMessage::ParticipantLeft(session_id)) => {
+                if let Some(keys) = &mut keys {
+                    keys.left(session_id);
+                }
+                println!("Participant {} left the call.", session_id);
+            }
+            Ok(ServerMessage::Participants(participants)) => {
+                println!("In the call:");
+                for participant in participants {
+                    let moderator = if participant.moderator {
+                        " (moderator)"
+                    } else {
+                        ""
+                    };
+                    let muted = if participant.muted { " (muted)" } else { "" };
+                    println!("  {}{}{}", participant.session_id, moderator, muted);
+                }
+            }
+            Ok(ServerMessage::ModeratorKeyShare(key_share)) => {
+                let Ok(pake) = moderation.try_recv() else {
+                    continue;
+                };
+                match pake.exchange(&key_share) {
+                    Ok(exchange) => {
+                        let _ = ClientMessage::ModeratorConfirmation(exchange.confirmation())
+                            .write_to(&mut tcp_stream);
+                    }
+                    Err(_) => println!("The server answered your claim with an invalid share."),
+                }
+            }
+            Ok(ServerMessage::Moderator) => println!("You moderate the call now."),
+            Ok(ServerMessage::Muted(true)) => println!("A moderator muted you."),
+            Ok(ServerMessage::Muted(false)) => println!("A moderator unmuted you."),
+            Ok(ServerMessage::NotAllowed) => {
+                println!("You are not allowed to do that, or not in this call.");
+            }
+            Ok(ServerMessage::Error(code)) => exit_on_error(code),
+            Ok(other) => eprintln!("Unexpected signal from server during the call: {:?}", other),
+            Err(_) => {
+                let _ = end.send(CallEnd::ServerLost);
+                return;
+            }
+        }
+    }
+}
 
-    let peer_udp_addr = if relay {
-        server_udp_addr
-    } else {
-        // Get peer's UDP address
-        let size = tcp_stream
-            .read(&mut buffer)
-            .expect("Failed to read from TCP stream.");
-
-        assert_eq!(
-            size, 6,
-            "Expected to receive 6 bytes, ipv4 addr + port, but received {} bytes",
-            size
-        );
-
-        addr_from_bytes(&buffer[0..6])
+/// Reads commands typed during a call and sends them to the server, see [`COMMANDS_HELP`].
+/// Claims of moderation go on with the server's answer, `moderation` takes our side of them.
+pub fn read_commands(mut tcp_stream: SignalingStream, moderation: Sender<Pake>) {
+    println!("Type help for the commands you can use during the call.");
+
+    for line in io::stdin().lines() {
+        let Ok(line) = line else {
+            return;
+        };
+
+        let command = match parse_command(&line, &moderation) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{} {}", e, COMMANDS_HELP);
+                continue;
+            }
+        };
+
+        // The call is over once the connection fails, watching it tells why
+        if command.write_to(&mut tcp_stream).is_err() {
+            return;
+        }
+    }
+}
+
+/// Turns a typed command into the message asking the server for it. Nothing to send for an
+/// empty line or `help`. Our side of a claim of moderation goes to `moderation`.
+fn parse_command(line: &str, moderation: &Sender<Pake>) -> Result<Option<ClientMessage>, String> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(None);
+    };
+    let argument = words.next();
+
+    let session_id = || {
+        let id = argument.ok_or_else(|| format!("{} needs a participant id.", command))?;
+        u32::from_str_radix(id, 16)
+            .map(SessionId)
+            .map_err(|_| format!("Invalid participant id {:?}.", id))
+    };
+
+    let message = match command {
+        "help" => {
+            println!("{}", COMMANDS_HELP);
+            return Ok(None);
+        }
+        "list" => ClientMessage::ListParticipants,
+        "mute" => ClientMessage::SetMuted {
+            session_id: session_id()?,
+            muted: true,
+        },
+        "unmute" => ClientMessage::SetMuted {
+            session_id: session_id()?,
+            muted: false,
+        },
+        "kick" => ClientMessage::Kick(session_id()?),
+        "lock" => ClientMessage::SetLocked(true),
+        "unlock" => ClientMessage::SetLocked(false),
+        "moderate" => {
+            let secret = argument.ok_or("moderate needs the moderator secret.")?;
+            let pake = Pake::moderator(secret.as_bytes());
+            let claim = ClientMessage::Moderate(pake.share());
+            // Only fails once the call is over
+            let _ = moderation.send(pake);
+            claim
+        }
+        other => return Err(format!("Unknown command {:?}.", other)),
     };
 
-    handle_call(udp_sock, peer_udp_addr);
+    Ok(Some(message))
 }
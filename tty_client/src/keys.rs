@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use simple_call_protocol::{
+    Message,
+    encryption::MediaKey,
+    key_exchange::{Agreement, Commitment, KeyExchange},
+    relay::SessionId,
+    signal::{ClientMessage, ServerMessage},
+};
+
+use crate::tls::SignalingStream;
+
+/// The keys the audio needs: ours to encrypt what we send, and the others' as they arrive,
+/// by who sends with them. Direct calls have a single other, known as `None`.
+pub struct AudioKeys {
+    pub own: MediaKey,
+    pub received: Receiver<(Option<SessionId>, MediaKey)>,
+}
+
+/// Exchanges keys with everyone in the call, so that the server relaying it cannot listen in.
+pub struct CallKeys {
+    /// Derived from the room secret, goes into every exchange.
+    room_key: MediaKey,
+    /// What we encrypt our audio with, handed to everyone we exchanged keys with.
+    own: MediaKey,
+    relayed: bool,
+    exchanges: HashMap<SessionId, Exchange>,
+    received: Sender<(Option<SessionId>, MediaKey)>,
+}
+
+/// Where the exchange with one peer is at.
+enum Exchange {
+    /// Our commitment went out, the peer's did not arrive yet.
+    Committed(KeyExchange),
+    /// Both commitments went out, then our public key.
+    Revealed(KeyExchange, Commitment),
+    /// Both reached the same agreement, unless someone is in the middle.
+    Agreed(Agreement),
+}
+
+impl CallKeys {
+    pub fn new(room_key: MediaKey, relayed: bool) -> (Self, AudioKeys) {
+        let own = MediaKey::random();
+        let (received, audio_received) = mpsc::channel();
+
+        let keys = Self {
+            room_key,
+            own: own.clone(),
+            relayed,
+            exchanges: HashMap::new(),
+            received,
+        };
+        let audio = AudioKeys {
+            own,
+            received: audio_received,
+        };
+        (keys, audio)
+    }
+
+    /// Takes the next step of a key exchange, `message` being one of those the server sends
+    /// about them.
+    pub fn handle(
+        &mut self,
+        tcp_stream: &mut SignalingStream,
+        message: ServerMessage,
+    ) -> io::Result<()> {
+        match message {
+            ServerMessage::ExchangeKeys(peer) => {
+                let exchange = KeyExchange::new();
+                ClientMessage::KeyCommitment {
+                    peer,
+                    commitment: exchange.commitment(),
+                }
+                .write_to(tcp_stream)?;
+                self.exchanges.insert(peer, Exchange::Committed(exchange));
+            }
+            ServerMessage::PeerKeyCommitment { peer, commitment } => {
+                let Some(Exchange::Committed(exchange)) = self.exchanges.remove(&peer) else {
+                    eprintln!("Participant {} sent keys out of turn, ignoring them.", peer);
+                    return Ok(());
+                };
+                ClientMessage::PublicKey {
+                    peer,
+                    public_key: exchange.public_key(),
+                }
+                .write_to(tcp_stream)?;
+                self.exchanges
+                    .insert(peer, Exchange::Revealed(exchange, commitment));
+            }
+            ServerMessage::PeerPublicKey { peer, public_key } => {
+                let Some(Exchange::Revealed(exchange, commitment)) = self.exchanges.remove(&peer)
+                else {
+                    eprintln!("Participant {} sent keys out of turn, ignoring them.", peer);
+                    return Ok(());
+                };
+                let agreement = match exchange.finish(&self.room_key, &commitment, &public_key) {
+                    Ok(agreement) => agreement,
+                    Err(e) => {
+                        eprintln!(
+                            "The key exchange with participant {} failed: {}. You will not hear \
+                             them.",
+                            peer, e
+                        );
+                        return Ok(());
+                    }
+                };
+
+                println!(
+                    "Security code with participant {}: {}. Read it to them out loud, if theirs \
+                     differs someone may be listening in.",
+                    peer,
+                    agreement.sas()
+                );
+                ClientMessage::SenderKey {
+                    peer,
+                    sealed: agreement.seal_key(&self.own),
+                }
+                .write_to(tcp_stream)?;
+                self.exchanges.insert(peer, Exchange::Agreed(agreement));
+            }
+            ServerMessage::PeerSenderKey { peer, sealed } => {
+                let Some(Exchange::Agreed(agreement)) = self.exchanges.get(&peer) else {
+                    eprintln!("Participant {} sent keys out of turn, ignoring them.", peer);
+                    return Ok(());
+                };
+                match agreement.open_key(&sealed) {
+                    Ok(key) => {
+                        let sender = self.relayed.then_some(peer);
+                        // Only fails once the call is over
+                        let _ = self.received.send((sender, key));
+                    }
+                    Err(e) => eprintln!(
+                        "Participant {}'s key could not be opened: {}. You will not hear them.",
+                        peer, e
+                    ),
+                }
+            }
+            other => unreachable!("Not part of a key exchange: {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    /// Forgets about `peer`, who left the call.
+    pub fn left(&mut self, peer: SessionId) {
+        self.exchanges.remove(&peer);
+    }
+}
@@ -1,7 +1,10 @@
 mod call;
 mod cli_args;
 mod coordination;
-mod utils;
+mod keys;
+mod tls;
+
+use std::process;
 
 #[cfg(debug_assertions)]
 use std::net::UdpSocket;
@@ -10,6 +13,7 @@ use std::net::UdpSocket;
 use call::handle_call;
 use clap::Parser;
 use coordination::handle_coordination;
+use tls::TlsSettings;
 
 fn main() {
     // Parse command line arguments
@@ -21,8 +25,27 @@ fn main() {
         let udp_sock = UdpSocket::bind("127.0.0.1:0")
             .expect("Failed to bind UDP socket. All UDP ports are in use?");
         let addr = udp_sock.local_addr().unwrap();
-        handle_call(udp_sock, addr);
+        handle_call(udp_sock, addr, None, None, None);
+        return;
     }
 
-    handle_coordination(args.host, args.host_tcp_port, args.room, args.relay);
+    let tls = TlsSettings::new(
+        args.tls_ca.as_deref(),
+        args.tls_pin,
+        args.tls_name,
+        args.tls,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    handle_coordination(
+        args.host,
+        args.host_tcp_port,
+        args.room,
+        args.secret,
+        args.relay,
+        tls,
+    );
 }
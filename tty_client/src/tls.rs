@@ -0,0 +1,268 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, WebPkiSupportedAlgorithms, ring},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+use sha2::{Digest, Sha256};
+
+/// Length of a certificate's SHA-256 fingerprint.
+pub const FINGERPRINT_LEN: usize = 32;
+
+/// How the signaling connection switches to TLS.
+pub struct TlsSettings {
+    /// Refuse to go on in the clear with a server that does not offer TLS.
+    pub required: bool,
+    config: Arc<ClientConfig>,
+    /// What the server's certificate must be for, its address if not set.
+    server_name: Option<ServerName<'static>>,
+}
+
+/// The signaling connection, in the clear or through TLS.
+///
+/// Clones share the connection, so one thread can wait for messages while others send: only
+/// the TLS state is locked, never while waiting on the socket.
+pub struct SignalingStream {
+    tcp_stream: TcpStream,
+    tls: Option<Arc<Mutex<ClientConnection>>>,
+}
+
+/// Accepts the one certificate with a known fingerprint, whoever signed it.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; FINGERPRINT_LEN],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl TlsSettings {
+    /// Trusts the certificate authorities in the PEM file `ca`, or only the certificate with
+    /// the SHA-256 fingerprint `pin`, or else the usual authorities of the web.
+    pub fn new(
+        ca: Option<&Path>,
+        pin: Option<[u8; FINGERPRINT_LEN]>,
+        server_name: Option<String>,
+        required: bool,
+    ) -> Result<Self, String> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+
+        let config = match (ca, pin) {
+            (_, Some(fingerprint)) => {
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                        fingerprint,
+                        algorithms: provider.signature_verification_algorithms,
+                    }))
+            }
+            (Some(ca), None) => {
+                let mut roots = RootCertStore::empty();
+                let certs = CertificateDer::pem_file_iter(ca)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| format!("Failed to read {}: {}", ca.display(), e))?;
+                let (added, _) = roots.add_parsable_certificates(certs);
+                if added == 0 {
+                    return Err(format!("No usable certificate in {}", ca.display()));
+                }
+                builder.with_root_certificates(roots)
+            }
+            (None, None) => builder.with_root_certificates(RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            }),
+        };
+
+        let server_name = server_name
+            .map(|name| {
+                ServerName::try_from(name.clone())
+                    .map_err(|_| format!("Invalid TLS server name {:?}", name))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            required: required || ca.is_some() || pin.is_some(),
+            config: Arc::new(config.with_no_client_auth()),
+            server_name,
+        })
+    }
+}
+
+impl SignalingStream {
+    pub fn new(tcp_stream: TcpStream) -> Self {
+        Self {
+            tcp_stream,
+            tls: None,
+        }
+    }
+
+    /// Runs the TLS handshake with the server at `host`, everything after goes through TLS.
+    pub fn start_tls(&mut self, settings: &TlsSettings, host: IpAddr) -> io::Result<()> {
+        let server_name = settings
+            .server_name
+            .clone()
+            .unwrap_or_else(|| ServerName::IpAddress(host.into()));
+        let mut connection = ClientConnection::new(settings.config.clone(), server_name)
+            .map_err(io::Error::other)?;
+
+        while connection.is_handshaking() {
+            connection.complete_io(&mut self.tcp_stream)?;
+        }
+
+        self.tls = Some(Arc::new(Mutex::new(connection)));
+        Ok(())
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            tcp_stream: self.tcp_stream.try_clone()?,
+            tls: self.tls.clone(),
+        })
+    }
+
+    /// Shuts the connection down, telling the server through TLS first that we are done
+    /// writing.
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if let Some(tls) = &self.tls
+            && how != Shutdown::Read
+        {
+            let mut connection = lock(tls);
+            connection.send_close_notify();
+            flush_tls(&mut connection, &mut self.tcp_stream)?;
+        }
+        self.tcp_stream.shutdown(how)
+    }
+}
+
+impl Read for SignalingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return self.tcp_stream.read(buf);
+        };
+
+        loop {
+            match lock(tls).reader().read(buf) {
+                // Nothing left to read once the server closed its side
+                Ok(read) => return Ok(read),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // Wait for more from the server without holding up those sending meanwhile
+            let mut received = [0; 4096];
+            let read = self.tcp_stream.read(&mut received)?;
+
+            let mut connection = lock(tls);
+            let mut received = &received[..read];
+            loop {
+                // Reading nothing tells the connection the server is gone
+                connection.read_tls(&mut received)?;
+                connection
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if received.is_empty() {
+                    break;
+                }
+            }
+            // Whatever the connection has to answer, alerts for instance
+            flush_tls(&mut connection, &mut self.tcp_stream)?;
+        }
+    }
+}
+
+impl Write for SignalingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return self.tcp_stream.write(buf);
+        };
+
+        let mut connection = lock(tls);
+        let written = connection.writer().write(buf)?;
+        flush_tls(&mut connection, &mut self.tcp_stream)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp_stream.flush()
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity)[..] == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Reads a fingerprint written in hex, the bytes optionally separated by colons like OpenSSL
+/// prints them.
+pub fn parse_fingerprint(s: &str) -> Result<[u8; FINGERPRINT_LEN], String> {
+    let hex: String = s.chars().filter(|&c| c != ':').collect();
+    if hex.len() != FINGERPRINT_LEN * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "expected {} hex bytes, like the output of `openssl x509 -noout -fingerprint -sha256`",
+            FINGERPRINT_LEN
+        ));
+    }
+
+    let mut fingerprint = [0; FINGERPRINT_LEN];
+    for (byte, digits) in fingerprint.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16).unwrap();
+    }
+    Ok(fingerprint)
+}
+
+/// Sends the server what the connection has for it.
+fn flush_tls(connection: &mut ClientConnection, tcp_stream: &mut TcpStream) -> io::Result<()> {
+    while connection.wants_write() {
+        connection.write_tls(tcp_stream)?;
+    }
+    Ok(())
+}
+
+fn lock(tls: &Mutex<ClientConnection>) -> MutexGuard<'_, ClientConnection> {
+    // A panic elsewhere ends the call anyway, the connection is only needed until then
+    tls.lock().unwrap_or_else(PoisonError::into_inner)
+}